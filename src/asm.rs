@@ -0,0 +1,648 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+
+use crate::image::Image;
+use crate::state::invalid;
+use crate::symbols::Symbols;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+const ALU_IMMEDIATE: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+const IMPLIED: [(&str, u8); 17] = [
+    ("NOP", 0x00),
+    ("RLC", 0x07),
+    ("RRC", 0x0F),
+    ("RAL", 0x17),
+    ("RAR", 0x1F),
+    ("DAA", 0x27),
+    ("CMA", 0x2F),
+    ("STC", 0x37),
+    ("CMC", 0x3F),
+    ("HLT", 0x76),
+    ("RET", 0xC9),
+    ("XTHL", 0xE3),
+    ("PCHL", 0xE9),
+    ("XCHG", 0xEB),
+    ("DI", 0xF3),
+    ("SPHL", 0xF9),
+    ("EI", 0xFB),
+];
+
+// Looks up a symbol's value; None if it is not defined.
+pub type Lookup<'a> = &'a dyn Fn(&str) -> Option<u16>;
+
+pub fn symbols(symbols: &Symbols) -> impl Fn(&str) -> Option<u16> + '_ {
+    move |name| symbols.address(name)
+}
+
+// Splits operands at commas outside quotes.
+pub fn operands(text: &str) -> Vec<&str> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let (mut start, mut quoted) = (0, false);
+    for (index, c) in text.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            ',' if !quoted => {
+                operands.push(text[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+// Evaluates an expression of numbers, symbols, 'c' characters and $ (the
+// current address) with + - * / and parentheses. Numbers take Intel's
+// suffixes, H, D, O, Q and B, and are otherwise in `radix`; in radix 16 only
+// H is a suffix, since B and D are digits.
+pub fn evaluate(text: &str, address: u16, radix: u32, lookup: Lookup) -> Result<u16, String> {
+    let mut parser = Parser { text: text.trim(), position: 0, address, radix, lookup };
+    let value = parser.sum()?;
+    parser.skip_spaces();
+    match parser.position < parser.text.len() {
+        true => Err(format!("unexpected {} in {text}", &parser.text[parser.position..])),
+        false => Ok(value),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a str,
+    position: usize,
+    address: u16,
+    radix: u32,
+    lookup: Lookup<'a>,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.text[self.position..].starts_with(' ') {
+            self.position += 1;
+        }
+    }
+
+    fn next_is(&mut self, c: char) -> bool {
+        self.skip_spaces();
+        if self.text[self.position..].starts_with(c) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn sum(&mut self) -> Result<u16, String> {
+        let mut value = self.product()?;
+        loop {
+            if self.next_is('+') {
+                value = value.wrapping_add(self.product()?);
+            } else if self.next_is('-') {
+                value = value.wrapping_sub(self.product()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<u16, String> {
+        let mut value = self.term()?;
+        loop {
+            if self.next_is('*') {
+                value = value.wrapping_mul(self.term()?);
+            } else if self.next_is('/') {
+                let divisor = self.term()?;
+                value = value.checked_div(divisor).ok_or("division by zero")?;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<u16, String> {
+        if self.next_is('-') {
+            return Ok(self.term()?.wrapping_neg());
+        }
+        if self.next_is('+') {
+            return self.term();
+        }
+        if self.next_is('(') {
+            let value = self.sum()?;
+            return match self.next_is(')') {
+                true => Ok(value),
+                false => Err("missing )".to_string()),
+            };
+        }
+        if self.next_is('$') {
+            return Ok(self.address);
+        }
+        let rest = &self.text[self.position..];
+        if let Some(quoted) = rest.strip_prefix('\'') {
+            let end = quoted.find('\'').ok_or("unterminated character")?;
+            let characters: Vec<char> = quoted[..end].chars().collect();
+            self.position += end + 2;
+            return match characters[..] {
+                [c] if c.is_ascii() => Ok(c as u16),
+                [high, low] if high.is_ascii() && low.is_ascii() => Ok((high as u16) << 8 | low as u16),
+                _ => Err(format!("bad character constant '{}'", &quoted[..end])),
+            };
+        }
+        let length = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '?' || c == '@')).unwrap_or(rest.len());
+        let word = &rest[..length];
+        self.position += length;
+        match word.chars().next() {
+            None => Err(format!("missing value in {}", self.text)),
+            Some(first) if first.is_ascii_digit() => number(word, self.radix),
+            Some(_) => (self.lookup)(&word.to_ascii_uppercase()).ok_or_else(|| format!("undefined symbol {word}")),
+        }
+    }
+}
+
+fn number(word: &str, radix: u32) -> Result<u16, String> {
+    let upper = word.to_ascii_uppercase();
+    let (digits, radix) = match upper.as_bytes()[upper.len() - 1] {
+        b'H' => (&upper[..upper.len() - 1], 16),
+        _ if radix == 16 => (&upper[..], 16),
+        b'D' => (&upper[..upper.len() - 1], 10),
+        b'O' | b'Q' => (&upper[..upper.len() - 1], 8),
+        b'B' => (&upper[..upper.len() - 1], 2),
+        _ => (&upper[..], radix),
+    };
+    u32::from_str_radix(digits, radix)
+        .ok()
+        .filter(|&value| value <= 0xFFFF)
+        .map(|value| value as u16)
+        .ok_or_else(|| format!("bad number {word}"))
+}
+
+// A value that must fit in a byte; negative values down to -256 are taken as bytes too.
+pub fn byte(value: u16) -> Result<u8, String> {
+    match value {
+        0..=0xFF | 0xFF00..=0xFFFF => Ok(value as u8),
+        _ => Err(format!("{value:04X}h does not fit in a byte")),
+    }
+}
+
+// Assembles one instruction, such as "MVI A,42H", at `address`.
+pub fn encode(mnemonic: &str, operands: &[&str], address: u16, radix: u32, lookup: Lookup) -> Result<Vec<u8>, String> {
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let value = |operand: &str| evaluate(operand, address, radix, lookup);
+    let register = |operand: &str| -> Result<u8, String> {
+        match REGISTERS.iter().position(|name| operand.eq_ignore_ascii_case(name)) {
+            Some(index) => Ok(index as u8),
+            None => Some(value(operand)?).filter(|&value| value < 8).map(|value| value as u8).ok_or_else(|| format!("bad register {operand}")),
+        }
+    };
+    let pair = |operand: &str, allowed: &[&str]| -> Result<u8, String> {
+        match allowed.iter().position(|name| operand.eq_ignore_ascii_case(name)) {
+            Some(index) => Ok(index as u8),
+            None => Err(format!("bad register pair {operand} for {mnemonic}")),
+        }
+    };
+    let count = |expected: usize| match operands.len() == expected {
+        true => Ok(()),
+        false => Err(format!("{mnemonic} takes {expected} operand{}", if expected == 1 { "" } else { "s" })),
+    };
+    let word = |opcode: u8, operand: &str| -> Result<Vec<u8>, String> {
+        let [low, high] = value(operand)?.to_le_bytes();
+        Ok(vec![opcode, low, high])
+    };
+    let immediate = |opcode: u8, operand: &str| -> Result<Vec<u8>, String> { Ok(vec![opcode, byte(value(operand)?)?]) };
+    let alu = |names: &[&str]| names.iter().position(|name| *name == mnemonic).map(|index| index as u8);
+    let condition = |prefix: char| {
+        mnemonic.strip_prefix(prefix).and_then(|condition| CONDITIONS.iter().position(|name| *name == condition)).map(|index| index as u8)
+    };
+
+    if let Some(&(_, opcode)) = IMPLIED.iter().find(|(name, _)| *name == mnemonic) {
+        count(0)?;
+        return Ok(vec![opcode]);
+    }
+    if let Some(operation) = alu(&ALU) {
+        count(1)?;
+        return Ok(vec![0x80 | operation << 3 | register(operands[0])?]);
+    }
+    if let Some(operation) = alu(&ALU_IMMEDIATE) {
+        count(1)?;
+        return immediate(0xC6 | operation << 3, operands[0]);
+    }
+    match mnemonic.as_str() {
+        "MOV" => {
+            count(2)?;
+            let (destination, source) = (register(operands[0])?, register(operands[1])?);
+            if destination == 6 && source == 6 {
+                return Err("MOV M,M is HLT".to_string());
+            }
+            Ok(vec![0x40 | destination << 3 | source])
+        }
+        "MVI" => {
+            count(2)?;
+            immediate(0x06 | register(operands[0])? << 3, operands[1])
+        }
+        "INR" | "DCR" => {
+            count(1)?;
+            Ok(vec![if mnemonic == "INR" { 0x04 } else { 0x05 } | register(operands[0])? << 3])
+        }
+        "LXI" => {
+            count(2)?;
+            word(0x01 | pair(operands[0], &["B", "D", "H", "SP"])? << 4, operands[1])
+        }
+        "DAD" | "INX" | "DCX" => {
+            count(1)?;
+            let opcode = match mnemonic.as_str() {
+                "DAD" => 0x09,
+                "INX" => 0x03,
+                _ => 0x0B,
+            };
+            Ok(vec![opcode | pair(operands[0], &["B", "D", "H", "SP"])? << 4])
+        }
+        "PUSH" | "POP" => {
+            count(1)?;
+            let opcode = if mnemonic == "PUSH" { 0xC5 } else { 0xC1 };
+            Ok(vec![opcode | pair(operands[0], &["B", "D", "H", "PSW"])? << 4])
+        }
+        "STAX" | "LDAX" => {
+            count(1)?;
+            let opcode = if mnemonic == "STAX" { 0x02 } else { 0x0A };
+            Ok(vec![opcode | pair(operands[0], &["B", "D"])? << 4])
+        }
+        "STA" | "LDA" | "SHLD" | "LHLD" | "JMP" | "CALL" => {
+            count(1)?;
+            let opcode = match mnemonic.as_str() {
+                "STA" => 0x32,
+                "LDA" => 0x3A,
+                "SHLD" => 0x22,
+                "LHLD" => 0x2A,
+                "JMP" => 0xC3,
+                _ => 0xCD,
+            };
+            word(opcode, operands[0])
+        }
+        "IN" | "OUT" => {
+            count(1)?;
+            immediate(if mnemonic == "IN" { 0xDB } else { 0xD3 }, operands[0])
+        }
+        "RST" => {
+            count(1)?;
+            match value(operands[0])? {
+                vector @ 0..=7 => Ok(vec![0xC7 | (vector as u8) << 3]),
+                vector => Err(format!("bad restart {vector}")),
+            }
+        }
+        _ => {
+            if let Some(condition) = condition('J') {
+                count(1)?;
+                word(0xC2 | condition << 3, operands[0])
+            } else if let Some(condition) = condition('C') {
+                count(1)?;
+                word(0xC4 | condition << 3, operands[0])
+            } else if let Some(condition) = condition('R') {
+                count(0)?;
+                Ok(vec![0xC0 | condition << 3])
+            } else {
+                Err(format!("unknown instruction {mnemonic}"))
+            }
+        }
+    }
+}
+
+// Assembles an instruction written on one line, as a monitor's A command takes it.
+pub fn assemble_line(line: &str, address: u16, radix: u32, lookup: Lookup) -> Result<Vec<u8>, String> {
+    let line = line.split(';').next().unwrap_or("").trim();
+    let (mnemonic, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    encode(mnemonic, &operands(rest), address, radix, lookup)
+}
+
+// A program assembled from source: its bytes and entry point, its labels and
+// EQUs, and a listing laid out like CP/M ASM's .PRN files.
+#[derive(Clone, Debug)]
+pub struct Assembly {
+    pub image: Image,
+    pub symbols: Symbols,
+    pub listing: String,
+}
+
+// Assembles source in the style of CP/M's ASM: a label, with or without a
+// colon, then an instruction or one of ORG, EQU, SET, DB, DW, DS and END, and
+// a comment after ;. A line starting with * is a comment too. Assembly starts
+// at `origin`, and `predefined` symbols can be used as if given with EQU.
+// Errors from every line are reported together.
+pub fn assemble(source: &str, origin: u16, predefined: &Symbols) -> io::Result<Assembly> {
+    let mut assembler = Assembler { values: HashMap::new(), sets: HashSet::new(), final_pass: false, address: origin };
+    for (address, name) in predefined.iter() {
+        assembler.values.insert(name.to_ascii_uppercase(), address);
+    }
+    let statements: Vec<_> = source.lines().map(Statement::parse).collect();
+    let mut errors = BTreeMap::new(); // the first for each line
+    for (number, statement) in statements.iter().enumerate() {
+        // Other errors are left to the second pass, when forward references
+        // have values.
+        let start = assembler.address;
+        let result = assembler.operation(statement);
+        if let Err(error) = assembler.define(statement, start, &result) {
+            errors.insert(number + 1, error);
+        }
+        if statement.operation == "END" {
+            break;
+        }
+    }
+
+    assembler.final_pass = true;
+    assembler.address = origin;
+    let mut assembly = Assembly { image: Image::new(), symbols: Symbols::new(), listing: String::new() };
+    let mut ended = false;
+    for (number, (line, statement)) in source.lines().zip(&statements).enumerate() {
+        let mut list = |columns: String, text: &str| {
+            let line = format!("{columns:16}{text}");
+            assembly.listing.push_str(line.trim_end());
+            assembly.listing.push_str("\r\n");
+        };
+        if ended {
+            list(String::new(), line);
+            continue;
+        }
+        // Nothing after END is assembled, even if its operand is wrong.
+        ended = statement.operation == "END";
+        let listed = match assembler.statement(statement) {
+            Ok(listed) => listed,
+            Err(error) => {
+                errors.entry(number + 1).or_insert(error);
+                Listed::Nothing
+            }
+        };
+        match listed {
+            Listed::Nothing => list(String::new(), line),
+            Listed::Address(address) => list(format!("{address:04X}"), line),
+            Listed::Value(value) => list(format!("{value:04X} ="), line),
+            Listed::Bytes(address, bytes) => {
+                for (index, chunk) in bytes.chunks(4).enumerate() {
+                    let hex: String = chunk.iter().map(|byte| format!("{byte:02X}")).collect();
+                    list(format!("{:04X} {hex}", address.wrapping_add(index as u16 * 4)), if index == 0 { line } else { "" });
+                }
+                assembly.image.insert(address, &bytes);
+            }
+            Listed::End(entry) => {
+                assembly.image.entry = entry;
+                list(String::new(), line);
+            }
+        }
+        if let (Some(label), false) = (&statement.label, statement.operation == "SET") {
+            let name = label.to_ascii_uppercase();
+            if let Some(&value) = assembler.values.get(&name) {
+                assembly.symbols.insert(&name, value);
+            }
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(assembly),
+        false => Err(invalid(errors.iter().map(|(number, error)| format!("line {number}: {error}")).collect::<Vec<_>>().join("\n"))),
+    }
+}
+
+// One line of source, split into its parts.
+struct Statement {
+    label: Option<String>,
+    operation: String,
+    operands: String,
+}
+
+impl Statement {
+    fn parse(line: &str) -> Self {
+        let mut quoted = false;
+        let end = line
+            .char_indices()
+            .find(|&(_, c)| {
+                quoted ^= c == '\'';
+                c == ';' && !quoted
+            })
+            .map_or(line.len(), |(index, _)| index);
+        let text = if line.starts_with('*') { "" } else { line[..end].trim_end() };
+        let mut words = text.split_whitespace();
+        let (first, second) = (words.next().unwrap_or(""), words.next().unwrap_or(""));
+        let labelled = first.ends_with(':')
+            || !text.starts_with(char::is_whitespace) && !first.is_empty() && !is_operation(first)
+            || ["EQU", "SET"].iter().any(|directive| second.eq_ignore_ascii_case(directive));
+        let (label, rest) = match labelled {
+            true => (Some(first.trim_end_matches(':').to_string()), text.trim_start()[first.len()..].trim()),
+            false => (None, text.trim()),
+        };
+        let (operation, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        Self { label, operation: operation.to_ascii_uppercase(), operands: operands.trim().to_string() }
+    }
+}
+
+fn is_operation(word: &str) -> bool {
+    const OPERATIONS: [&str; 28] = [
+        "MOV", "MVI", "INR", "DCR", "LXI", "DAD", "INX", "DCX", "PUSH", "POP", "STAX", "LDAX", "STA", "LDA", "SHLD", "LHLD",
+        "JMP", "CALL", "IN", "OUT", "RST", "ORG", "EQU", "SET", "DB", "DW", "DS", "END",
+    ];
+    let word = word.to_ascii_uppercase();
+    let conditional = ['J', 'C', 'R'].iter().any(|&prefix| word.strip_prefix(prefix).is_some_and(|condition| CONDITIONS.contains(&condition)));
+    OPERATIONS.contains(&word.as_str())
+        || IMPLIED.iter().any(|(name, _)| *name == word)
+        || ALU.contains(&word.as_str())
+        || ALU_IMMEDIATE.contains(&word.as_str())
+        || conditional
+}
+
+// What a statement puts in the listing's address and bytes columns.
+enum Listed {
+    Nothing,
+    Address(u16),
+    Value(u16),
+    Bytes(u16, Vec<u8>),
+    End(Option<u16>),
+}
+
+struct Assembler {
+    values: HashMap<String, u16>,
+    sets: HashSet<String>, // names given with SET, which can be given again
+    final_pass: bool,
+    address: u16,
+}
+
+impl Assembler {
+    // In the first pass a symbol not yet defined counts as 0 so that
+    // addresses can still be worked out.
+    fn evaluate(&self, text: &str) -> Result<u16, String> {
+        let final_pass = self.final_pass;
+        let lookup = |name: &str| self.values.get(name).copied().or((!final_pass).then_some(0));
+        evaluate(text, self.address, 10, &lookup)
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<Listed, String> {
+        let start = self.address;
+        let result = self.operation(statement);
+        self.define(statement, start, &result)?;
+        result
+    }
+
+    // Defines a statement's label, even if the rest of the line is wrong so
+    // that only that line is reported.
+    fn define(&mut self, statement: &Statement, start: u16, result: &Result<Listed, String>) -> Result<(), String> {
+        if let Some(label) = &statement.label {
+            let name = label.to_ascii_uppercase();
+            let value = match (statement.operation.as_str(), result) {
+                ("ORG", _) => self.address,
+                (_, Ok(Listed::Value(value))) => *value,
+                _ => start,
+            };
+            if statement.operation == "SET" {
+                self.sets.insert(name.clone());
+                self.values.insert(name, value);
+            } else if !self.final_pass {
+                if self.values.contains_key(&name) {
+                    return Err(format!("{label} is already defined"));
+                }
+                self.values.insert(name, value);
+            } else if self.values.get(&name) != Some(&value) {
+                return Err(format!("phase error at {label}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn operation(&mut self, statement: &Statement) -> Result<Listed, String> {
+        let operands = operands(&statement.operands);
+        let start = self.address;
+        let listed = match statement.operation.as_str() {
+            "" if statement.label.is_none() => Listed::Nothing,
+            "" => Listed::Address(start),
+            "EQU" | "SET" if statement.label.is_none() => return Err(format!("{} needs a label", statement.operation)),
+            "EQU" | "SET" => Listed::Value(self.evaluate(&statement.operands)?),
+            "ORG" => {
+                self.address = self.evaluate(&statement.operands)?;
+                Listed::Nothing
+            }
+            "DS" => {
+                self.address = start.wrapping_add(self.evaluate(&statement.operands)?);
+                Listed::Address(start)
+            }
+            "END" => Listed::End(match statement.operands.is_empty() {
+                true => None,
+                false => Some(self.evaluate(&statement.operands)?),
+            }),
+            "DB" | "DW" => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    let string = operand.strip_prefix('\'').and_then(|text| text.strip_suffix('\'')).filter(|_| operand.len() > 2);
+                    match (statement.operation.as_str(), string) {
+                        ("DB", Some(string)) => bytes.extend(string.replace("''", "'").bytes()),
+                        ("DB", None) => bytes.push(byte(self.evaluate(operand)?)?),
+                        _ => bytes.extend(self.evaluate(operand)?.to_le_bytes()),
+                    }
+                }
+                Listed::Bytes(start, bytes)
+            }
+            operation => {
+                let final_pass = self.final_pass;
+                let lookup = |name: &str| self.values.get(name).copied().or((!final_pass).then_some(0));
+                Listed::Bytes(start, encode(operation, &operands, start, 10, &lookup)?)
+            }
+        };
+        if let Listed::Bytes(_, bytes) = &listed {
+            self.address = start.wrapping_add(bytes.len() as u16);
+        }
+
+        Ok(listed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coverage::Listing;
+    use crate::disasm;
+
+    #[test]
+    fn expressions() {
+        let symbols = Symbols::parse("0005 BDOS 0100 START").unwrap();
+        let lookup = super::symbols(&symbols);
+        let value = |text: &str| evaluate(text, 0x0200, 10, &lookup);
+        assert_eq!(value("10"), Ok(10));
+        assert_eq!(value("0FFH"), Ok(0xFF));
+        assert_eq!(value("101B + 17Q"), Ok(5 + 15));
+        assert_eq!(value("start+2*(bdos-1)"), Ok(0x0108));
+        assert_eq!(value("'A'"), Ok(0x41));
+        assert_eq!(value("-1"), Ok(0xFFFF));
+        assert_eq!(value("$-10H"), Ok(0x01F0));
+        assert!(value("UNKNOWN").unwrap_err().contains("undefined"));
+        assert!(value("1/0").is_err());
+        assert!(value("(1").is_err());
+        assert_eq!(evaluate("1BD", 0, 16, &lookup), Ok(0x01BD));
+    }
+
+    #[test]
+    fn instructions() {
+        let lookup = |_: &str| None;
+        let assemble = |line: &str| assemble_line(line, 0x0100, 10, &lookup);
+        assert_eq!(assemble("MVI A,42H"), Ok(vec![0x3E, 0x42]));
+        assert_eq!(assemble("mov m,a ; store"), Ok(vec![0x77]));
+        assert_eq!(assemble("LXI SP,$"), Ok(vec![0x31, 0x00, 0x01]));
+        assert_eq!(assemble("PUSH PSW"), Ok(vec![0xF5]));
+        assert_eq!(assemble("CM 1234H"), Ok(vec![0xFC, 0x34, 0x12]));
+        assert_eq!(assemble("CMP M"), Ok(vec![0xBE]));
+        assert_eq!(assemble("RPE"), Ok(vec![0xE8]));
+        assert_eq!(assemble("RST 7"), Ok(vec![0xFF]));
+        assert_eq!(assemble("CPI ','"), Ok(vec![0xFE, 0x2C]));
+        assert!(assemble("MVI A,100H").is_err());
+        assert!(assemble("STAX H").is_err());
+        assert!(assemble("MOV A").is_err());
+        assert!(assemble("FOO").is_err());
+
+        // Everything the disassembler writes assembles back to the same bytes.
+        for opcode in (0..=0xFF).filter(|&opcode| disasm::documented(opcode) == opcode) {
+            let bytes = [opcode, 0x34, 0x12];
+            let instruction = disasm::decode(bytes, None);
+            assert_eq!(assemble(&instruction.text).unwrap(), bytes[..instruction.length], "{}", instruction.text);
+        }
+    }
+
+    #[test]
+    fn programs() {
+        let source = "\
+* Prints a message through BDOS
+\tORG\t100H
+START:\tLXI\tD,MESSAGE
+\tMVI\tC,PRINT
+\tCALL\tBDOS\t; forward reference
+\tJMP\tDONE
+MESSAGE\tDB\t'It''s OK',13,10,'$'
+COUNT\tSET\t1
+COUNT\tSET\tCOUNT+1
+TABLE:\tDW\tSTART,COUNT
+\tDS\t2
+DONE:\tRET
+PRINT\tEQU\t9
+\tEND\tSTART
+\tNOP
+";
+        let assembly = assemble(source, 0, &Symbols::parse("0005 BDOS").unwrap()).unwrap();
+        let message: Vec<u8> = b"It's OK\r\n$".to_vec();
+        let mut code = vec![0x11, 0x0B, 0x01, 0x0E, 0x09, 0xCD, 0x05, 0x00, 0xC3, 0x1B, 0x01];
+        code.extend(&message);
+        code.extend([0x00, 0x01, 0x02, 0x00]);
+        assert_eq!(assembly.image, Image { segments: vec![(0x0100, code), (0x011B, vec![0xC9])], entry: Some(0x0100) });
+        assert_eq!(assembly.symbols.to_sym(), "0009 PRINT\t0100 START\t010B MESSAGE\t0115 TABLE\r\n011B DONE\r\n");
+
+        let listing: Vec<_> = assembly.listing.lines().collect();
+        assert_eq!(listing[0], "                * Prints a message through BDOS");
+        assert_eq!(listing[2], "0100 110B01     START:\tLXI\tD,MESSAGE");
+        assert_eq!(listing[6], "010B 49742773   MESSAGE\tDB\t'It''s OK',13,10,'$'");
+        assert_eq!(listing[7], "010F 204F4B0D");
+        assert_eq!(listing[10], "0002 =          COUNT\tSET\tCOUNT+1");
+        assert_eq!(listing[12], "0119            \tDS\t2");
+        assert_eq!(listing[16], "                \tNOP");
+        let lines = Listing::parse("HELLO.ASM", &assembly.listing).lines;
+        assert_eq!(lines.iter().map(|line| (line.number, line.address, line.length, line.code)).take(5).collect::<Vec<_>>(),
+            [(3, 0x0100, 3, true), (4, 0x0103, 2, true), (5, 0x0105, 3, true), (6, 0x0108, 3, true), (7, 0x010B, 10, false)]);
+
+        let error = assemble("A: NOP\nA: NOP\n JMP NOWHERE\n MVI A,300\n", 0, &Symbols::new()).unwrap_err().to_string();
+        assert_eq!(error, "line 2: A is already defined\nline 3: undefined symbol NOWHERE\nline 4: 012Ch does not fit in a byte");
+        let error = assemble("\tEND\tNOWHERE\nX:\tNOP\n", 0, &Symbols::new()).unwrap_err().to_string();
+        assert_eq!(error, "line 1: undefined symbol NOWHERE");
+    }
+}
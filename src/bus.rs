@@ -0,0 +1,196 @@
+use std::io;
+use std::ops::RangeInclusive;
+
+use crate::state::{Field, Reader, Writer};
+
+// Status bits placed on the data bus at SYNC (T1) of every machine cycle.
+pub const INTA: u8 = 0x01;
+pub const WO: u8 = 0x02; // active low: cleared for memory write and output cycles
+pub const STACK: u8 = 0x04;
+pub const HLTA: u8 = 0x08;
+pub const OUT: u8 = 0x10;
+pub const M1: u8 = 0x20;
+pub const INP: u8 = 0x40;
+pub const MEMR: u8 = 0x80;
+
+// Status words of the ten machine cycle types.
+pub const INSTRUCTION_FETCH: u8 = MEMR | M1 | WO;
+pub const MEMORY_READ: u8 = MEMR | WO;
+pub const MEMORY_WRITE: u8 = 0;
+pub const STACK_READ: u8 = MEMR | STACK | WO;
+pub const STACK_WRITE: u8 = STACK;
+pub const INPUT_READ: u8 = INP | WO;
+pub const OUTPUT_WRITE: u8 = OUT;
+pub const INTERRUPT_ACKNOWLEDGE: u8 = INTA | M1 | WO;
+pub const HALT_ACKNOWLEDGE: u8 = HLTA | MEMR | WO;
+pub const INTERRUPT_ACKNOWLEDGE_WHILE_HALT: u8 = INTA | HLTA | M1 | WO;
+
+// One machine cycle as seen on the pins. SYNC is high during T1 with the
+// status word on the data bus, the address is valid from T2, DBIN is high
+// from T2 through T3 of a read and WR is low during T3 of a write. Wait
+// states (TW) come between T2 and T3.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MachineCycle {
+    pub number: u8, // M1..M5 within the instruction
+    pub start: u64, // T-state of T1
+    pub states: usize,
+    pub wait: usize,
+    pub status: u8,
+    pub address: u16,
+    pub data: u8,
+    pub inte: bool,
+}
+
+impl MachineCycle {
+    pub fn dbin(&self) -> bool {
+        self.status & WO != 0 && self.status != HALT_ACKNOWLEDGE
+    }
+
+    pub fn wr(&self) -> bool {
+        self.status & WO == 0
+    }
+}
+
+impl Field for MachineCycle {
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.number);
+        state.put(&self.start);
+        state.put(&self.states);
+        state.put(&self.wait);
+        state.put(&self.status);
+        state.put(&self.address);
+        state.put(&self.data);
+        state.put(&self.inte);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(Self {
+            number: state.get()?,
+            start: state.get()?,
+            states: state.get()?,
+            wait: state.get()?,
+            status: state.get()?,
+            address: state.get()?,
+            data: state.get()?,
+            inte: state.get()?,
+        })
+    }
+}
+
+pub trait BusObserver {
+    fn machine_cycle(&mut self, cycle: &MachineCycle);
+
+    // HLDA is high from T-state `start` for `states` T-states.
+    fn hold(&mut self, _start: u64, _states: usize) {}
+}
+
+pub trait Ready {
+    // Wait states to insert into `cycle`; its timing fields are not yet set.
+    fn wait_states(&mut self, cycle: &MachineCycle) -> usize;
+}
+
+#[derive(Default)]
+pub struct WaitStates {
+    fetch: usize,
+    memory: Vec<(RangeInclusive<u16>, usize)>,
+    ports: Vec<(RangeInclusive<u8>, usize)>,
+}
+
+impl WaitStates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Extra wait states for M1, as on boards that insert one for every opcode fetch.
+    pub fn set_fetch(&mut self, states: usize) {
+        self.fetch = states;
+    }
+
+    pub fn add_memory(&mut self, range: RangeInclusive<u16>, states: usize) {
+        self.memory.push((range, states));
+    }
+
+    pub fn add_ports(&mut self, range: RangeInclusive<u8>, states: usize) {
+        self.ports.push((range, states));
+    }
+}
+
+impl Ready for WaitStates {
+    fn wait_states(&mut self, cycle: &MachineCycle) -> usize {
+        if cycle.status & (INP | OUT) != 0 {
+            let port = cycle.address as u8;
+            return self.ports.iter().filter(|(range, _)| range.contains(&port)).map(|(_, states)| states).sum();
+        }
+        if cycle.status & (INTA | HLTA) != 0 {
+            return 0;
+        }
+        let fetch = if cycle.status & M1 != 0 { self.fetch } else { 0 };
+        let memory: usize = self.memory.iter().filter(|(range, _)| range.contains(&cycle.address)).map(|(_, states)| states).sum();
+        fetch + memory
+    }
+}
+
+pub trait DmaMaster {
+    // Level of the HOLD input, sampled between machine cycles.
+    fn hold(&self) -> bool;
+
+    // Runs once HLDA is granted and returns the T-states the transfer took.
+    fn transfer(&mut self, memory: &mut [u8]) -> usize;
+}
+
+// Instructions that spend an extra T-state in M1 before their next machine cycle.
+pub(crate) fn fetch_states(opcode: u8) -> usize {
+    match opcode {
+        0x40..=0x7F if opcode & 0x07 != 0x06 && opcode & 0x38 != 0x30 => 5, // MOV r,r
+        0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x3C => 5,                  // INR r
+        0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x3D => 5,                  // DCR r
+        0x03 | 0x13 | 0x23 | 0x33 | 0x0B | 0x1B | 0x2B | 0x3B => 5,           // INX/DCX
+        0xF9 | 0xE9 => 5,                                                     // SPHL/PCHL
+        0xC0..=0xFF if opcode & 0x07 == 0x00 => 5,                            // Rcc
+        0xC0..=0xFF if opcode & 0x07 == 0x04 => 5,                            // Ccc
+        0xC0..=0xFF if opcode & 0x07 == 0x07 => 5,                            // RST
+        0xC5 | 0xD5 | 0xE5 | 0xF5 => 5,                                       // PUSH
+        0xCD | 0xDD | 0xED | 0xFD => 5,                                       // CALL
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins() {
+        let mut cycle = MachineCycle { status: STACK_WRITE, ..Default::default() };
+        assert!(cycle.wr() && !cycle.dbin());
+        cycle.status = HALT_ACKNOWLEDGE;
+        assert!(!cycle.wr() && !cycle.dbin());
+        cycle.status = INTERRUPT_ACKNOWLEDGE_WHILE_HALT;
+        assert!(cycle.dbin());
+    }
+
+    #[test]
+    fn wait_states() {
+        let mut wait_states = WaitStates::new();
+        wait_states.set_fetch(1);
+        wait_states.add_memory(0xE000..=0xFFFF, 2);
+        wait_states.add_ports(0x10..=0x11, 3);
+        let mut cycle = MachineCycle { status: INSTRUCTION_FETCH, address: 0xE800, ..Default::default() };
+        assert_eq!(wait_states.wait_states(&cycle), 3);
+        cycle.status = MEMORY_WRITE;
+        assert_eq!(wait_states.wait_states(&cycle), 2);
+        cycle.address = 0x1111;
+        assert_eq!(wait_states.wait_states(&cycle), 0);
+        cycle.status = OUTPUT_WRITE;
+        assert_eq!(wait_states.wait_states(&cycle), 3);
+    }
+    #[test]
+    fn fetch_states() {
+        assert_eq!(super::fetch_states(0x41), 5); // MOV B,C
+        assert_eq!(super::fetch_states(0x46), 4); // MOV B,M
+        assert_eq!(super::fetch_states(0x70), 4); // MOV M,B
+        assert_eq!(super::fetch_states(0xC8), 5); // RZ
+        assert_eq!(super::fetch_states(0xC9), 4); // RET
+        assert_eq!(super::fetch_states(0xFF), 5); // RST 7
+    }
+}
@@ -0,0 +1,313 @@
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::mem;
+
+use crate::bus::{self, BusObserver, MachineCycle};
+use crate::disasm::{self, is_call, is_return};
+use crate::symbols::Symbols;
+use crate::I8080;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entry {
+    Call,
+    Restart,
+    Interrupt,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub entry: Entry,
+    pub site: u16, // the CALL or RST, or the instruction an interrupt came before
+    pub target: u16,
+    pub return_address: u16,
+    pub slot: u16, // where the return address is on the stack
+}
+
+// Stack handling that broke the pairing of calls and returns.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Anomaly {
+    pub ticks: u64,
+    pub pc: u16,
+    pub reason: String,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "call stack broken at T-state {}: {}", self.ticks, self.reason)
+    }
+}
+
+impl Error for Anomaly {}
+
+// The instruction in progress as seen on the bus.
+#[derive(Default)]
+struct Instruction {
+    pc: u16,
+    ticks: u64,
+    opcode: u8,
+    interrupt: bool,
+    stack_reads: Vec<(u16, u8)>,
+    stack_writes: Vec<(u16, u8)>,
+    writes: Vec<u16>,
+}
+
+// A shadow of the call stack, kept from the stack traffic on the bus: CALL,
+// RST and interrupts push a frame and returns pop it. Code that moves the
+// stack or handles return addresses itself is reported as an anomaly, and
+// the frames it made meaningless are dropped. The stack in place when
+// tracking began is unknown, so returns into it pass without comment.
+//
+// A frame is only pushed or popped when the next instruction starts, or on
+// `update`, since a call's target is the address fetched next.
+#[derive(Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    anomalies: Vec<Anomaly>,
+    instruction: Option<Instruction>,
+    moved: Option<(u16, &'static str)>, // the last SPHL or LXI SP
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Innermost last.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn anomalies(&self) -> &[Anomaly] {
+        &self.anomalies
+    }
+
+    pub fn take_anomalies(&mut self) -> Vec<Anomaly> {
+        mem::take(&mut self.anomalies)
+    }
+
+    // Applies the instruction last run between steps; `pc` is the next to run.
+    pub fn update(&mut self, pc: u16) {
+        let Some(instruction) = self.instruction.take() else {
+            return;
+        };
+        let name = || disasm::decode([instruction.opcode, 0, 0], None).text;
+        let opcode = disasm::documented(instruction.opcode);
+        match opcode {
+            0xF9 => self.moved = Some((instruction.pc, "SPHL")),
+            0x31 => self.moved = Some((instruction.pc, "LXI SP")),
+            _ => {}
+        }
+        for &address in &instruction.writes {
+            if let Some(frame) = self.frames.iter().find(|frame| address.wrapping_sub(frame.slot) < 2) {
+                let reason = format!("store at {:04X}h overwrote the return address to {:04X}h", instruction.pc, frame.return_address);
+                self.report(&instruction, reason);
+            }
+        }
+
+        if let [(slot, low), (_, high)] = instruction.stack_reads[..] {
+            let value = u16::from_le_bytes([low, high]);
+            if is_return(opcode) {
+                self.abandon(&instruction, |frame| frame.slot < slot, &name());
+                match self.frames.last() {
+                    Some(frame) if frame.slot == slot => {
+                        if frame.return_address != value {
+                            let reason = format!("{} at {:04X}h returned to {value:04X}h, not {:04X}h", name(), instruction.pc, frame.return_address);
+                            self.report(&instruction, reason);
+                        }
+                        self.frames.pop();
+                    }
+                    Some(_) => {
+                        let reason = format!("{} at {:04X}h returned to pushed address {value:04X}h", name(), instruction.pc);
+                        self.report(&instruction, reason);
+                    }
+                    None => {}
+                }
+            } else if opcode == 0xE3 {
+                if let [(_, high), (_, low)] = instruction.stack_writes[..] {
+                    let swapped = u16::from_le_bytes([low, high]);
+                    if let Some(index) = self.frames.iter().position(|frame| frame.slot == slot) {
+                        let reason = format!("XTHL at {:04X}h replaced the return address {value:04X}h with {swapped:04X}h", instruction.pc);
+                        self.report(&instruction, reason);
+                        self.frames[index].return_address = swapped;
+                    }
+                }
+            } else if let Some(frame) = self.frames.iter().find(|frame| frame.slot == slot) {
+                let reason = format!("{} at {:04X}h took the return address to {:04X}h", name(), instruction.pc, frame.return_address);
+                self.report(&instruction, reason);
+                self.frames.retain(|frame| frame.slot > slot);
+            } else {
+                self.abandon(&instruction, |frame| frame.slot < slot, &name());
+            }
+        }
+
+        if opcode != 0xE3 {
+            if let Some(&(slot, _)) = instruction.stack_writes.iter().min_by_key(|(address, _)| *address) {
+                // The stack pointer was two above the lowest write.
+                self.abandon(&instruction, |frame| frame.slot < slot.wrapping_add(2), &name());
+            }
+            if let ([(_, high), (slot, low)], true) = (&instruction.stack_writes[..], is_call(opcode)) {
+                let entry = match instruction.interrupt {
+                    true => Entry::Interrupt,
+                    false if opcode & 0xC7 == 0xC7 => Entry::Restart,
+                    false => Entry::Call,
+                };
+                let return_address = u16::from_le_bytes([*low, *high]);
+                let site = if instruction.interrupt { return_address } else { instruction.pc };
+                self.frames.push(Frame { entry, site, target: pc, return_address, slot: *slot });
+            }
+        }
+    }
+
+    // One line per frame, innermost first, naming addresses from `symbols`.
+    pub fn backtrace(&mut self, i8080: &I8080, symbols: Option<&Symbols>) -> String {
+        self.update(i8080.pc());
+        let format = |address: u16| symbols.map_or_else(|| format!("{address:04X}h"), |symbols| symbols.format(address));
+        let mut text = String::new();
+        writeln!(text, "#0  {}", format(i8080.pc())).unwrap();
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            let entry = match frame.entry {
+                Entry::Call => "called",
+                Entry::Restart => "restarted",
+                Entry::Interrupt => "interrupted",
+            };
+            writeln!(text, "#{}  {}  {entry} {}", depth + 1, format(frame.site), format(frame.target)).unwrap();
+        }
+        text
+    }
+
+    // Drops the frames the stack pointer has moved past.
+    fn abandon(&mut self, instruction: &Instruction, abandoned: impl Fn(&Frame) -> bool, name: &str) {
+        let Some(index) = self.frames.iter().position(abandoned) else {
+            return;
+        };
+        for frame in self.frames.split_off(index) {
+            let mut reason = format!("{name} at {:04X}h abandoned the return to {:04X}h", instruction.pc, frame.return_address);
+            if let Some((pc, moved)) = self.moved {
+                write!(reason, " after {moved} at {pc:04X}h").unwrap();
+            }
+            self.report(instruction, reason);
+        }
+    }
+
+    fn report(&mut self, instruction: &Instruction, reason: String) {
+        self.anomalies.push(Anomaly { ticks: instruction.ticks, pc: instruction.pc, reason });
+    }
+}
+
+impl BusObserver for CallStack {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) {
+        if cycle.number == 1 && cycle.status & bus::M1 != 0 {
+            self.update(cycle.address);
+            self.instruction = Some(Instruction {
+                pc: cycle.address,
+                ticks: cycle.start,
+                opcode: cycle.data,
+                interrupt: cycle.status & bus::INTA != 0,
+                ..Default::default()
+            });
+            return;
+        }
+        let Some(instruction) = self.instruction.as_mut() else {
+            return;
+        };
+        match cycle.status {
+            bus::STACK_READ => instruction.stack_reads.push((cycle.address, cycle.data)),
+            bus::STACK_WRITE => instruction.stack_writes.push((cycle.address, cycle.data)),
+            bus::MEMORY_WRITE => instruction.writes.push(cycle.address),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // 0000: LXI SP,0100h; CALL 0010h; CALL 0030h; HLT
+    // 0010: CALL 0020h; RET
+    // 0020: POP H; PCHL
+    // 0030: LXI H,0040h; XTHL; RET
+    // 0040: CALL 0050h; HLT
+    // 0050: LXI H,0100h; SPHL; PUSH B; HLT
+    fn machine() -> (I8080, Rc<RefCell<CallStack>>) {
+        let mut i8080 = I8080::new(1024);
+        let code: [(u16, &[u8]); 6] = [
+            (0x0000, &[0x31, 0x00, 0x01, 0xCD, 0x10, 0x00, 0xCD, 0x30, 0x00, 0x76]),
+            (0x0010, &[0xCD, 0x20, 0x00, 0xC9]),
+            (0x0020, &[0xE1, 0xE9]),
+            (0x0030, &[0x21, 0x40, 0x00, 0xE3, 0xC9]),
+            (0x0040, &[0xCD, 0x50, 0x00, 0x76]),
+            (0x0050, &[0x21, 0x00, 0x01, 0xF9, 0xC5, 0x76]),
+        ];
+        for (start, bytes) in code {
+            for (offset, &value) in bytes.iter().enumerate() {
+                i8080.poke(start + offset as u16, value);
+            }
+        }
+        let calls = Rc::new(RefCell::new(CallStack::new()));
+        i8080.observe(calls.clone());
+        (i8080, calls)
+    }
+
+    #[test]
+    fn backtrace() {
+        let (mut i8080, calls) = machine();
+        while i8080.pc() != 0x0020 {
+            i8080.step();
+        }
+        let symbols = Symbols::parse("0000 MAIN 0010 OUTER 0020 INNER").unwrap();
+        let backtrace = calls.borrow_mut().backtrace(&i8080, Some(&symbols));
+        assert_eq!(backtrace, "#0  INNER\n#1  OUTER  called INNER\n#2  MAIN+3  called OUTER\n");
+        let frames = calls.borrow().frames().to_vec();
+        assert_eq!(frames[1], Frame { entry: Entry::Call, site: 0x0010, target: 0x0020, return_address: 0x0013, slot: 0x00FC });
+    }
+
+    #[test]
+    fn anomalies() {
+        let (mut i8080, calls) = machine();
+        while !i8080.halted() {
+            i8080.step();
+        }
+        let mut calls = calls.borrow_mut();
+        calls.update(i8080.pc());
+        let reasons: Vec<_> = calls.anomalies().iter().map(|anomaly| (anomaly.pc, anomaly.reason.as_str())).collect();
+        assert_eq!(
+            reasons,
+            [
+                (0x0020, "POP H at 0020h took the return address to 0013h"),
+                (0x0033, "XTHL at 0033h replaced the return address 0009h with 0040h"),
+                (0x0054, "PUSH B at 0054h abandoned the return to 0043h after SPHL at 0053h"),
+            ]
+        );
+        assert_eq!(calls.depth(), 0);
+    }
+
+    #[test]
+    fn interrupt() {
+        // EI; loop: JMP loop; at 0038h: RET
+        let mut i8080 = I8080::new(1024);
+        for (location, &value) in [0x31, 0x00, 0x01, 0xFB, 0xC3, 0x04, 0x00].iter().enumerate() {
+            i8080.poke(location as u16, value);
+        }
+        i8080.poke(0x0038, 0xC9);
+        let calls = Rc::new(RefCell::new(CallStack::new()));
+        i8080.observe(calls.clone());
+        for _ in 0..3 {
+            i8080.step();
+        }
+        i8080.interrupt(0xFF);
+        i8080.step();
+        let backtrace = calls.borrow_mut().backtrace(&i8080, None);
+        assert_eq!(backtrace, "#0  0038h\n#1  0004h  interrupted 0038h\n");
+        i8080.step();
+        calls.borrow_mut().update(i8080.pc());
+        assert_eq!(calls.borrow().depth(), 0);
+        assert!(calls.borrow().anomalies().is_empty());
+    }
+}
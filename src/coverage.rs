@@ -0,0 +1,293 @@
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use crate::bus::{self, BusObserver, MachineCycle};
+use crate::disasm;
+use crate::symbols::Symbols;
+use crate::I8080;
+
+// How each byte of memory was used, as bits of a map entry.
+pub const OPCODE: u8 = 0x01; // fetched as the first byte of an instruction
+pub const OPERAND: u8 = 0x02; // read as the immediate data or address of one
+pub const READ: u8 = 0x04; // read as data, including off the stack
+pub const WRITE: u8 = 0x08;
+
+// Records how the CPU used each byte of memory. Only the CPU's own accesses
+// are seen: bytes moved by DMA and instructions supplied during an interrupt
+// acknowledge are not counted.
+pub struct Coverage {
+    map: Vec<u8>,
+    executions: Vec<u64>,
+    operands: (u16, u16), // first address and count of the operands being fetched
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self { map: vec![0; 0x10000], executions: vec![0; 0x10000], operands: (0, 0) }
+    }
+
+    pub fn flags(&self, address: u16) -> u8 {
+        self.map[address as usize]
+    }
+
+    // Times an instruction was fetched from `address`.
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executions[address as usize]
+    }
+
+    // The whole map, a byte of flags per address, for other tools.
+    pub fn map(&self) -> &[u8] {
+        &self.map
+    }
+
+    // The map as text, 64 addresses to a line: x opcode, o operand, r read,
+    // w written, m read and written, . untouched.
+    pub fn format(&self, range: RangeInclusive<u16>) -> String {
+        let mut text = String::new();
+        for address in range.clone() {
+            if address == *range.start() || address % 64 == 0 {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                write!(text, "{address:04X} ").unwrap();
+            }
+            let flags = self.flags(address);
+            text.push(match flags {
+                _ if flags & OPCODE != 0 => 'x',
+                _ if flags & OPERAND != 0 => 'o',
+                _ if flags & (READ | WRITE) == READ | WRITE => 'm',
+                _ if flags & READ != 0 => 'r',
+                _ if flags & WRITE != 0 => 'w',
+                _ => '.',
+            });
+        }
+        text.push('\n');
+        text
+    }
+
+    // Disassembles `range` from the CPU's memory using what was executed to
+    // tell code from data. Each line is marked in the first column:
+    //   blank  executed code
+    //   !      never executed, disassembled as code
+    //   d      data, read or written but never executed
+    pub fn annotate(&self, i8080: &I8080, range: RangeInclusive<u16>, symbols: Option<&Symbols>) -> String {
+        let mut text = String::new();
+        let end = *range.end() as usize;
+        let mut address = *range.start() as usize;
+        while address <= end {
+            if let Some(name) = symbols.and_then(|symbols| symbols.name(address as u16)) {
+                writeln!(text, "{name}:").unwrap();
+            }
+            let flags = self.map[address];
+            let bytes = [0, 1, 2].map(|offset| i8080.peek((address + offset) as u16));
+            let length = disasm::length(bytes[0]);
+            // Code that was never run is shown as such unless it runs into
+            // bytes that were, or past the end of the range.
+            let overlaps = (1..length).any(|offset| address + offset > end || self.map[address + offset] & OPCODE != 0);
+            let (mark, length, statement) = if flags & OPCODE != 0 || (flags == 0 && !overlaps) {
+                let mark = if flags & OPCODE != 0 { ' ' } else { '!' };
+                (mark, length, disasm::decode(bytes, symbols).text)
+            } else {
+                // Data runs up to eight bytes, the next symbol or the next instruction.
+                let mut length = 1;
+                while length < 8
+                    && address + length <= end
+                    && self.map[address + length] & OPCODE == 0
+                    && (self.map[address + length] == 0) == (flags == 0)
+                    && symbols.is_none_or(|symbols| symbols.name((address + length) as u16).is_none())
+                {
+                    length += 1;
+                }
+                let values: Vec<_> =
+                    (0..length).map(|offset| disasm::hex(i8080.peek((address + offset) as u16) as u16, 2)).collect();
+                (if flags == 0 { '!' } else { 'd' }, length, format!("DB {}", values.join(",")))
+            };
+            let hex: String = (0..length.min(3)).map(|offset| format!("{:02X}", i8080.peek((address + offset) as u16))).collect();
+            writeln!(text, "{mark} {address:04X}  {hex:<8} {statement}").unwrap();
+            address += length;
+        }
+        text
+    }
+
+    // An lcov tracefile for the code lines of assembler listings, giving each
+    // line the number of times the instructions it assembled to were run.
+    pub fn lcov(&self, test: &str, listings: &[Listing]) -> String {
+        let mut text = String::new();
+        for listing in listings {
+            writeln!(text, "TN:{test}").unwrap();
+            writeln!(text, "SF:{}", listing.source).unwrap();
+            let (mut found, mut hit) = (0, 0);
+            for line in listing.lines.iter().filter(|line| line.code) {
+                let executions: u64 =
+                    (0..line.length).map(|offset| self.executions(line.address.wrapping_add(offset as u16))).sum();
+                writeln!(text, "DA:{},{executions}", line.number).unwrap();
+                found += 1;
+                if executions > 0 {
+                    hit += 1;
+                }
+            }
+            writeln!(text, "LH:{hit}").unwrap();
+            writeln!(text, "LF:{found}").unwrap();
+            writeln!(text, "end_of_record").unwrap();
+        }
+        text
+    }
+
+    pub fn reset(&mut self) {
+        self.map.fill(0);
+        self.executions.fill(0);
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusObserver for Coverage {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) {
+        let address = cycle.address as usize;
+        match cycle.status {
+            bus::INSTRUCTION_FETCH => {
+                self.map[address] |= OPCODE;
+                self.executions[address] += 1;
+                self.operands = (cycle.address.wrapping_add(1), disasm::length(cycle.data) as u16 - 1);
+            }
+            bus::MEMORY_READ if cycle.address.wrapping_sub(self.operands.0) < self.operands.1 => self.map[address] |= OPERAND,
+            bus::MEMORY_READ | bus::STACK_READ => self.map[address] |= READ,
+            bus::MEMORY_WRITE | bus::STACK_WRITE => self.map[address] |= WRITE,
+            _ => {}
+        }
+    }
+}
+
+// A line of an assembler listing that generated bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    pub number: usize, // in the source file
+    pub address: u16,
+    pub length: usize,
+    pub code: bool, // false for DB, DW and DS
+}
+
+// Where the lines of a source file were assembled to, read from a listing in
+// the .PRN layout of CP/M's ASM: the address in columns 1-4, the bytes
+// generated after it and the source line from column 17. Lines with bytes
+// but no source continue the line before, as ASM does for long DBs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Listing {
+    pub source: String,
+    pub lines: Vec<Line>,
+}
+
+impl Listing {
+    pub fn parse(source: &str, listing: &str) -> Self {
+        let mut lines: Vec<Line> = Vec::new();
+        let mut number = 0;
+        for text in listing.lines() {
+            let prefix: String = text.chars().take(16).collect();
+            let statement: String = text.chars().skip(16).collect();
+            let address = prefix
+                .get(0..4)
+                .filter(|_| prefix.get(4..).is_some_and(|rest| rest.is_empty() || rest.starts_with(' ')))
+                .and_then(|address| u16::from_str_radix(address, 16).ok());
+            let words: Vec<_> = prefix.get(5..).unwrap_or("").split_whitespace().collect();
+            let length = match words.iter().all(|word| word.len() % 2 == 0 && word.chars().all(|c| c.is_ascii_hexdigit())) {
+                true => words.iter().map(|word| word.len() / 2).sum(),
+                false => 0,
+            };
+            if statement.trim().is_empty() && length > 0 {
+                if let Some(last) = lines.last_mut().filter(|last| address == Some(last.address.wrapping_add(last.length as u16))) {
+                    last.length += length;
+                    continue;
+                }
+            }
+            number += 1;
+            if let (Some(address), true) = (address, length > 0) {
+                let data = statement.split_whitespace().take(2).any(|word| matches!(word.to_ascii_uppercase().as_str(), "DB" | "DW" | "DS"));
+                lines.push(Line { number, address, length, code: !data });
+            }
+        }
+        Self { source: source.to_string(), lines }
+    }
+
+    pub fn load(source: &str, path: &Path) -> io::Result<Self> {
+        Ok(Self::parse(source, &fs::read_to_string(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const PROGRAM: [(&str, &[u8], &str); 8] = [
+        ("0000", &[0x01, 0x10, 0x00], "LXI B,TABLE"),
+        ("0003", &[0x0A], "LDAX B"),
+        ("0004", &[0x03], "INX B"),
+        ("0005", &[0x02], "STAX B"),
+        ("0006", &[0xB7], "ORA A"),
+        ("0007", &[0xC2, 0x0B, 0x00], "JNZ DONE"),
+        ("000A", &[0x76], "HLT"),
+        ("000B", &[0x76], "DONE: HLT"),
+    ];
+
+    fn run() -> (I8080, Coverage) {
+        let mut i8080 = I8080::new(1024);
+        for (address, bytes, _) in PROGRAM {
+            for (offset, &value) in bytes.iter().enumerate() {
+                i8080.poke(u16::from_str_radix(address, 16).unwrap() + offset as u16, value);
+            }
+        }
+        i8080.poke(0x0010, 0x42);
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        i8080.observe(coverage.clone());
+        while !i8080.halted() {
+            i8080.step();
+        }
+        let coverage = coverage.replace(Coverage::new());
+        (i8080, coverage)
+    }
+
+    #[test]
+    fn maps() {
+        let (i8080, coverage) = run();
+        assert_eq!(coverage.flags(0x0000), OPCODE);
+        assert_eq!(coverage.flags(0x0008), OPERAND);
+        assert_eq!(coverage.flags(0x0010), READ);
+        assert_eq!(coverage.flags(0x0011), WRITE);
+        assert_eq!(coverage.flags(0x000A), 0);
+        assert_eq!(coverage.format(0x0000..=0x0011), "0000 xooxxxxxoo.x....rw\n");
+
+        let symbols = Symbols::parse("0010 TABLE").unwrap();
+        let annotated = coverage.annotate(&i8080, 0x0000..=0x0011, Some(&symbols));
+        let lines: Vec<_> = annotated.lines().collect();
+        assert_eq!(lines[0], "  0000  011000   LXI B,TABLE");
+        assert_eq!(lines[6], "! 000A  76       HLT");
+        assert_eq!(lines[8], "! 000C  00       NOP");
+        assert_eq!(lines[12], "TABLE:");
+        assert_eq!(lines[13], "d 0010  4242     DB 42H,42H");
+    }
+
+    #[test]
+    fn lcov() {
+        let (_, coverage) = run();
+        let mut prn = String::from("                ; test\r\n");
+        for (address, bytes, source) in PROGRAM {
+            let hex: String = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+            prn += &format!("{:<16}{source}\r\n", format!("{address} {hex}"));
+        }
+        prn += &format!("{:<16}TABLE: DB 42H,0\r\n", "0010 4200");
+        let listing = Listing::parse("test.asm", &prn);
+        assert_eq!(listing.lines.len(), 9);
+        assert_eq!(listing.lines[8], Line { number: 10, address: 0x0010, length: 2, code: false });
+        let lcov = coverage.lcov("unit", &[listing]);
+        assert!(lcov.starts_with("TN:unit\nSF:test.asm\nDA:2,1\n"));
+        assert!(lcov.contains("DA:8,0\nDA:9,1\nLH:7\nLF:8\nend_of_record\n"));
+    }
+}
@@ -0,0 +1,253 @@
+use std::f64::consts::PI;
+use std::io;
+
+use crate::io::Device;
+use crate::state::{Reader, Writer};
+use crate::wav::Wave;
+
+// Frequency shift keyed tape format. Characters are framed like a serial line,
+// one start bit of space tone, eight data bits LSB first and the stop bits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Format {
+    pub baud: u32,
+    pub mark_hz: u32,  // 1 bits
+    pub space_hz: u32, // 0 bits
+    pub stop_bits: usize,
+}
+
+impl Format {
+    // Kansas City Standard: 300 baud, eight cycles of 2400 Hz or four of 1200 Hz per bit.
+    pub const KCS: Format = Format { baud: 300, mark_hz: 2400, space_hz: 1200, stop_bits: 2 };
+    // Processor Technology CUTS at 1200 baud: a cycle of 1200 Hz or half a cycle of 600 Hz.
+    pub const CUTS_1200: Format = Format { baud: 1200, mark_hz: 1200, space_hz: 600, stop_bits: 1 };
+
+    // Half cycles in one bit of each tone.
+    fn half_cycles(&self, hz: u32) -> f64 {
+        2.0 * hz as f64 / self.baud as f64
+    }
+}
+
+// Leader of mark tone written before the data, in seconds.
+const LEADER: f64 = 1.0;
+
+pub fn encode(data: &[u8], format: &Format, sample_rate: u32) -> Wave {
+    let mut bits = vec![true; (LEADER * format.baud as f64) as usize];
+    for &byte in data {
+        bits.push(false);
+        bits.extend((0..8).map(|bit| byte & (1 << bit) != 0));
+        bits.extend(std::iter::repeat_n(true, format.stop_bits));
+    }
+    bits.extend(std::iter::repeat_n(true, format.baud as usize / 10));
+    let samples_per_bit = sample_rate as f64 / format.baud as f64;
+    // The phase carries across bits since a CUTS space bit is only half a cycle.
+    let mut phase: f64 = 0.0;
+    let samples = (0..(bits.len() as f64 * samples_per_bit) as usize)
+        .map(|sample| {
+            let hz = if bits[(sample as f64 / samples_per_bit) as usize] { format.mark_hz } else { format.space_hz };
+            let value = (phase.sin() * 24000.0) as i16;
+            phase = (phase + 2.0 * PI * hz as f64 / sample_rate as f64) % (2.0 * PI);
+            value
+        })
+        .collect();
+    Wave::new(sample_rate, samples)
+}
+
+// Sample indices where the signal swings through zero, with some hysteresis
+// so noise near zero does not count.
+fn crossings(wave: &Wave) -> Vec<usize> {
+    let threshold = (wave.samples.iter().map(|sample| sample.unsigned_abs()).max().unwrap_or(0) / 8) as i32;
+    let mut positive = true;
+    let mut crossings = Vec::new();
+    for (index, &sample) in wave.samples.iter().enumerate() {
+        let sample = sample as i32;
+        if (positive && sample < -threshold) || (!positive && sample > threshold) {
+            positive = !positive;
+            crossings.push(index);
+        }
+    }
+    crossings
+}
+
+pub fn decode(wave: &Wave, format: &Format) -> Vec<u8> {
+    let crossings = crossings(wave);
+    let samples_per_bit = wave.sample_rate as f64 / format.baud as f64;
+    let mark = format.half_cycles(format.mark_hz);
+    let space = format.half_cycles(format.space_hz);
+    // Half cycles shorter than this are mark tone.
+    let split = samples_per_bit * (1.0 / mark + 1.0 / space) / 2.0;
+    // Bits are told apart by counting half cycles, in windows shifted a little
+    // late so a crossing right on a bit boundary lands in the bit it ends.
+    let shift = samples_per_bit / mark / 4.0;
+    let bit = |start: f64, bit: usize| {
+        let from = start + bit as f64 * samples_per_bit + shift;
+        let count = crossings.partition_point(|&index| index as f64 <= from + samples_per_bit)
+            - crossings.partition_point(|&index| index as f64 <= from);
+        count as f64 > (mark + space) / 2.0
+    };
+    let frame = 1 + 8 + format.stop_bits;
+    let mut data = Vec::new();
+    let mut index = 2;
+    while index < crossings.len() {
+        let long = |index: usize| (crossings[index] - crossings[index - 1]) as f64 >= split;
+        // A start bit shows up as the first space half cycle after mark tone.
+        if long(index - 1) || !long(index) {
+            index += 1;
+            continue;
+        }
+        let start = crossings[index] as f64 - samples_per_bit / space;
+        let end = start + (frame as f64 - 0.5) * samples_per_bit;
+        if end + samples_per_bit > wave.samples.len() as f64 || bit(start, 0) || !(9..frame).all(|stop| bit(start, stop)) {
+            index += 1;
+            continue;
+        }
+        data.push((1..=8).filter(|&index| bit(start, index)).fold(0, |byte, index| byte | 1 << (index - 1)));
+        // Carry on from within the stop bits.
+        index = crossings.partition_point(|&index| (index as f64) < end).max(index + 1);
+    }
+    data
+}
+
+// Cassette interface wired to single bits of an I/O port: the input bit
+// follows the sign of the tape being played, like the comparator on a real
+// interface, and the output bit is recorded as a square wave. Both are timed
+// in CPU T-states.
+pub struct Cassette {
+    cpu_hz: u64,
+    ticks: u64,
+    input_bit: u8,
+    output_bit: u8,
+    playing: Option<(Wave, u64)>, // tape, T-state it started
+    recording: Option<Wave>,
+    level: bool,
+}
+
+impl Cassette {
+    pub fn new(cpu_hz: u32, input_bit: u8, output_bit: u8) -> Self {
+        Self {
+            cpu_hz: cpu_hz.max(1) as u64,
+            ticks: 0,
+            input_bit,
+            output_bit,
+            playing: None,
+            recording: None,
+            level: false,
+        }
+    }
+
+    pub fn play(&mut self, wave: Wave) {
+        self.playing = Some((wave, self.ticks));
+    }
+
+    pub fn playing(&self) -> bool {
+        self.playing.as_ref().is_some_and(|(wave, _)| self.sample_index(wave) < wave.samples.len() as u64)
+    }
+
+    pub fn record(&mut self, sample_rate: u32) {
+        self.recording = Some(Wave::new(sample_rate, Vec::new()));
+    }
+
+    // Stops recording and returns the tape.
+    pub fn stop(&mut self) -> Option<Wave> {
+        self.recording.take()
+    }
+
+    fn sample_index(&self, wave: &Wave) -> u64 {
+        let start = self.playing.as_ref().map_or(0, |(_, start)| *start);
+        (self.ticks - start) * wave.sample_rate as u64 / self.cpu_hz
+    }
+}
+
+impl Device for Cassette {
+    fn input(&mut self, _port: u8) -> u8 {
+        let Some((wave, _)) = &self.playing else {
+            return 0x00;
+        };
+        match wave.samples.get(self.sample_index(wave) as usize) {
+            Some(&sample) if sample > 0 => self.input_bit,
+            _ => 0x00,
+        }
+    }
+
+    fn output(&mut self, _port: u8, value: u8) {
+        self.level = value & self.output_bit != 0;
+    }
+
+    fn clock(&mut self, states: usize) {
+        self.ticks += states as u64;
+        if let Some(wave) = &mut self.recording {
+            let samples = (self.ticks * wave.sample_rate as u64 / self.cpu_hz) as usize;
+            let sample = if self.level { 24000 } else { -24000 };
+            wave.samples.resize(samples, sample);
+        }
+    }
+
+    // The tapes themselves are the host's; only where the one playing has got to is saved.
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.ticks);
+        state.put(&self.level);
+        state.put(&self.playing.as_ref().map(|(_, start)| *start));
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.ticks = state.get()?;
+        self.level = state.get()?;
+        let start: Option<u64> = state.get()?;
+        if let (Some((_, playing)), Some(start)) = (&mut self.playing, start) {
+            *playing = start;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kcs() {
+        let wave = encode(b"Hello, 8080\x00\xFF", &Format::KCS, 22050);
+        assert_eq!(wave.samples.len(), (300 + 13 * 11 + 30) * 147 / 2);
+        assert_eq!(decode(&wave, &Format::KCS), b"Hello, 8080\x00\xFF");
+    }
+
+    #[test]
+    fn cuts() {
+        let wave = encode(&[0x55, 0xAA, 0x01], &Format::CUTS_1200, 44100);
+        assert_eq!(decode(&wave, &Format::CUTS_1200), [0x55, 0xAA, 0x01]);
+        assert_eq!(decode(&wave, &Format::KCS), []);
+    }
+
+    #[test]
+    fn interface() {
+        // 1 MHz CPU: the 2400 Hz leader spends about 208 T-states per half cycle.
+        let mut cassette = Cassette::new(1_000_000, 0x01, 0x80);
+        cassette.play(encode(b"A", &Format::KCS, 48000));
+        cassette.record(48000);
+        let mut edges = 0;
+        let mut last = 0;
+        for _ in 0..10_000 {
+            let bit = cassette.input(0);
+            if bit != last {
+                edges += 1;
+            }
+            last = bit;
+            cassette.output(0, bit << 7);
+            cassette.clock(1);
+        }
+        assert_eq!(edges, 48);
+        while cassette.playing() {
+            let bit = cassette.input(0);
+            cassette.output(0, bit << 7);
+            cassette.clock(4);
+        }
+        let recording = cassette.stop().unwrap();
+        assert_eq!(decode(&recording, &Format::KCS), b"A");
+
+        let mut cassette = Cassette::new(0, 0x01, 0x80);
+        cassette.play(encode(b"A", &Format::KCS, 48000));
+        cassette.record(48000);
+        cassette.clock(1);
+        cassette.input(0);
+        assert!(cassette.playing());
+    }
+}
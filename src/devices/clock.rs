@@ -0,0 +1,287 @@
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::io::Device;
+use crate::state::{Reader, Writer};
+
+// Tick status and control on port 2
+pub const TICKED: u8 = 0x01;
+pub const TICK_ENABLE: u8 = 0x01;
+
+// Hours tens register flags
+const HOUR_24: u8 = 0x08;
+const PM: u8 = 0x04;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Period {
+    Cycles(u64),
+    Millis(u64), // of emulated time
+}
+
+// Where the date and time come from.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Source {
+    Host,
+    // Seconds since 1970 at power on, advanced by emulated T-states so that
+    // runs are repeatable.
+    Emulated(u64),
+}
+
+// Clock board in the style of the MSM5832 ones common on the S-100 bus: write
+// a register number 0-12 to port 0 and read or write its BCD digit on port 1.
+// The registers are seconds, minutes and hours units and tens, day of week,
+// then day, month and year units and tens. Port 2 reads TICKED once per tick
+// since the last read and writing TICK_ENABLE lets ticks interrupt.
+pub struct Clock {
+    cpu_hz: u64,
+    ticks: u64,
+    source: Source,
+    offset: i64, // seconds the time has been set forward
+    register: u8,
+    period: Option<u64>, // T-states
+    next: u64,
+    ticked: bool,
+    enabled: bool,
+    pending: bool,
+    interrupt: Option<u8>,
+}
+
+impl Clock {
+    pub fn new(cpu_hz: u32, source: Source) -> Self {
+        Self {
+            cpu_hz: cpu_hz.max(1) as u64,
+            ticks: 0,
+            source,
+            offset: 0,
+            register: 0,
+            period: None,
+            next: 0,
+            ticked: false,
+            enabled: false,
+            pending: false,
+            interrupt: None,
+        }
+    }
+
+    pub fn set_period(&mut self, period: Option<Period>) {
+        self.period = period.map(|period| match period {
+            Period::Cycles(cycles) => cycles.max(1),
+            Period::Millis(millis) => (millis * self.cpu_hz / 1000).max(1),
+        });
+        self.next = self.ticks + self.period.unwrap_or(0);
+    }
+
+    // Ticks interrupt while enabled through port 2.
+    pub fn set_interrupt(&mut self, instruction: Option<u8>) {
+        self.interrupt = instruction;
+    }
+
+    // Seconds since 1970 as the board reads them.
+    pub fn time(&self) -> u64 {
+        let time = match self.source {
+            Source::Host => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()),
+            Source::Emulated(epoch) => epoch + self.ticks / self.cpu_hz,
+        };
+        (time as i64 + self.offset).max(0) as u64
+    }
+
+    fn digits(&self) -> [u8; 13] {
+        let time = self.time();
+        let days = time / 86400;
+        let seconds = time % 86400;
+        let (year, month, day) = civil(days as i64);
+        let hour = (seconds / 3600) as u8;
+        let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let bcd = |value: u8| [value % 10, value / 10];
+        let [s1, s10] = bcd((seconds % 60) as u8);
+        let [m1, m10] = bcd((seconds / 60 % 60) as u8);
+        let [h1, h10] = bcd(hour);
+        let [d1, d10] = bcd(day);
+        let [mo1, mo10] = bcd(month);
+        let [y1, y10] = bcd((year % 100) as u8);
+        // 1970-01-01 was a Thursday; Sunday is day 0.
+        let weekday = ((days + 4) % 7) as u8;
+        let h10 = h10 | HOUR_24 | if hour >= 12 { PM } else { 0 };
+        let d10 = d10 | if leap { 0x04 } else { 0 };
+        [s1, s10, m1, m10, h1, h10, weekday, d1, d10, mo1, mo10, y1, y10]
+    }
+
+    // Setting a digit moves the clock by the difference, keeping it running.
+    fn set_digit(&mut self, register: usize, value: u8) {
+        let mut digits = self.digits();
+        digits[register] = value & 0x0F;
+        let number = |units: usize, mask: u8| (digits[units] + 10 * (digits[units + 1] & mask)) as u64;
+        let year = self.civil_year() / 100 * 100 + number(11, 0x0F) as i64;
+        let days = days(year, number(9, 0x01).clamp(1, 12) as u8, number(7, 0x03).max(1) as u8);
+        let time = days as u64 * 86400 + number(4, 0x03) * 3600 + number(2, 0x07) * 60 + number(0, 0x07);
+        self.offset += time as i64 - self.time() as i64;
+    }
+
+    fn civil_year(&self) -> i64 {
+        civil((self.time() / 86400) as i64).0
+    }
+}
+
+// Year, month and day of a day number counted from 1970-01-01.
+fn civil(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u8;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Inverse of `civil`.
+fn days(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+impl Device for Clock {
+    fn input(&mut self, port: u8) -> u8 {
+        match port % 3 {
+            0 => self.register,
+            1 => self.digits().get(self.register as usize).copied().unwrap_or(0x0F),
+            _ => {
+                let status = if self.ticked { TICKED } else { 0 };
+                self.ticked = false;
+                status
+            }
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port % 3 {
+            0 => self.register = value & 0x0F,
+            1 => {
+                if (self.register as usize) < 13 {
+                    self.set_digit(self.register as usize, value);
+                }
+            }
+            _ => {
+                self.enabled = value & TICK_ENABLE != 0;
+                self.pending = false;
+            }
+        }
+    }
+
+    fn clock(&mut self, states: usize) {
+        self.ticks += states as u64;
+        let Some(period) = self.period else {
+            return;
+        };
+        while self.ticks >= self.next {
+            self.next += period;
+            self.ticked = true;
+            self.pending |= self.enabled;
+        }
+    }
+
+    fn intr(&self) -> bool {
+        self.interrupt.is_some() && self.pending
+    }
+
+    fn inta(&mut self) -> u8 {
+        self.pending = false;
+        self.interrupt.unwrap_or(0xFF)
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.ticks);
+        state.put(&self.offset);
+        state.put(&self.register);
+        state.put(&self.period);
+        state.put(&self.next);
+        state.put(&self.ticked);
+        state.put(&self.enabled);
+        state.put(&self.pending);
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.ticks = state.get()?;
+        self.offset = state.get()?;
+        self.register = state.get()?;
+        self.period = state.get()?;
+        self.next = state.get()?;
+        self.ticked = state.get()?;
+        self.enabled = state.get()?;
+        self.pending = state.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1979-07-04 23:59:58, a Wednesday.
+    const EPOCH: u64 = 299_980_798;
+
+    fn read(clock: &mut Clock) -> Vec<u8> {
+        (0..13)
+            .map(|register| {
+                clock.output(0, register);
+                clock.input(1)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn calendar() {
+        assert_eq!(civil(0), (1970, 1, 1));
+        assert_eq!(civil(11016), (2000, 2, 29));
+        assert_eq!(days(2000, 2, 29), 11016);
+        assert_eq!(days(1969, 12, 31), -1);
+    }
+
+    #[test]
+    fn date() {
+        let mut clock = Clock::new(1_000_000, Source::Emulated(EPOCH));
+        assert_eq!(read(&mut clock), [8, 5, 9, 5, 3, 2 | HOUR_24 | PM, 3, 4, 0, 7, 0, 9, 7]);
+        clock.clock(2_000_000);
+        assert_eq!(read(&mut clock), [0, 0, 0, 0, 0, HOUR_24, 4, 5, 0, 7, 0, 9, 7]);
+        // Set the year to 1984, a leap year.
+        clock.output(0, 11);
+        clock.output(1, 4);
+        clock.output(0, 12);
+        clock.output(1, 8);
+        assert_eq!(read(&mut clock)[7..], [5, 0x04, 7, 0, 4, 8]);
+        clock.clock(1_000_000);
+        assert_eq!(read(&mut clock)[0], 1);
+    }
+
+    #[test]
+    fn tick() {
+        let mut clock = Clock::new(2_000_000, Source::Emulated(0));
+        clock.set_interrupt(Some(0xFF));
+        clock.set_period(Some(Period::Millis(10)));
+        clock.clock(19_999);
+        assert_eq!(clock.input(2), 0);
+        clock.clock(1);
+        assert_eq!(clock.input(2), TICKED);
+        assert_eq!(clock.input(2), 0);
+        assert!(!clock.intr());
+        clock.output(2, TICK_ENABLE);
+        clock.clock(20_000);
+        assert!(clock.intr());
+        assert_eq!(clock.inta(), 0xFF);
+        clock.set_period(Some(Period::Cycles(100)));
+        clock.clock(99);
+        assert!(!clock.intr());
+        clock.clock(1);
+        assert!(clock.intr());
+        clock.output(2, 0);
+        assert!(!clock.intr());
+        assert_eq!(Clock::new(0, Source::Emulated(5)).time(), 5);
+    }
+}
@@ -0,0 +1,590 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::io::Device;
+use crate::state::{self, Field, Reader, Writer};
+
+// Status register, active low
+pub const ENWD: u8 = 0x01;
+pub const MOVE_HEAD: u8 = 0x02;
+pub const HEAD_STATUS: u8 = 0x04;
+pub const INTE: u8 = 0x20;
+pub const TRACK_0: u8 = 0x40;
+pub const NRDA: u8 = 0x80;
+
+// Control register
+const STEP_IN: u8 = 0x01;
+const STEP_OUT: u8 = 0x02;
+const HEAD_LOAD: u8 = 0x04;
+const HEAD_UNLOAD: u8 = 0x08;
+const INTERRUPT_ENABLE: u8 = 0x10;
+const INTERRUPT_DISABLE: u8 = 0x20;
+const WRITE_ENABLE: u8 = 0x80;
+
+pub const SECTOR_BYTES: usize = 137;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Geometry {
+    pub tracks: usize,
+    pub sectors: usize,
+    pub rpm: u64,
+    pub bit_rate: u64,
+}
+
+impl Geometry {
+    // 8" floppy on the 88-DCDD.
+    pub const STANDARD: Geometry = Geometry { tracks: 77, sectors: 32, rpm: 360, bit_rate: 250_000 };
+    // 5.25" minidisk on the 88-MDS.
+    pub const MINIDISK: Geometry = Geometry { tracks: 35, sectors: 16, rpm: 300, bit_rate: 125_000 };
+
+    pub fn image_size(&self) -> usize {
+        self.tracks * self.sectors * SECTOR_BYTES
+    }
+}
+
+impl Field for Geometry {
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.tracks);
+        state.put(&self.sectors);
+        state.put(&self.rpm);
+        state.put(&self.bit_rate);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(Self { tracks: state.get()?, sectors: state.get()?, rpm: state.get()?, bit_rate: state.get()? })
+    }
+}
+
+struct Drive {
+    geometry: Geometry,
+    image: Vec<u8>,
+    path: Option<PathBuf>,
+    dirty: bool,
+    track: usize,
+    head_loaded: bool,
+}
+
+struct Write {
+    sector: u64, // sectors since power on
+    index: usize,
+    ready: u64, // T-state ENWD goes active
+}
+
+impl Field for Write {
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.sector);
+        state.put(&self.index);
+        state.put(&self.ready);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(Self { sector: state.get()?, index: state.get()?, ready: state.get()? })
+    }
+}
+
+// MITS 88-DCDD floppy disk controller at ports 08h-0Ah: drive select and
+// status, control and sector position, and data. Bytes pass under the head
+// at the drive's bit rate while the disk turns, counted in CPU T-states.
+pub struct Dcdd {
+    cpu_hz: u64,
+    ticks: u64,
+    drives: Vec<Option<Drive>>,
+    selected: Option<usize>,
+    read: Option<(u64, usize)>, // last byte read: sector since power on, index
+    write: Option<Write>,
+    busy: u64,   // T-state the head is done moving or settling
+    inte: bool,
+    sector: Option<u64>, // last sector seen by `clock`
+    pending: bool,
+    interrupt: Option<u8>,
+}
+
+impl Dcdd {
+    pub fn new(cpu_hz: u32) -> Self {
+        Self {
+            cpu_hz: cpu_hz as u64,
+            ticks: 0,
+            drives: (0..16).map(|_| None).collect(),
+            selected: None,
+            read: None,
+            write: None,
+            busy: 0,
+            inte: false,
+            sector: None,
+            pending: false,
+            interrupt: None,
+        }
+    }
+
+    // Loads an Altair .DSK image; its size tells an 8" disk from a minidisk.
+    // Changes are written back by `flush`, `eject` or dropping the controller.
+    pub fn insert(&mut self, drive: usize, path: &Path) -> io::Result<()> {
+        self.slot(drive)?;
+        let image = fs::read(path)?;
+        self.insert_image(drive, image)?;
+        if let Some(drive) = self.slot(drive)? {
+            drive.path = Some(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    pub fn insert_image(&mut self, drive: usize, image: Vec<u8>) -> io::Result<()> {
+        let geometry = [Geometry::STANDARD, Geometry::MINIDISK]
+            .into_iter()
+            .find(|geometry| geometry.image_size() == image.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an Altair disk image"))?;
+        *self.slot(drive)? = Some(Drive { geometry, image, path: None, dirty: false, track: 0, head_loaded: false });
+        Ok(())
+    }
+
+    // Removes the disk, writing it back to its file first, and returns the image.
+    pub fn eject(&mut self, drive: usize) -> io::Result<Option<Vec<u8>>> {
+        self.slot(drive)?;
+        self.flush()?;
+        Ok(self.slot(drive)?.take().map(|drive| drive.image))
+    }
+
+    // Writes changed images back to their files.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for drive in self.drives.iter_mut().flatten() {
+            if let (true, Some(path)) = (drive.dirty, &drive.path) {
+                fs::write(path, &drive.image)?;
+                drive.dirty = false;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn image(&self, drive: usize) -> Option<&[u8]> {
+        self.drives.get(drive)?.as_ref().map(|drive| &drive.image[..])
+    }
+
+    fn slot(&mut self, drive: usize) -> io::Result<&mut Option<Drive>> {
+        let count = self.drives.len();
+        self.drives.get_mut(drive).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no drive {drive}; there are {count}")))
+    }
+
+    // Interrupts at the start of each sector while enabled.
+    pub fn set_interrupt(&mut self, instruction: Option<u8>) {
+        self.interrupt = instruction;
+    }
+
+    fn drive(&self) -> Option<&Drive> {
+        self.selected.and_then(|drive| self.drives[drive].as_ref())
+    }
+
+    fn sector_states(&self, geometry: &Geometry) -> u64 {
+        (self.cpu_hz * 60 / geometry.rpm / geometry.sectors as u64).max(1)
+    }
+
+    fn byte_states(&self, geometry: &Geometry) -> u64 {
+        (self.cpu_hz * 8 / geometry.bit_rate).max(1)
+    }
+
+    // Sector under the head, counted since power on, and T-states into it.
+    fn position(&self, geometry: &Geometry) -> (u64, u64) {
+        let sector_states = self.sector_states(geometry);
+        (self.ticks / sector_states, self.ticks % sector_states)
+    }
+
+    // SECTOR TRUE is active for one byte time at the start of each sector, then
+    // the sector's bytes follow.
+    fn byte(&self, geometry: &Geometry) -> Option<(u64, usize)> {
+        let (sector, offset) = self.position(geometry);
+        let index = (offset / self.byte_states(geometry)).checked_sub(1)? as usize;
+        (index < SECTOR_BYTES).then_some((sector, index))
+    }
+
+    fn offset(drive: &Drive, sector: u64) -> usize {
+        let sector = sector as usize % drive.geometry.sectors;
+        (drive.track * drive.geometry.sectors + sector) * SECTOR_BYTES
+    }
+
+    fn status(&self) -> u8 {
+        let Some(drive) = self.drive() else {
+            return 0xFF;
+        };
+        let mut status = 0xE7;
+        let settled = self.ticks >= self.busy;
+        if settled {
+            status &= !MOVE_HEAD;
+        }
+        if drive.head_loaded && settled {
+            status &= !HEAD_STATUS;
+            let byte = self.byte(&drive.geometry);
+            if byte.is_some() && byte != self.read {
+                status &= !NRDA;
+            }
+        }
+        if self.write.as_ref().is_some_and(|write| self.ticks >= write.ready) {
+            status &= !ENWD;
+        }
+        if self.inte {
+            status &= !INTE;
+        }
+        if drive.track == 0 {
+            status &= !TRACK_0;
+        }
+        status
+    }
+
+    fn sector_position(&self) -> u8 {
+        let Some(drive) = self.drive().filter(|drive| drive.head_loaded) else {
+            return 0xFF;
+        };
+        let (sector, offset) = self.position(&drive.geometry);
+        let sector_true = offset < self.byte_states(&drive.geometry);
+        0xC0 | ((sector as usize % drive.geometry.sectors) << 1) as u8 | !sector_true as u8
+    }
+
+    fn control(&mut self, value: u8) {
+        let step_states = self.cpu_hz / 100; // 10 ms
+        let settle_states = self.cpu_hz / 25; // 40 ms
+        let ticks = self.ticks;
+        if value & INTERRUPT_ENABLE != 0 {
+            self.inte = true;
+        }
+        if value & INTERRUPT_DISABLE != 0 {
+            self.inte = false;
+            self.pending = false;
+        }
+        let Some(drive) = self.selected.and_then(|drive| self.drives[drive].as_mut()) else {
+            return;
+        };
+        if value & STEP_IN != 0 && drive.track + 1 < drive.geometry.tracks {
+            drive.track += 1;
+            self.busy = ticks + step_states;
+        }
+        if value & STEP_OUT != 0 && drive.track > 0 {
+            drive.track -= 1;
+            self.busy = ticks + step_states;
+        }
+        if value & HEAD_LOAD != 0 && !drive.head_loaded {
+            drive.head_loaded = true;
+            self.busy = self.busy.max(ticks + settle_states);
+        }
+        if value & HEAD_UNLOAD != 0 {
+            drive.head_loaded = false;
+        }
+        if value & WRITE_ENABLE != 0 {
+            let geometry = drive.geometry;
+            let (sector, _) = self.position(&geometry);
+            self.write = Some(Write { sector, index: 0, ready: ticks });
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let Some(drive) = self.drive().filter(|drive| drive.head_loaded) else {
+            return 0xFF;
+        };
+        let Some((sector, index)) = self.byte(&drive.geometry) else {
+            return 0xFF;
+        };
+        let value = drive.image[Self::offset(drive, sector) + index];
+        self.read = Some((sector, index));
+        value
+    }
+
+    fn write_data(&mut self, value: u8) {
+        let byte_states = match self.drive() {
+            Some(drive) => self.byte_states(&drive.geometry),
+            None => return,
+        };
+        let (Some(write), Some(drive)) = (&mut self.write, self.selected.and_then(|drive| self.drives[drive].as_mut()))
+        else {
+            return;
+        };
+        let offset = Self::offset(drive, write.sector) + write.index;
+        drive.image[offset] = value;
+        drive.dirty = true;
+        write.index += 1;
+        write.ready = self.ticks + byte_states;
+        if write.index == SECTOR_BYTES {
+            self.write = None;
+        }
+    }
+}
+
+// Writes changed disks back to their files. Errors are lost here; call
+// `flush` first to see them.
+impl Drop for Dcdd {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl Device for Dcdd {
+    fn input(&mut self, port: u8) -> u8 {
+        match port {
+            0 => self.status(),
+            1 => self.sector_position(),
+            _ => self.read_data(),
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            0 => {
+                self.selected = (value & 0x80 == 0).then_some((value & 0x0F) as usize);
+                self.write = None;
+            }
+            1 => self.control(value),
+            _ => self.write_data(value),
+        }
+    }
+
+    fn clock(&mut self, states: usize) {
+        self.ticks += states as u64;
+        let Some(geometry) = self.drive().map(|drive| drive.geometry) else {
+            return;
+        };
+        let (sector, _) = self.position(&geometry);
+        // The write gate drops when the next sector comes round.
+        if self.write.as_ref().is_some_and(|write| write.sector != sector) {
+            self.write = None;
+        }
+        if self.inte && self.sector != Some(sector) {
+            self.pending = true;
+        }
+        self.sector = Some(sector);
+    }
+
+    fn intr(&self) -> bool {
+        self.interrupt.is_some() && self.pending
+    }
+
+    fn inta(&mut self) -> u8 {
+        self.pending = false;
+        self.interrupt.unwrap_or(0xFF)
+    }
+
+    // Disk contents are saved too; a restored drive keeps the file of the
+    // disk now in it.
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.ticks);
+        for drive in &self.drives {
+            state.put(&drive.is_some());
+            if let Some(drive) = drive {
+                state.put(&drive.geometry);
+                state.put(&drive.image);
+                state.put(&drive.dirty);
+                state.put(&drive.track);
+                state.put(&drive.head_loaded);
+            }
+        }
+        state.put(&self.selected);
+        state.put(&self.read);
+        state.put(&self.write);
+        state.put(&self.busy);
+        state.put(&self.inte);
+        state.put(&self.sector);
+        state.put(&self.pending);
+    }
+
+    // Changed disks are written back before the saved ones replace them.
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        let ticks = state.get()?;
+        let mut drives = Vec::new();
+        for number in 0..self.drives.len() {
+            if !state.get()? {
+                drives.push(None);
+                continue;
+            }
+            let geometry: Geometry = state.get()?;
+            let image: Vec<u8> = state.get()?;
+            if ![Geometry::STANDARD, Geometry::MINIDISK].contains(&geometry) || image.len() != geometry.image_size() {
+                return Err(state::invalid(format!("disk in drive {number} in save state is not an Altair disk image")));
+            }
+            let dirty = state.get()?;
+            let track = state.get()?;
+            if track >= geometry.tracks {
+                return Err(state::invalid(format!("track {track} of drive {number} in save state")));
+            }
+            drives.push(Some(Drive { geometry, image, path: None, dirty, track, head_loaded: state.get()? }));
+        }
+        let selected: Option<usize> = state.get()?;
+        if let Some(drive) = selected.filter(|&drive| drive >= self.drives.len()) {
+            return Err(state::invalid(format!("selected drive {drive} in save state")));
+        }
+        let read = state.get()?;
+        let write: Option<Write> = state.get()?;
+        if let Some(index) = write.as_ref().map(|write| write.index).filter(|&index| index >= SECTOR_BYTES) {
+            return Err(state::invalid(format!("disk write at byte {index} in save state")));
+        }
+        let (busy, inte, sector, pending) = (state.get()?, state.get()?, state.get()?, state.get()?);
+        self.flush()?;
+        for (drive, mut loaded) in self.drives.iter_mut().zip(drives) {
+            if let Some(loaded) = &mut loaded {
+                loaded.path = drive.take().and_then(|drive| drive.path);
+            }
+            *drive = loaded;
+        }
+        self.ticks = ticks;
+        self.selected = selected;
+        self.read = read;
+        self.write = write;
+        self.busy = busy;
+        self.inte = inte;
+        self.sector = sector;
+        self.pending = pending;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2 MHz: 10416 T-states per sector and 64 per byte.
+    fn dcdd() -> Dcdd {
+        let mut image = vec![0xE5; Geometry::STANDARD.image_size()];
+        for (index, byte) in image[(2 * 32 + 5) * SECTOR_BYTES..][..SECTOR_BYTES].iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        let mut dcdd = Dcdd::new(2_000_000);
+        dcdd.insert_image(1, image).unwrap();
+        dcdd.output(0, 0x01);
+        dcdd
+    }
+
+    fn seek(dcdd: &mut Dcdd, sector: u8) {
+        while dcdd.input(1) != 0xC0 | sector << 1 {
+            dcdd.clock(4);
+        }
+    }
+
+    #[test]
+    fn status() {
+        let mut dcdd = Dcdd::new(2_000_000);
+        assert_eq!(dcdd.input(0), 0xFF);
+        let mut dcdd = self::dcdd();
+        assert_eq!(dcdd.input(0), 0xE7 & !MOVE_HEAD & !TRACK_0);
+        dcdd.output(1, STEP_IN);
+        assert_eq!(dcdd.input(0) & (MOVE_HEAD | TRACK_0), MOVE_HEAD | TRACK_0);
+        dcdd.clock(20_000);
+        assert_eq!(dcdd.input(0) & MOVE_HEAD, 0);
+        dcdd.output(1, STEP_OUT | STEP_OUT);
+        dcdd.output(1, STEP_OUT);
+        dcdd.output(1, HEAD_LOAD | INTERRUPT_ENABLE);
+        assert_eq!(dcdd.input(0) & (HEAD_STATUS | TRACK_0 | INTE), HEAD_STATUS);
+        dcdd.clock(80_000);
+        assert_eq!(dcdd.input(0) & HEAD_STATUS, 0);
+        dcdd.output(0, 0x80);
+        assert_eq!(dcdd.input(0), 0xFF);
+    }
+
+    #[test]
+    fn read() {
+        let mut dcdd = dcdd();
+        dcdd.output(1, STEP_IN);
+        dcdd.clock(20_000);
+        dcdd.output(1, STEP_IN | HEAD_LOAD);
+        dcdd.clock(80_000);
+        seek(&mut dcdd, 5);
+        let mut sector = Vec::new();
+        let mut polls = 0;
+        while sector.len() < SECTOR_BYTES {
+            if dcdd.input(0) & NRDA == 0 {
+                sector.push(dcdd.input(2));
+            } else {
+                polls += 1;
+            }
+            dcdd.clock(10);
+        }
+        assert_eq!(sector, (0..SECTOR_BYTES).map(|index| index as u8).collect::<Vec<_>>());
+        assert!(polls > SECTOR_BYTES * 4);
+        assert_eq!(dcdd.input(1) & 0x3E, 5 << 1);
+    }
+
+    #[test]
+    fn write() {
+        let mut dcdd = dcdd();
+        dcdd.output(1, HEAD_LOAD);
+        seek(&mut dcdd, 31);
+        dcdd.output(1, WRITE_ENABLE);
+        for index in 0..SECTOR_BYTES {
+            while dcdd.input(0) & ENWD != 0 {
+                dcdd.clock(4);
+            }
+            dcdd.output(2, !index as u8);
+            dcdd.clock(4);
+        }
+        assert_eq!(dcdd.input(0) & ENWD, ENWD);
+        let image = dcdd.image(1).unwrap();
+        assert_eq!(image[31 * SECTOR_BYTES], 0xFF);
+        assert_eq!(image[32 * SECTOR_BYTES - 1], !136u8);
+        assert_eq!(image[32 * SECTOR_BYTES], 0xE5);
+    }
+
+    #[test]
+    fn interrupt() {
+        let mut dcdd = dcdd();
+        dcdd.set_interrupt(Some(0xFF));
+        dcdd.output(1, HEAD_LOAD | INTERRUPT_ENABLE);
+        dcdd.clock(1);
+        assert!(dcdd.intr());
+        assert_eq!(dcdd.inta(), 0xFF);
+        dcdd.clock(10_000);
+        assert!(!dcdd.intr());
+        dcdd.clock(1_000);
+        assert!(dcdd.intr());
+    }
+
+    #[test]
+    fn image_file() {
+        let path = std::env::temp_dir().join(format!("dcdd-{}.dsk", std::process::id()));
+        fs::write(&path, vec![0; Geometry::MINIDISK.image_size()]).unwrap();
+        let mut dcdd = Dcdd::new(2_000_000);
+        dcdd.insert(0, &path).unwrap();
+        dcdd.output(0, 0x00);
+        dcdd.output(1, HEAD_LOAD | WRITE_ENABLE);
+        dcdd.output(2, 0x42);
+        dcdd.eject(0).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0], 0x42);
+        assert!(dcdd.insert_image(0, vec![0; 100]).is_err());
+        assert_eq!(dcdd.insert(16, &path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(dcdd.eject(16).is_err() && dcdd.image(16).is_none());
+
+        // Dropping the controller writes back what was changed.
+        dcdd.insert(0, &path).unwrap();
+        dcdd.output(0, 0x00);
+        dcdd.output(1, HEAD_LOAD | WRITE_ENABLE);
+        dcdd.output(2, 0x43);
+        drop(dcdd);
+        assert_eq!(fs::read(&path).unwrap()[0], 0x43);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn save_state() {
+        let path = std::env::temp_dir().join(format!("dcdd-state-{}.dsk", std::process::id()));
+        fs::write(&path, vec![0; Geometry::MINIDISK.image_size()]).unwrap();
+        let mut dcdd = Dcdd::new(1_000);
+        dcdd.insert(0, &path).unwrap();
+        let mut saved = Writer::new();
+        dcdd.save(&mut saved);
+        let saved = saved.into_bytes();
+        dcdd.output(0, 0x00);
+        dcdd.output(1, HEAD_LOAD | WRITE_ENABLE);
+        dcdd.output(2, 0x42);
+        dcdd.clock(1_000);
+        dcdd.input(0);
+
+        let mut state = Writer::new();
+        state.put(&0u64);
+        for _ in 0..16 {
+            state.put(&false);
+        }
+        state.put(&Some(16usize));
+        let bytes = state.into_bytes();
+        assert!(dcdd.load(&mut Reader::new(&bytes)).unwrap_err().to_string().contains("selected drive 16"));
+        assert_eq!(dcdd.image(0).unwrap()[0], 0x42);
+
+        // The write reaches the file before the saved disk replaces it.
+        dcdd.load(&mut Reader::new(&saved)).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0], 0x42);
+        assert_eq!(dcdd.image(0).unwrap()[0], 0x00);
+        fs::remove_file(path).unwrap();
+    }
+}
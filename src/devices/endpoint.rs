@@ -0,0 +1,96 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+// Host side of a serial line or other byte stream.
+pub trait Endpoint {
+    // The next byte from the host, if one is waiting.
+    fn receive(&mut self) -> Option<u8>;
+    fn send(&mut self, value: u8);
+}
+
+// In-memory endpoint. Clones share the same buffers, so one can be given to a
+// device while the other feeds input and collects output.
+#[derive(Clone, Default)]
+pub struct Buffer {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Buffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_input(&self, bytes: &[u8]) {
+        self.input.borrow_mut().extend(bytes);
+    }
+
+    pub fn take_output(&self) -> Vec<u8> {
+        self.output.take()
+    }
+}
+
+impl Endpoint for Buffer {
+    fn receive(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+
+    fn send(&mut self, value: u8) {
+        self.output.borrow_mut().push(value);
+    }
+}
+
+// Standard input and output. Input is read on its own thread so `receive`
+// never blocks the emulation.
+pub struct Console {
+    input: Receiver<u8>,
+    eof: bool,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        Self { input, eof: false }
+    }
+
+    // True once standard input has been closed and drained.
+    pub fn eof(&self) -> bool {
+        self.eof
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Endpoint for Console {
+    fn receive(&mut self) -> Option<u8> {
+        match self.input.try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.eof = true;
+                None
+            }
+        }
+    }
+
+    fn send(&mut self, value: u8) {
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(&[value]).and_then(|_| stdout.flush());
+    }
+}
@@ -0,0 +1,432 @@
+use std::io;
+
+use crate::devices::endpoint::Endpoint;
+use crate::io::Device;
+use crate::state::{self, Field, Reader, Writer};
+
+// Status register
+pub const TXRDY: u8 = 0x01;
+pub const RXRDY: u8 = 0x02;
+pub const TXEMPTY: u8 = 0x04;
+pub const PE: u8 = 0x08;
+pub const OE: u8 = 0x10;
+pub const FE: u8 = 0x20;
+pub const SYNDET: u8 = 0x40;
+pub const DSR: u8 = 0x80;
+
+// Command instruction
+const TXEN: u8 = 0x01;
+const DTR: u8 = 0x02;
+const RXE: u8 = 0x04;
+const ER: u8 = 0x10;
+const RTS: u8 = 0x20;
+const IR: u8 = 0x40;
+const EH: u8 = 0x80;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Mode,
+    Sync1,
+    Sync2,
+    Command,
+}
+
+impl Field for State {
+    fn save(&self, state: &mut Writer) {
+        state.put(&(*self as u8));
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        match state.get::<u8>()? {
+            0 => Ok(State::Mode),
+            1 => Ok(State::Sync1),
+            2 => Ok(State::Sync2),
+            3 => Ok(State::Command),
+            value => Err(state::invalid(format!("8251 state {value} in save state"))),
+        }
+    }
+}
+
+// Intel 8251 USART. Port 0 is data and port 1 is control/status (C/D high).
+// Characters are exchanged with the endpoint at the rate set by the TxC/RxC
+// clock and the mode's baud rate factor, counted in CPU T-states.
+pub struct I8251 {
+    endpoint: Box<dyn Endpoint>,
+    cpu_hz: u64,
+    txc_hz: u64,
+    state: State,
+    mode: u8,
+    command: u8,
+    sync: [u8; 2],
+    hunt: usize, // sync characters matched while hunting
+    errors: u8,  // PE, OE, FE and SYNDET
+    tx_buffer: Option<u8>,
+    tx_shift: Option<u8>,
+    tx_states: u64,
+    rx_buffer: u8,
+    rx_ready: bool,
+    rx_states: u64,
+    dsr: bool,
+    interrupt: Option<u8>,
+}
+
+impl I8251 {
+    // A TxC/RxC clock of 0 is taken as 1 Hz.
+    pub fn new(endpoint: Box<dyn Endpoint>, cpu_hz: u32, txc_hz: u32) -> Self {
+        Self {
+            endpoint,
+            cpu_hz: cpu_hz as u64,
+            txc_hz: txc_hz.max(1) as u64,
+            state: State::Mode,
+            mode: 0,
+            command: 0,
+            sync: [0; 2],
+            hunt: 0,
+            errors: 0,
+            tx_buffer: None,
+            tx_shift: None,
+            tx_states: 0,
+            rx_buffer: 0,
+            rx_ready: false,
+            rx_states: 0,
+            dsr: false,
+            interrupt: None,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = State::Mode;
+        self.command = 0;
+        self.hunt = 0;
+        self.errors = 0;
+        self.tx_buffer = None;
+        self.tx_shift = None;
+        self.tx_states = 0;
+        self.rx_ready = false;
+        self.rx_states = 0;
+    }
+
+    // Wires RxRDY and TxRDY to INTR.
+    pub fn set_interrupt(&mut self, instruction: Option<u8>) {
+        self.interrupt = instruction;
+    }
+
+    pub fn set_dsr(&mut self, dsr: bool) {
+        self.dsr = dsr;
+    }
+
+    pub fn txrdy(&self) -> bool {
+        self.tx_buffer.is_none() && self.command & TXEN != 0
+    }
+
+    pub fn rxrdy(&self) -> bool {
+        self.rx_ready
+    }
+
+    pub fn txempty(&self) -> bool {
+        self.tx_buffer.is_none() && self.tx_shift.is_none()
+    }
+
+    pub fn dtr(&self) -> bool {
+        self.command & DTR != 0
+    }
+
+    pub fn rts(&self) -> bool {
+        self.command & RTS != 0
+    }
+
+    pub fn status(&self) -> u8 {
+        let mut status = self.errors;
+        if self.tx_buffer.is_none() {
+            status |= TXRDY;
+        }
+        if self.rx_ready {
+            status |= RXRDY;
+        }
+        if self.txempty() {
+            status |= TXEMPTY;
+        }
+        if self.dsr {
+            status |= DSR;
+        }
+        status
+    }
+
+    fn synchronous(&self) -> bool {
+        self.mode & 0x03 == 0
+    }
+
+    fn data_bits(&self) -> u64 {
+        5 + ((self.mode >> 2) & 0x03) as u64
+    }
+
+    // Length of one character on the line in T-states.
+    fn frame_states(&self) -> u64 {
+        let factor = match self.mode & 0x03 {
+            2 => 16,
+            3 => 64,
+            _ => 1,
+        };
+        let parity = ((self.mode >> 4) & 0x01) as u64;
+        let half_bits = if self.synchronous() {
+            2 * (self.data_bits() + parity)
+        } else {
+            let stop = match self.mode >> 6 {
+                2 => 3,
+                3 => 4,
+                _ => 2,
+            };
+            2 * (1 + self.data_bits() + parity) + stop
+        };
+        self.cpu_hz * factor * half_bits / (2 * self.txc_hz)
+    }
+
+    fn control(&mut self, value: u8) {
+        match self.state {
+            State::Mode => {
+                self.mode = value;
+                self.state = if self.synchronous() { State::Sync1 } else { State::Command };
+            }
+            State::Sync1 => {
+                self.sync[0] = value;
+                self.state = if self.mode & 0x80 != 0 { State::Command } else { State::Sync2 };
+            }
+            State::Sync2 => {
+                self.sync[1] = value;
+                self.state = State::Command;
+            }
+            State::Command => {
+                if value & IR != 0 {
+                    self.reset();
+                    return;
+                }
+                if value & ER != 0 {
+                    self.errors &= !(PE | OE | FE);
+                }
+                if value & EH != 0 {
+                    self.hunt = 0;
+                }
+                self.command = value & !(IR | ER);
+            }
+        }
+    }
+
+    fn receive(&mut self, value: u8) {
+        let value = value & ((1u16 << self.data_bits()) - 1) as u8;
+        if self.synchronous() && self.command & EH != 0 {
+            let sync_characters = if self.mode & 0x80 != 0 { 1 } else { 2 };
+            self.hunt = if value == self.sync[self.hunt] { self.hunt + 1 } else { 0 };
+            if self.hunt == sync_characters {
+                self.command &= !EH;
+                self.errors |= SYNDET;
+            }
+            return;
+        }
+        if self.rx_ready {
+            self.errors |= OE;
+        }
+        self.rx_buffer = value;
+        self.rx_ready = true;
+    }
+}
+
+impl Device for I8251 {
+    fn input(&mut self, port: u8) -> u8 {
+        if port & 0x01 == 0 {
+            self.rx_ready = false;
+            return self.rx_buffer;
+        }
+        let status = self.status();
+        if self.synchronous() {
+            self.errors &= !SYNDET;
+        }
+        status
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port & 0x01 == 0 {
+            self.tx_buffer = Some(value);
+        } else {
+            self.control(value);
+        }
+    }
+
+    fn clock(&mut self, states: usize) {
+        if self.state != State::Command {
+            return;
+        }
+        let states = states as u64;
+        if self.tx_shift.is_none() && self.command & TXEN != 0 {
+            if let Some(value) = self.tx_buffer.take() {
+                self.tx_shift = Some(value);
+                self.tx_states = self.frame_states();
+            }
+        }
+        if let Some(value) = self.tx_shift {
+            self.tx_states = self.tx_states.saturating_sub(states);
+            if self.tx_states == 0 {
+                let value = value & ((1u16 << self.data_bits()) - 1) as u8;
+                self.endpoint.send(value);
+                self.tx_shift = None;
+            }
+        }
+        if self.command & RXE != 0 {
+            self.rx_states = self.rx_states.saturating_sub(states);
+            if self.rx_states == 0 {
+                self.rx_states = self.frame_states();
+                if let Some(value) = self.endpoint.receive() {
+                    self.receive(value);
+                }
+            }
+        }
+    }
+
+    fn intr(&self) -> bool {
+        self.interrupt.is_some() && (self.rxrdy() || self.txrdy())
+    }
+
+    fn inta(&mut self) -> u8 {
+        self.interrupt.unwrap_or(0xFF)
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.state);
+        state.put(&self.mode);
+        state.put(&self.command);
+        state.put(&self.sync);
+        state.put(&self.hunt);
+        state.put(&self.errors);
+        state.put(&self.tx_buffer);
+        state.put(&self.tx_shift);
+        state.put(&self.tx_states);
+        state.put(&self.rx_buffer);
+        state.put(&self.rx_ready);
+        state.put(&self.rx_states);
+        state.put(&self.dsr);
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.state = state.get()?;
+        self.mode = state.get()?;
+        self.command = state.get()?;
+        self.sync = state.get()?;
+        self.hunt = state.get()?;
+        if self.hunt > self.sync.len() {
+            return Err(state::invalid(format!("8251 sync count {} in save state", self.hunt)));
+        }
+        self.errors = state.get()?;
+        self.tx_buffer = state.get()?;
+        self.tx_shift = state.get()?;
+        self.tx_states = state.get()?;
+        self.rx_buffer = state.get()?;
+        self.rx_ready = state.get()?;
+        self.rx_states = state.get()?;
+        self.dsr = state.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::endpoint::Buffer;
+
+    // 2 MHz CPU, 9600 baud at 16x: 8N1 takes 2083 T-states per character
+    fn usart(buffer: &Buffer) -> I8251 {
+        let mut usart = I8251::new(Box::new(buffer.clone()), 2_000_000, 9600 * 16);
+        usart.output(1, 0x4E);
+        usart.output(1, 0x37); // RTS, ER, RxE, DTR, TxEN
+        usart
+    }
+
+    #[test]
+    fn transmit() {
+        let buffer = Buffer::new();
+        let mut usart = usart(&buffer);
+        assert_eq!(usart.input(1) & (TXRDY | TXEMPTY), TXRDY | TXEMPTY);
+        usart.output(0, b'A');
+        assert_eq!(usart.input(1) & (TXRDY | TXEMPTY), 0);
+        usart.clock(1);
+        assert_eq!(usart.input(1) & (TXRDY | TXEMPTY), TXRDY);
+        usart.output(0, b'B');
+        usart.clock(2081);
+        assert_eq!(buffer.take_output(), b"");
+        usart.clock(1);
+        assert_eq!(buffer.take_output(), b"A");
+        usart.clock(1);
+        usart.clock(2083);
+        assert_eq!(buffer.take_output(), b"B");
+        assert!(usart.txempty());
+    }
+
+    #[test]
+    fn receive() {
+        let buffer = Buffer::new();
+        let mut usart = usart(&buffer);
+        buffer.push_input(b"xyz");
+        usart.clock(1);
+        assert_eq!(usart.input(1) & RXRDY, RXRDY);
+        assert_eq!(usart.input(0), b'x');
+        assert_eq!(usart.input(1) & RXRDY, 0);
+        usart.clock(2083);
+        usart.clock(2083);
+        assert_eq!(usart.input(1) & (RXRDY | OE), RXRDY | OE);
+        assert_eq!(usart.input(0), b'z');
+        usart.output(1, 0x15); // ER, RxE, TxEN
+        assert_eq!(usart.input(1) & OE, 0);
+    }
+
+    #[test]
+    fn internal_reset() {
+        let buffer = Buffer::new();
+        let mut usart = usart(&buffer);
+        usart.output(1, 0x40);
+        usart.output(1, 0x00); // synchronous mode, 5 bits, two sync characters: 65 T-states each
+        usart.output(1, 0x16);
+        usart.output(1, 0x17);
+        usart.output(1, 0x84); // hunt, RxE
+        buffer.push_input(&[0x16, 0x17, 0x01]);
+        usart.clock(1);
+        usart.clock(65);
+        assert_eq!(usart.input(1) & (SYNDET | RXRDY), SYNDET);
+        let mut state = Writer::new();
+        usart.save(&mut state);
+        usart.load(&mut Reader::new(&state.into_bytes())).unwrap();
+        usart.clock(65);
+        assert_eq!(usart.input(1) & (SYNDET | RXRDY), RXRDY);
+        assert_eq!(usart.input(0), 0x01);
+    }
+
+    #[test]
+    fn bad_settings() {
+        let buffer = Buffer::new();
+        let mut usart = I8251::new(Box::new(buffer.clone()), 2_000_000, 0);
+        usart.output(1, 0x4E);
+        usart.output(1, 0x01); // TxEN
+        usart.output(0, b'A');
+        usart.clock(1);
+        assert!(!usart.txempty());
+
+        let mut state = Writer::new();
+        state.put(&State::Command);
+        state.put(&[0x4Eu8, 0x00, 0x16, 0x17]);
+        state.put(&3usize);
+        let bytes = state.into_bytes();
+        assert!(usart.load(&mut Reader::new(&bytes)).unwrap_err().to_string().contains("sync count 3"));
+    }
+
+    #[test]
+    fn interrupt() {
+        let buffer = Buffer::new();
+        let mut usart = usart(&buffer);
+        usart.set_interrupt(Some(0xEF));
+        usart.output(1, 0x04); // RxE only
+        assert!(!usart.intr());
+        buffer.push_input(b"!");
+        usart.clock(1);
+        assert!(usart.intr());
+        assert_eq!(usart.inta(), 0xEF);
+        usart.input(0);
+        assert!(!usart.intr());
+    }
+}
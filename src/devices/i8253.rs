@@ -0,0 +1,475 @@
+use std::io;
+
+use crate::io::Device;
+use crate::state::{Reader, Writer};
+
+pub type OutLine = Box<dyn FnMut(u64, bool)>;
+
+struct Counter {
+    mode: u8,
+    bcd: bool,
+    access: u8, // RL1 RL0: 1 LSB only, 2 MSB only, 3 LSB then MSB
+    count: u16, // count register as written
+    low: Option<u8>, // LSB written, waiting for the MSB
+    element: u32, // clock pulses left, 0 while not counting
+    half: u32,    // mode 3 only, pulses left in the current half period
+    latch: Option<u16>,
+    read_msb: bool,
+    armed: bool,   // count written, loaded on the next clock pulse
+    trigger: bool, // gate rising edge
+    strobe: bool,
+    gate: bool,
+    out: bool,
+    clk_hz: u64,
+    phase: u64,
+    interrupt: Option<u8>,
+    pending: bool,
+    line: Option<OutLine>,
+}
+
+impl Counter {
+    fn new(clk_hz: u64) -> Self {
+        Self {
+            mode: 0,
+            bcd: false,
+            access: 3,
+            count: 0,
+            low: None,
+            element: 0,
+            half: 0,
+            latch: None,
+            read_msb: false,
+            armed: false,
+            trigger: false,
+            strobe: false,
+            gate: true,
+            out: false,
+            clk_hz,
+            phase: 0,
+            interrupt: None,
+            pending: false,
+            line: None,
+        }
+    }
+
+    // The count register as a number of clock pulses; 0 is the largest count.
+    fn initial(&self) -> u32 {
+        match (self.count, self.bcd) {
+            (0, false) => 0x10000,
+            (0, true) => 10000,
+            (count, false) => count as u32,
+            (count, true) => (0..4).rev().fold(0, |value, digit| value * 10 + ((count >> (digit * 4)) & 0xF) as u32),
+        }
+    }
+
+    fn value(&self) -> u16 {
+        let element = if self.mode == 3 { self.half * 2 } else { self.element };
+        if self.bcd {
+            let element = element % 10000;
+            (0..4).fold(0, |value, digit| value | ((element / 10u32.pow(digit) % 10) as u16) << (digit * 4))
+        } else {
+            element as u16
+        }
+    }
+
+    fn set_out(&mut self, out: bool, ticks: u64) {
+        if out == self.out {
+            return;
+        }
+        self.out = out;
+        if out && self.interrupt.is_some() {
+            self.pending = true;
+        }
+        if let Some(line) = &mut self.line {
+            line(ticks, out);
+        }
+    }
+
+    fn control(&mut self, value: u8, ticks: u64) {
+        let access = (value >> 4) & 0x03;
+        if access == 0 {
+            self.latch.get_or_insert(self.value());
+            return;
+        }
+        self.access = access;
+        self.mode = match (value >> 1) & 0x07 {
+            6 => 2,
+            7 => 3,
+            mode => mode,
+        };
+        self.bcd = value & 0x01 != 0;
+        self.low = None;
+        self.latch = None;
+        self.read_msb = false;
+        self.element = 0;
+        self.armed = false;
+        self.trigger = false;
+        self.strobe = false;
+        self.set_out(self.mode != 0, ticks);
+    }
+
+    fn write(&mut self, value: u8, ticks: u64) {
+        let count = match (self.access, self.low.take()) {
+            (1, _) => value as u16,
+            (2, _) => (value as u16) << 8,
+            (_, Some(low)) => u16::from_le_bytes([low, value]),
+            (_, None) => {
+                self.low = Some(value);
+                if self.mode == 0 {
+                    self.set_out(false, ticks);
+                }
+                return;
+            }
+        };
+        self.count = count;
+        match self.mode {
+            0 => {
+                self.set_out(false, ticks);
+                self.armed = true;
+            }
+            2 | 3 if self.element > 0 => {} // taken at the end of the current period
+            2..=4 => self.armed = true,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.latch.unwrap_or_else(|| self.value());
+        let msb = match self.access {
+            1 => false,
+            2 => true,
+            _ => {
+                self.read_msb = !self.read_msb;
+                !self.read_msb
+            }
+        };
+        if self.access != 3 || msb {
+            self.latch = None;
+        }
+        value.to_le_bytes()[msb as usize]
+    }
+
+    fn set_gate(&mut self, gate: bool, ticks: u64) {
+        if gate && !self.gate {
+            self.trigger = true;
+        }
+        self.gate = gate;
+        if !gate && (self.mode == 2 || self.mode == 3) {
+            self.set_out(true, ticks);
+        }
+    }
+
+    fn load(&mut self) {
+        self.element = self.initial();
+        self.half = self.element.div_ceil(2);
+        self.armed = false;
+        self.trigger = false;
+    }
+
+    fn pulse(&mut self, ticks: u64) {
+        if self.strobe {
+            self.strobe = false;
+            self.set_out(true, ticks);
+        }
+        match self.mode {
+            0 | 4 if self.armed => self.load(),
+            1 | 5 if self.trigger => {
+                self.load();
+                if self.mode == 1 {
+                    self.set_out(false, ticks);
+                }
+            }
+            2 | 3 if self.armed || (self.trigger && self.element > 0) => {
+                self.load();
+                self.set_out(true, ticks);
+            }
+            _ if self.element == 0 || (!self.gate && self.mode != 1 && self.mode != 5) => {}
+            2 => {
+                self.element -= 1;
+                if self.element == 1 {
+                    self.set_out(false, ticks);
+                } else if self.element == 0 {
+                    self.set_out(true, ticks);
+                    self.load();
+                }
+            }
+            3 => {
+                self.half -= 1;
+                if self.half == 0 {
+                    let out = !self.out;
+                    self.set_out(out, ticks);
+                    let initial = self.initial();
+                    self.half = if out { initial.div_ceil(2) } else { initial / 2 };
+                }
+            }
+            _ => {
+                self.element -= 1;
+                if self.element == 0 {
+                    match self.mode {
+                        0 | 1 => self.set_out(true, ticks),
+                        _ => {
+                            self.set_out(false, ticks);
+                            self.strobe = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn save_state(&self, state: &mut Writer) {
+        state.put(&self.mode);
+        state.put(&self.bcd);
+        state.put(&self.access);
+        state.put(&self.count);
+        state.put(&self.low);
+        state.put(&self.element);
+        state.put(&self.half);
+        state.put(&self.latch);
+        state.put(&self.read_msb);
+        state.put(&self.armed);
+        state.put(&self.trigger);
+        state.put(&self.strobe);
+        state.put(&self.gate);
+        state.put(&self.out);
+        state.put(&self.phase);
+        state.put(&self.pending);
+    }
+
+    fn load_state(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.mode = state.get()?;
+        self.bcd = state.get()?;
+        self.access = state.get()?;
+        self.count = state.get()?;
+        self.low = state.get()?;
+        self.element = state.get()?;
+        self.half = state.get()?;
+        self.latch = state.get()?;
+        self.read_msb = state.get()?;
+        self.armed = state.get()?;
+        self.trigger = state.get()?;
+        self.strobe = state.get()?;
+        self.gate = state.get()?;
+        self.out = state.get()?;
+        self.phase = state.get()?;
+        self.pending = state.get()?;
+        Ok(())
+    }
+}
+
+// Intel 8253 programmable interval timer. Ports 0-2 are the counters and port
+// 3 the control word. Each counter's CLK input runs at `clk_hz` while the
+// device is clocked with CPU T-states at `cpu_hz`.
+pub struct I8253 {
+    counters: [Counter; 3],
+    cpu_hz: u64,
+    ticks: u64,
+}
+
+impl I8253 {
+    pub fn new(cpu_hz: u32, clk_hz: u32) -> Self {
+        let clk_hz = clk_hz as u64;
+        Self {
+            counters: [Counter::new(clk_hz), Counter::new(clk_hz), Counter::new(clk_hz)],
+            cpu_hz: cpu_hz.max(1) as u64,
+            ticks: 0,
+        }
+    }
+
+    pub fn set_clock(&mut self, counter: usize, clk_hz: u32) {
+        self.counters[counter].clk_hz = clk_hz as u64;
+    }
+
+    pub fn set_gate(&mut self, counter: usize, gate: bool) {
+        self.counters[counter].set_gate(gate, self.ticks);
+    }
+
+    pub fn out(&self, counter: usize) -> bool {
+        self.counters[counter].out
+    }
+
+    // Rising edges of OUT raise INTR until acknowledged with `instruction`.
+    pub fn set_interrupt(&mut self, counter: usize, instruction: Option<u8>) {
+        self.counters[counter].interrupt = instruction;
+        self.counters[counter].pending = false;
+    }
+
+    // Called with the T-state and new level whenever OUT changes, e.g. to drive a speaker.
+    pub fn connect_out(&mut self, counter: usize, line: OutLine) {
+        self.counters[counter].line = Some(line);
+    }
+}
+
+impl Device for I8253 {
+    fn input(&mut self, port: u8) -> u8 {
+        match port & 0x03 {
+            3 => 0xFF,
+            counter => self.counters[counter as usize].read(),
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port & 0x03 {
+            3 => {
+                if let Some(counter) = self.counters.get_mut((value >> 6) as usize) {
+                    counter.control(value, self.ticks);
+                }
+            }
+            counter => self.counters[counter as usize].write(value, self.ticks),
+        }
+    }
+
+    fn clock(&mut self, states: usize) {
+        for _ in 0..states {
+            self.ticks += 1;
+            for counter in &mut self.counters {
+                counter.phase += counter.clk_hz;
+                while counter.phase >= self.cpu_hz {
+                    counter.phase -= self.cpu_hz;
+                    counter.pulse(self.ticks);
+                }
+            }
+        }
+    }
+
+    fn intr(&self) -> bool {
+        self.counters.iter().any(|counter| counter.pending)
+    }
+
+    fn inta(&mut self) -> u8 {
+        match self.counters.iter_mut().find(|counter| counter.pending) {
+            Some(counter) => {
+                counter.pending = false;
+                counter.interrupt.unwrap_or(0xFF)
+            }
+            None => 0xFF,
+        }
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.ticks);
+        for counter in &self.counters {
+            counter.save_state(state);
+        }
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.ticks = state.get()?;
+        for counter in &mut self.counters {
+            counter.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn outs(pit: &mut I8253, counter: usize, pulses: usize) -> String {
+        (0..pulses)
+            .map(|_| {
+                pit.clock(1);
+                if pit.out(counter) { '1' } else { '0' }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn interrupt_on_terminal_count() {
+        let mut pit = I8253::new(1, 1);
+        pit.output(3, 0x30); // counter 0, LSB then MSB, mode 0
+        pit.output(0, 0x03);
+        pit.output(0, 0x00);
+        assert_eq!(outs(&mut pit, 0, 6), "000111");
+        pit.set_gate(0, false);
+        pit.output(0, 0x02);
+        pit.output(0, 0x00);
+        assert_eq!(outs(&mut pit, 0, 4), "0000");
+        pit.set_gate(0, true);
+        assert_eq!(outs(&mut pit, 0, 2), "01");
+    }
+
+    #[test]
+    fn one_shot() {
+        let mut pit = I8253::new(1, 1);
+        pit.output(3, 0x52); // counter 1, LSB only, mode 1
+        pit.output(1, 0x03);
+        pit.set_gate(1, false);
+        assert_eq!(outs(&mut pit, 1, 2), "11");
+        pit.set_gate(1, true);
+        assert_eq!(outs(&mut pit, 1, 6), "000111");
+    }
+
+    #[test]
+    fn rate_generator() {
+        let mut pit = I8253::new(1, 1);
+        pit.output(3, 0x94); // counter 2, LSB only, mode 2
+        pit.output(2, 0x04);
+        assert_eq!(outs(&mut pit, 2, 13), "1110111011101");
+        // A CPU clock of 0 runs as 1 Hz.
+        let mut pit = I8253::new(0, 1);
+        pit.output(3, 0x94);
+        pit.output(2, 0x04);
+        assert_eq!(outs(&mut pit, 2, 13), "1110111011101");
+    }
+
+    #[test]
+    fn square_wave() {
+        let mut pit = I8253::new(1, 1);
+        pit.output(3, 0x16); // counter 0, LSB only, mode 3
+        pit.output(0, 0x05);
+        assert_eq!(outs(&mut pit, 0, 11), "11100111001");
+        pit.set_gate(0, false);
+        assert!(pit.out(0));
+    }
+
+    #[test]
+    fn strobes() {
+        let mut pit = I8253::new(1, 1);
+        pit.output(3, 0x18); // counter 0, LSB only, mode 4
+        pit.output(0, 0x02);
+        assert_eq!(outs(&mut pit, 0, 5), "11011");
+        pit.output(3, 0x5A); // counter 1, LSB only, mode 5
+        pit.output(1, 0x02);
+        assert_eq!(outs(&mut pit, 1, 3), "111");
+        pit.set_gate(1, false);
+        pit.set_gate(1, true);
+        assert_eq!(outs(&mut pit, 1, 5), "11011");
+    }
+
+    #[test]
+    fn latch_and_bcd() {
+        let mut pit = I8253::new(2_000_000, 1_000_000);
+        pit.output(3, 0x31); // counter 0, LSB then MSB, mode 0, BCD
+        pit.output(0, 0x00);
+        pit.output(0, 0x10);
+        pit.clock(2);
+        pit.clock(2);
+        pit.output(3, 0x00);
+        pit.clock(20);
+        assert_eq!(pit.input(0), 0x99);
+        assert_eq!(pit.input(0), 0x09);
+        assert_eq!(pit.input(0), 0x89);
+        assert_eq!(pit.input(0), 0x09);
+    }
+
+    #[test]
+    fn outputs() {
+        let edges = Rc::new(RefCell::new(Vec::new()));
+        let mut pit = I8253::new(1, 1);
+        let log = edges.clone();
+        pit.connect_out(0, Box::new(move |ticks, out| log.borrow_mut().push((ticks, out))));
+        pit.set_interrupt(0, Some(0xCF));
+        pit.output(3, 0x16);
+        pit.output(0, 0x04);
+        pit.clock(3);
+        assert!(pit.intr());
+        assert_eq!(pit.inta(), 0xCF);
+        assert!(!pit.intr());
+        pit.clock(4);
+        assert_eq!(*edges.borrow(), [(0, true), (3, false), (5, true), (7, false)]);
+    }
+}
@@ -0,0 +1,451 @@
+use std::io;
+
+use crate::io::Device;
+use crate::state::{Reader, Writer};
+
+pub type InputPins = Box<dyn FnMut() -> u8>;
+pub type OutputPins = Box<dyn FnMut(u8)>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Port {
+    A,
+    B,
+    C,
+}
+
+// Strobed handshake state of port A or B in mode 1 or 2.
+#[derive(Default)]
+struct Handshake {
+    latch: u8, // input latch
+    ibf: bool,
+    obf: bool, // buffer full; the OBF pin is active low
+    ack: bool, // acknowledged since the last write
+    inte_in: bool,
+    inte_out: bool,
+    interrupt: Option<u8>,
+}
+
+impl Handshake {
+    fn intr(&self, input: bool, output: bool) -> bool {
+        (input && self.inte_in && self.ibf) || (output && self.inte_out && self.ack)
+    }
+
+    fn save_state(&self, state: &mut Writer) {
+        state.put(&self.latch);
+        state.put(&self.ibf);
+        state.put(&self.obf);
+        state.put(&self.ack);
+        state.put(&self.inte_in);
+        state.put(&self.inte_out);
+    }
+
+    fn load_state(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.latch = state.get()?;
+        self.ibf = state.get()?;
+        self.obf = state.get()?;
+        self.ack = state.get()?;
+        self.inte_in = state.get()?;
+        self.inte_out = state.get()?;
+        Ok(())
+    }
+}
+
+// Intel 8255 programmable peripheral interface. Ports 0-2 are ports A, B and
+// C and port 3 the control word. The host drives the pins through callbacks
+// and, in modes 1 and 2, the STB and ACK handshake inputs.
+pub struct I8255 {
+    mode_a: u8,
+    mode_b: u8,
+    a_input: bool,
+    b_input: bool,
+    c_upper_input: bool,
+    c_lower_input: bool,
+    outputs: [u8; 3],
+    handshakes: [Handshake; 2],
+    inputs: [Option<InputPins>; 3],
+    lines: [Option<OutputPins>; 3],
+    c_pins: u8, // last levels sent to the port C callback
+}
+
+impl I8255 {
+    pub fn new() -> Self {
+        let mut ppi = Self {
+            mode_a: 0,
+            mode_b: 0,
+            a_input: true,
+            b_input: true,
+            c_upper_input: true,
+            c_lower_input: true,
+            outputs: [0; 3],
+            handshakes: Default::default(),
+            inputs: [None, None, None],
+            lines: [None, None, None],
+            c_pins: 0,
+        };
+        ppi.control(0x9B); // all ports mode 0 input
+        ppi
+    }
+
+    // Levels on the port's pins while it is an input, 0xFF if unconnected.
+    pub fn connect_input(&mut self, port: Port, pins: InputPins) {
+        self.inputs[port as usize] = Some(pins);
+    }
+
+    // Called with the port's output levels whenever they may have changed.
+    // For port C this includes the handshake outputs, with OBF active low.
+    pub fn connect_output(&mut self, port: Port, line: OutputPins) {
+        self.lines[port as usize] = Some(line);
+    }
+
+    // INTR of port A or B raises the CPU's INTR; A is acknowledged first.
+    pub fn set_interrupt(&mut self, port: Port, instruction: Option<u8>) {
+        if let Some(handshake) = self.handshakes.get_mut(port as usize) {
+            handshake.interrupt = instruction;
+        }
+    }
+
+    // Pulses STB of port A or B, loading `value` into the input latch.
+    pub fn strobe(&mut self, port: Port, value: u8) {
+        if let Some(handshake) = self.handshakes.get_mut(port as usize) {
+            handshake.latch = value;
+            handshake.ibf = true;
+        }
+        self.update_c();
+    }
+
+    // Pulses ACK of port A or B and returns the byte in its output latch.
+    pub fn acknowledge(&mut self, port: Port) -> u8 {
+        if let Some(handshake) = self.handshakes.get_mut(port as usize) {
+            handshake.obf = false;
+            handshake.ack = true;
+        }
+        self.update_c();
+        self.outputs[port as usize]
+    }
+
+    pub fn ibf(&self, port: Port) -> bool {
+        self.handshakes.get(port as usize).is_some_and(|handshake| handshake.ibf)
+    }
+
+    pub fn obf(&self, port: Port) -> bool {
+        self.handshakes.get(port as usize).is_some_and(|handshake| handshake.obf)
+    }
+
+    pub fn intr_a(&self) -> bool {
+        match self.mode_a {
+            0 => false,
+            1 => self.handshakes[0].intr(self.a_input, !self.a_input),
+            _ => self.handshakes[0].intr(true, true),
+        }
+    }
+
+    pub fn intr_b(&self) -> bool {
+        self.mode_b == 1 && self.handshakes[1].intr(self.b_input, !self.b_input)
+    }
+
+    // Port C bits used for handshaking by groups A and B.
+    fn c_handshake(&self) -> u8 {
+        let upper = match (self.mode_a, self.a_input) {
+            (0, _) => 0x00,
+            (1, true) => 0x38,
+            (1, false) => 0xC8,
+            _ => 0xF8,
+        };
+        let lower = if self.mode_b == 1 { 0x07 } else { 0x00 };
+        upper | lower
+    }
+
+    // Port C bits that are plain inputs.
+    fn c_inputs(&self) -> u8 {
+        let mut inputs = 0;
+        if self.c_upper_input {
+            inputs |= 0xF0;
+        }
+        if self.c_lower_input {
+            inputs |= 0x0F;
+        }
+        inputs & !self.c_handshake()
+    }
+
+    // Status of the handshake bits as read from port C.
+    fn c_status(&self) -> u8 {
+        let a = &self.handshakes[0];
+        let b = &self.handshakes[1];
+        let mut status = 0;
+        if self.mode_a != 0 {
+            status |= (self.intr_a() as u8) << 3;
+            if self.mode_a == 2 || self.a_input {
+                status |= (a.ibf as u8) << 5 | (a.inte_in as u8) << 4;
+            }
+            if self.mode_a == 2 || !self.a_input {
+                status |= (!a.obf as u8) << 7 | (a.inte_out as u8) << 6;
+            }
+        }
+        if self.mode_b == 1 {
+            status |= self.intr_b() as u8;
+            if self.b_input {
+                status |= (b.ibf as u8) << 1 | (b.inte_in as u8) << 2;
+            } else {
+                status |= (!b.obf as u8) << 1 | (b.inte_out as u8) << 2;
+            }
+        }
+        status
+    }
+
+    fn update_c(&mut self) {
+        let pins = (self.outputs[2] & !self.c_inputs() & !self.c_handshake()) | self.c_status();
+        if pins != self.c_pins {
+            self.c_pins = pins;
+            if let Some(line) = &mut self.lines[2] {
+                line(pins);
+            }
+        }
+    }
+
+    fn pins(&mut self, port: Port) -> u8 {
+        match &mut self.inputs[port as usize] {
+            Some(pins) => pins(),
+            None => 0xFF,
+        }
+    }
+
+    fn control(&mut self, value: u8) {
+        if value & 0x80 == 0 {
+            self.set_c_bit((value >> 1) & 0x07, value & 0x01 != 0);
+            return;
+        }
+        self.mode_a = match (value >> 5) & 0x03 {
+            0 => 0,
+            1 => 1,
+            _ => 2,
+        };
+        self.a_input = value & 0x10 != 0;
+        self.c_upper_input = value & 0x08 != 0;
+        self.mode_b = (value >> 2) & 0x01;
+        self.b_input = value & 0x02 != 0;
+        self.c_lower_input = value & 0x01 != 0;
+        self.outputs = [0; 3];
+        for handshake in &mut self.handshakes {
+            *handshake = Handshake { interrupt: handshake.interrupt, ..Default::default() };
+        }
+        for port in [Port::A, Port::B] {
+            let input = if port == Port::A { self.a_input || self.mode_a == 2 } else { self.b_input };
+            if !input {
+                if let Some(line) = &mut self.lines[port as usize] {
+                    line(0);
+                }
+            }
+        }
+        self.c_pins = !self.c_pins;
+        self.update_c();
+    }
+
+    fn set_c_bit(&mut self, bit: u8, set: bool) {
+        let [a, b] = &mut self.handshakes;
+        match (bit, self.mode_a, self.a_input, self.mode_b) {
+            (4, 1, true, _) | (4, 2, _, _) => a.inte_in = set,
+            (6, 1, false, _) | (6, 2, _, _) => a.inte_out = set,
+            (2, _, _, 1) if self.b_input => b.inte_in = set,
+            (2, _, _, 1) => b.inte_out = set,
+            _ => {
+                let mask = 1 << bit;
+                self.outputs[2] = if set { self.outputs[2] | mask } else { self.outputs[2] & !mask };
+            }
+        }
+        self.update_c();
+    }
+
+    fn read_port(&mut self, port: Port) -> u8 {
+        let (mode, input) = match port {
+            Port::A => (self.mode_a, self.a_input),
+            _ => (self.mode_b, self.b_input),
+        };
+        match (mode, input) {
+            (0, true) => self.pins(port),
+            (0, false) | (1, false) => self.outputs[port as usize],
+            _ => {
+                let handshake = &mut self.handshakes[port as usize];
+                handshake.ibf = false;
+                let value = handshake.latch;
+                self.update_c();
+                value
+            }
+        }
+    }
+
+    fn write_port(&mut self, port: Port, value: u8) {
+        let (mode, input) = match port {
+            Port::A => (self.mode_a, self.a_input),
+            _ => (self.mode_b, self.b_input),
+        };
+        self.outputs[port as usize] = value;
+        if mode == 0 && input {
+            return;
+        }
+        if mode != 0 {
+            let handshake = &mut self.handshakes[port as usize];
+            handshake.obf = true;
+            handshake.ack = false;
+        }
+        // In mode 2 port A only drives the pins while ACK is low.
+        if mode != 2 {
+            if let Some(line) = &mut self.lines[port as usize] {
+                line(value);
+            }
+        }
+        self.update_c();
+    }
+}
+
+impl Default for I8255 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for I8255 {
+    fn input(&mut self, port: u8) -> u8 {
+        match port & 0x03 {
+            0 => self.read_port(Port::A),
+            1 => self.read_port(Port::B),
+            2 => {
+                let inputs = self.c_inputs();
+                let pins = if inputs != 0 { self.pins(Port::C) } else { 0 };
+                (pins & inputs) | (self.outputs[2] & !inputs & !self.c_handshake()) | self.c_status()
+            }
+            _ => 0xFF,
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port & 0x03 {
+            0 => self.write_port(Port::A, value),
+            1 => self.write_port(Port::B, value),
+            2 => {
+                self.outputs[2] = value;
+                self.update_c();
+            }
+            _ => self.control(value),
+        }
+    }
+
+    fn intr(&self) -> bool {
+        (self.intr_a() && self.handshakes[0].interrupt.is_some()) || (self.intr_b() && self.handshakes[1].interrupt.is_some())
+    }
+
+    fn inta(&mut self) -> u8 {
+        let handshake = if self.intr_a() && self.handshakes[0].interrupt.is_some() { &self.handshakes[0] } else { &self.handshakes[1] };
+        handshake.interrupt.unwrap_or(0xFF)
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.mode_a);
+        state.put(&self.mode_b);
+        state.put(&self.a_input);
+        state.put(&self.b_input);
+        state.put(&self.c_upper_input);
+        state.put(&self.c_lower_input);
+        state.put(&self.outputs);
+        state.put(&self.c_pins);
+        for handshake in &self.handshakes {
+            handshake.save_state(state);
+        }
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.mode_a = state.get()?;
+        self.mode_b = state.get()?;
+        self.a_input = state.get()?;
+        self.b_input = state.get()?;
+        self.c_upper_input = state.get()?;
+        self.c_lower_input = state.get()?;
+        self.outputs = state.get()?;
+        self.c_pins = state.get()?;
+        for handshake in &mut self.handshakes {
+            handshake.load_state(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    #[test]
+    fn mode_0() {
+        let keys = Rc::new(Cell::new(0x5A));
+        let display = Rc::new(Cell::new(0));
+        let c = Rc::new(Cell::new(0));
+        let mut ppi = I8255::new();
+        let pins = keys.clone();
+        ppi.connect_input(Port::A, Box::new(move || pins.get()));
+        let pins = display.clone();
+        ppi.connect_output(Port::B, Box::new(move |value| pins.set(value)));
+        let pins = c.clone();
+        ppi.connect_output(Port::C, Box::new(move |value| pins.set(value)));
+        ppi.output(3, 0x98); // A input, C upper input, B output, C lower output
+        assert_eq!(ppi.input(0), 0x5A);
+        ppi.output(1, 0x3C);
+        assert_eq!(display.get(), 0x3C);
+        assert_eq!(ppi.input(1), 0x3C);
+        ppi.output(3, 0x05); // set PC2
+        ppi.output(3, 0x0F); // set PC7, an input
+        assert_eq!(c.get(), 0x04);
+        ppi.output(3, 0x04); // reset PC2
+        assert_eq!(c.get(), 0x00);
+    }
+
+    #[test]
+    fn mode_1_input() {
+        let mut ppi = I8255::new();
+        ppi.set_interrupt(Port::A, Some(0xD7));
+        ppi.output(3, 0xB0); // A mode 1 input
+        ppi.output(3, 0x09); // INTE A
+        assert_eq!(ppi.input(2) & 0x38, 0x10);
+        ppi.strobe(Port::A, 0x41);
+        assert!(ppi.ibf(Port::A) && ppi.intr());
+        assert_eq!(ppi.input(2) & 0x38, 0x38);
+        assert_eq!(ppi.inta(), 0xD7);
+        assert_eq!(ppi.input(0), 0x41);
+        assert!(!ppi.ibf(Port::A) && !ppi.intr());
+    }
+
+    #[test]
+    fn mode_1_output() {
+        let printed = Rc::new(RefCell::new(Vec::new()));
+        let mut ppi = I8255::new();
+        let printer = printed.clone();
+        ppi.connect_output(Port::B, Box::new(move |value| printer.borrow_mut().push(value)));
+        ppi.output(3, 0x84); // B mode 1 output
+        ppi.output(3, 0x05); // INTE B
+        ppi.output(1, b'P');
+        assert!(ppi.obf(Port::B));
+        assert_eq!(ppi.input(2) & 0x07, 0x04);
+        assert_eq!(ppi.acknowledge(Port::B), b'P');
+        assert!(ppi.intr_b());
+        assert_eq!(ppi.input(2) & 0x07, 0x07);
+        ppi.output(1, b'Q');
+        assert!(!ppi.intr_b());
+        assert_eq!(*printed.borrow(), [0, b'P', b'Q']);
+    }
+
+    #[test]
+    fn mode_2() {
+        let mut ppi = I8255::new();
+        ppi.output(3, 0xC0); // A mode 2
+        ppi.output(3, 0x0D); // INTE 1
+        ppi.output(0, 0x12);
+        assert_eq!(ppi.input(2) & 0xF8, 0x40);
+        assert_eq!(ppi.acknowledge(Port::A), 0x12);
+        assert!(ppi.intr_a());
+        ppi.output(3, 0x0C); // clear INTE 1
+        ppi.output(3, 0x09); // INTE 2
+        ppi.strobe(Port::A, 0x34);
+        assert_eq!(ppi.input(2) & 0xF8, 0xB8);
+        assert_eq!(ppi.input(0), 0x34);
+        assert!(!ppi.intr_a());
+    }
+}
@@ -0,0 +1,282 @@
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use crate::bus::DmaMaster;
+use crate::io::Device;
+use crate::state::{Reader, Writer};
+
+// Mode set register
+const TC_STOP: u8 = 0x40;
+const AUTOLOAD: u8 = 0x80;
+const ROTATING: u8 = 0x10;
+
+// Status register
+pub const UPDATE: u8 = 0x10;
+
+// Transfer type in the top bits of the terminal count register, 0 to verify.
+const WRITE: u16 = 0x4000; // I/O to memory
+const READ: u16 = 0x8000; // memory to I/O
+
+// T-states of one DMA cycle, S1 to S4.
+const TRANSFER_STATES: usize = 4;
+
+// The I/O side of a DMA channel.
+pub trait Peripheral {
+    // Level of the DRQ input.
+    fn dreq(&self) -> bool;
+
+    // DACK with I/OR: the byte to write to memory.
+    fn read(&mut self) -> u8;
+
+    // DACK with I/OW: the byte read from memory.
+    fn write(&mut self, value: u8);
+
+    // TC was high during the last transfer.
+    fn terminal_count(&mut self) {}
+}
+
+// Intel 8257 DMA controller. Ports 0-7 are the address and terminal count
+// registers of channels 0-3 and port 8 the mode set and status register. It
+// takes the bus through HOLD for one 4 T-state cycle per byte.
+pub struct I8257 {
+    mode: u8,
+    status: u8,
+    address: [u16; 4],
+    count: [u16; 4], // transfer type and count - 1
+    msb: bool,       // first/last flip-flop
+    lowest: usize,   // channel with the lowest priority when rotating
+    peripherals: [Option<Rc<RefCell<dyn Peripheral>>>; 4],
+}
+
+impl I8257 {
+    pub fn new() -> Self {
+        Self {
+            mode: 0,
+            status: 0,
+            address: [0; 4],
+            count: [0; 4],
+            msb: false,
+            lowest: 3,
+            peripherals: Default::default(),
+        }
+    }
+
+    pub fn connect(&mut self, channel: usize, peripheral: Rc<RefCell<dyn Peripheral>>) {
+        self.peripherals[channel] = Some(peripheral);
+    }
+
+    fn requesting(&self, channel: usize) -> bool {
+        self.mode & (1 << channel) != 0
+            && self.peripherals[channel].as_ref().is_some_and(|peripheral| peripheral.borrow().dreq())
+    }
+
+    // The highest priority channel with DRQ high.
+    fn channel(&self) -> Option<usize> {
+        let highest = if self.mode & ROTATING != 0 { self.lowest + 1 } else { 0 };
+        (0..4).map(|channel| (highest + channel) % 4).find(|&channel| self.requesting(channel))
+    }
+}
+
+impl Default for I8257 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for I8257 {
+    fn input(&mut self, port: u8) -> u8 {
+        if port >= 8 {
+            let status = self.status;
+            self.status &= UPDATE;
+            return status;
+        }
+        let channel = (port >> 1) as usize;
+        let value = if port & 0x01 == 0 { self.address[channel] } else { self.count[channel] };
+        self.msb = !self.msb;
+        value.to_le_bytes()[!self.msb as usize]
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port >= 8 {
+            self.mode = value;
+            self.msb = false;
+            return;
+        }
+        let channel = (port >> 1) as usize;
+        // With autoload, channel 2 is also written to the reload registers of channel 3.
+        let channels = if channel == 2 && self.mode & AUTOLOAD != 0 { 2..=3 } else { channel..=channel };
+        for channel in channels {
+            let register = if port & 0x01 == 0 { &mut self.address[channel] } else { &mut self.count[channel] };
+            let mut bytes = register.to_le_bytes();
+            bytes[self.msb as usize] = value;
+            *register = u16::from_le_bytes(bytes);
+        }
+        self.msb = !self.msb;
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.mode);
+        state.put(&self.status);
+        state.put(&self.address);
+        state.put(&self.count);
+        state.put(&self.msb);
+        state.put(&self.lowest);
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.mode = state.get()?;
+        self.status = state.get()?;
+        self.address = state.get()?;
+        self.count = state.get()?;
+        self.msb = state.get()?;
+        self.lowest = state.get()?;
+        Ok(())
+    }
+}
+
+impl DmaMaster for I8257 {
+    fn hold(&self) -> bool {
+        self.channel().is_some()
+    }
+
+    fn transfer(&mut self, memory: &mut [u8]) -> usize {
+        let Some(channel) = self.channel() else {
+            return 0;
+        };
+        let Some(peripheral) = self.peripherals[channel].clone() else {
+            return 0;
+        };
+        let mut peripheral = peripheral.borrow_mut();
+        let address = self.address[channel] as usize;
+        match self.count[channel] & 0xC000 {
+            WRITE => {
+                let value = peripheral.read();
+                if let Some(location) = memory.get_mut(address) {
+                    *location = value;
+                }
+            }
+            READ => peripheral.write(memory.get(address).copied().unwrap_or(0xFF)),
+            _ => {}
+        }
+        self.lowest = channel;
+        self.address[channel] = self.address[channel].wrapping_add(1);
+        let count = self.count[channel] & 0x3FFF;
+        self.count[channel] = (self.count[channel] & 0xC000) | (count.wrapping_sub(1) & 0x3FFF);
+        if count == 0 {
+            self.status |= 1 << channel;
+            peripheral.terminal_count();
+            if channel == 2 && self.mode & AUTOLOAD != 0 {
+                self.address[2] = self.address[3];
+                self.count[2] = self.count[3];
+                self.status |= UPDATE;
+            } else if self.mode & TC_STOP != 0 {
+                self.mode &= !(1 << channel);
+            }
+        } else if channel == 2 {
+            self.status &= !UPDATE;
+        }
+        TRANSFER_STATES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::I8080;
+
+    #[derive(Default)]
+    struct Fifo {
+        input: Vec<u8>,
+        output: Vec<u8>,
+        requests: usize,
+        terminal_counts: usize,
+    }
+
+    impl Peripheral for Fifo {
+        fn dreq(&self) -> bool {
+            self.requests > 0
+        }
+
+        fn read(&mut self) -> u8 {
+            self.requests -= 1;
+            self.input.remove(0)
+        }
+
+        fn write(&mut self, value: u8) {
+            self.requests -= 1;
+            self.output.push(value);
+        }
+
+        fn terminal_count(&mut self) {
+            self.terminal_counts += 1;
+        }
+    }
+
+    fn program(dma: &mut I8257, channel: u8, address: u16, count: u16) {
+        for (port, value) in [(channel * 2, address), (channel * 2 + 1, count)] {
+            dma.output(port, value as u8);
+            dma.output(port, (value >> 8) as u8);
+        }
+    }
+
+    #[test]
+    fn cpu() {
+        let mut i8080 = I8080::new(0x400);
+        for (offset, value) in b"DMA".iter().enumerate() {
+            i8080.poke(0x200 + offset as u16, *value);
+        }
+        let fifo = Rc::new(RefCell::new(Fifo { requests: 3, ..Default::default() }));
+        let dma = Rc::new(RefCell::new(I8257::new()));
+        program(&mut dma.borrow_mut(), 1, 0x0200, READ | 2);
+        dma.borrow_mut().output(8, TC_STOP | 0x02);
+        dma.borrow_mut().connect(1, fifo.clone());
+        i8080.attach(0xF0..=0xF8, dma.clone());
+        i8080.attach_dma(dma.clone());
+        assert_eq!(i8080.step(), 12 + 4); // three transfers and a NOP
+        assert_eq!(fifo.borrow().output, b"DMA");
+        assert_eq!(fifo.borrow().terminal_counts, 1);
+        assert_eq!(dma.borrow_mut().input(8), 0x02);
+        assert_eq!(dma.borrow_mut().input(8), 0x00);
+        assert_eq!(dma.borrow().mode, TC_STOP);
+    }
+
+    #[test]
+    fn write_and_autoload() {
+        let mut memory = [0; 16];
+        let fifo = Rc::new(RefCell::new(Fifo { input: vec![1, 2, 3, 4], requests: 4, ..Default::default() }));
+        let mut dma = I8257::new();
+        dma.output(8, AUTOLOAD);
+        program(&mut dma, 2, 0x0004, WRITE | 1);
+        assert_eq!(dma.address, [0, 0, 4, 4]);
+        program(&mut dma, 3, 0x0008, WRITE | 1);
+        dma.output(8, AUTOLOAD | 0x04);
+        dma.connect(2, fifo.clone());
+        while dma.hold() {
+            assert_eq!(dma.transfer(&mut memory), 4);
+        }
+        assert_eq!(memory[4..10], [1, 2, 0, 0, 3, 4]);
+        assert_eq!(fifo.borrow().terminal_counts, 2);
+        assert_eq!(dma.input(8), UPDATE | 0x04);
+        assert_eq!(dma.input(4), 0x08);
+        assert_eq!(dma.input(4), 0x00);
+    }
+
+    #[test]
+    fn rotating_priority() {
+        let mut memory = [0; 4];
+        let fifos: Vec<_> = (0..2).map(|_| Rc::new(RefCell::new(Fifo { requests: 2, ..Default::default() }))).collect();
+        let mut dma = I8257::new();
+        for (channel, fifo) in fifos.iter().enumerate() {
+            program(&mut dma, channel as u8, 0, READ | 7);
+            dma.connect(channel, fifo.clone());
+        }
+        dma.output(8, ROTATING | 0x03);
+        let mut order = Vec::new();
+        while let Some(channel) = dma.channel() {
+            order.push(channel);
+            dma.transfer(&mut memory);
+        }
+        assert_eq!(order, [0, 1, 0, 1]);
+    }
+}
@@ -0,0 +1,492 @@
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+use crate::io::Device;
+use crate::state::{self, Field, Reader, Writer};
+
+pub type IrLine = Box<dyn Fn() -> bool>;
+
+// ICW1
+const ICW1: u8 = 0x10;
+const IC4: u8 = 0x01;
+const SNGL: u8 = 0x02;
+const ADI: u8 = 0x04;
+const LTIM: u8 = 0x08;
+
+// ICW4
+const AEOI: u8 = 0x02;
+const SFNM: u8 = 0x10;
+
+// OCW3
+const OCW3: u8 = 0x08;
+const RIS: u8 = 0x01;
+const RR: u8 = 0x02;
+const P: u8 = 0x04;
+const SMM: u8 = 0x20;
+const ESMM: u8 = 0x40;
+
+const CALL: u8 = 0xCD;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Reset,
+    Icw2,
+    Icw3,
+    Icw4,
+    Ready,
+}
+
+impl Field for State {
+    fn save(&self, state: &mut Writer) {
+        state.put(&(*self as u8));
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        match state.get::<u8>()? {
+            0 => Ok(State::Reset),
+            1 => Ok(State::Icw2),
+            2 => Ok(State::Icw3),
+            3 => Ok(State::Icw4),
+            4 => Ok(State::Ready),
+            value => Err(state::invalid(format!("8259 state {value} in save state"))),
+        }
+    }
+}
+
+// Intel 8259 programmable interrupt controller in 8080 mode. Port 0 is A0 low
+// and port 1 is A0 high. Acknowledging it takes three INTA cycles: a CALL and
+// the two bytes of the service routine address.
+pub struct I8259 {
+    state: State,
+    icw1: u8,
+    icw2: u8,
+    icw3: u8,
+    icw4: u8,
+    irr: u8,
+    isr: u8,
+    imr: u8,
+    levels: u8, // IR input levels
+    lowest: u8, // line with the lowest priority
+    rotate_aeoi: bool,
+    special_mask: bool,
+    read_isr: bool,
+    poll: bool,
+    inta_cycle: usize,
+    vector: u16,
+    cascade: Option<Rc<RefCell<I8259>>>, // slave supplying the address
+    slave: bool,
+    sources: [Option<IrLine>; 8],
+    slaves: [Option<Rc<RefCell<I8259>>>; 8],
+}
+
+impl I8259 {
+    pub fn new() -> Self {
+        Self {
+            state: State::Reset,
+            icw1: 0,
+            icw2: 0,
+            icw3: 0,
+            icw4: 0,
+            irr: 0,
+            isr: 0,
+            imr: 0,
+            levels: 0,
+            lowest: 7,
+            rotate_aeoi: false,
+            special_mask: false,
+            read_isr: false,
+            poll: false,
+            inta_cycle: 0,
+            vector: 0,
+            cascade: None,
+            slave: false,
+            sources: Default::default(),
+            slaves: Default::default(),
+        }
+    }
+
+    pub fn set_ir(&mut self, line: usize, level: bool) {
+        let mask = 1 << line;
+        if self.icw1 & LTIM != 0 {
+            self.irr = if level { self.irr | mask } else { self.irr & !mask };
+        } else if level && self.levels & mask == 0 {
+            self.irr |= mask;
+        }
+        self.levels = if level { self.levels | mask } else { self.levels & !mask };
+    }
+
+    // Drives IR `line` from `source`, sampled every T-state, e.g. a USART's RxRDY.
+    pub fn connect(&mut self, line: usize, source: IrLine) {
+        self.sources[line] = Some(source);
+    }
+
+    // Wires the INT output of `slave` to IR `line`. The slave supplies the
+    // address when that line is acknowledged and no longer drives INTR itself.
+    pub fn cascade(&mut self, line: usize, slave: Rc<RefCell<I8259>>) {
+        slave.borrow_mut().slave = true;
+        self.slaves[line] = Some(slave);
+    }
+
+    // Level of the INT output.
+    pub fn int(&self) -> bool {
+        self.state == State::Ready && self.resolve().is_some()
+    }
+
+    fn priority(&self) -> impl Iterator<Item = u8> {
+        let highest = self.lowest + 1;
+        (0..8).map(move |line| (highest + line) % 8)
+    }
+
+    fn resolve(&self) -> Option<u8> {
+        let requests = self.irr & !self.imr;
+        for line in self.priority() {
+            let mask = 1 << line;
+            if self.isr & mask != 0 && !self.special_mask {
+                // In special fully nested mode a slave can interrupt its own service routine.
+                let nested = self.icw4 & SFNM != 0 && !self.slave && self.icw3 & mask != 0;
+                return (nested && requests & mask != 0).then_some(line);
+            }
+            if requests & mask != 0 && self.isr & mask == 0 {
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    // Moves the highest priority request into service, IR7 if there is none.
+    fn acknowledge(&mut self) -> u8 {
+        let line = self.resolve().unwrap_or(7);
+        let mask = 1 << line;
+        self.isr |= mask;
+        if self.icw1 & LTIM == 0 {
+            self.irr &= !mask;
+        }
+        line
+    }
+
+    fn eoi(&mut self, line: Option<u8>, rotate: bool) {
+        let line = match line {
+            Some(line) => line,
+            None => match self.priority().find(|line| self.isr & (1 << line) != 0) {
+                Some(line) => line,
+                None => return,
+            },
+        };
+        self.isr &= !(1 << line);
+        if rotate {
+            self.lowest = line;
+        }
+    }
+
+    fn address(&self, line: u8) -> u16 {
+        let low = if self.icw1 & ADI != 0 { (self.icw1 & 0xE0) | line << 2 } else { (self.icw1 & 0xC0) | line << 3 };
+        u16::from_le_bytes([low, self.icw2])
+    }
+
+    fn command(&mut self, value: u8) {
+        if value & ICW1 != 0 {
+            self.icw1 = value;
+            if value & IC4 == 0 {
+                self.icw4 = 0;
+            }
+            self.state = State::Icw2;
+            self.irr = 0;
+            self.isr = 0;
+            self.imr = 0;
+            self.lowest = 7;
+            self.rotate_aeoi = false;
+            self.special_mask = false;
+            self.read_isr = false;
+            self.poll = false;
+            self.inta_cycle = 0;
+        } else if value & OCW3 != 0 {
+            if value & ESMM != 0 {
+                self.special_mask = value & SMM != 0;
+            }
+            if value & RR != 0 {
+                self.read_isr = value & RIS != 0;
+            }
+            self.poll = value & P != 0;
+        } else {
+            let line = value & 0x07;
+            match value >> 5 {
+                1 => self.eoi(None, false),
+                3 => self.eoi(Some(line), false),
+                5 => self.eoi(None, true),
+                7 => self.eoi(Some(line), true),
+                4 => self.rotate_aeoi = true,
+                0 => self.rotate_aeoi = false,
+                6 => self.lowest = line,
+                _ => {}
+            }
+        }
+    }
+
+    fn data(&mut self, value: u8) {
+        self.state = match self.state {
+            State::Icw2 => {
+                self.icw2 = value;
+                if self.icw1 & SNGL == 0 {
+                    State::Icw3
+                } else if self.icw1 & IC4 != 0 {
+                    State::Icw4
+                } else {
+                    State::Ready
+                }
+            }
+            State::Icw3 => {
+                self.icw3 = value;
+                if self.icw1 & IC4 != 0 { State::Icw4 } else { State::Ready }
+            }
+            State::Icw4 => {
+                self.icw4 = value;
+                State::Ready
+            }
+            state => {
+                self.imr = value;
+                state
+            }
+        };
+    }
+}
+
+impl Default for I8259 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for I8259 {
+    fn input(&mut self, port: u8) -> u8 {
+        if port & 0x01 != 0 {
+            return self.imr;
+        }
+        if self.poll {
+            self.poll = false;
+            return if self.int() { 0x80 | self.acknowledge() } else { 0x00 };
+        }
+        if self.read_isr { self.isr } else { self.irr }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port & 0x01 == 0 {
+            self.command(value);
+        } else {
+            self.data(value);
+        }
+    }
+
+    fn clock(&mut self, _states: usize) {
+        for line in 0..8 {
+            let level = match (&self.sources[line], &self.slaves[line]) {
+                (Some(source), _) => source(),
+                (None, Some(slave)) => slave.borrow().int(),
+                (None, None) => continue,
+            };
+            self.set_ir(line, level);
+        }
+    }
+
+    fn intr(&self) -> bool {
+        !self.slave && self.int()
+    }
+
+    fn inta(&mut self) -> u8 {
+        self.inta_cycle += 1;
+        match self.inta_cycle {
+            1 => {
+                let line = self.acknowledge();
+                self.vector = self.address(line);
+                self.cascade = match &self.slaves[line as usize] {
+                    Some(slave) if self.icw1 & SNGL == 0 && self.icw3 & (1 << line) != 0 => Some(slave.clone()),
+                    _ => None,
+                };
+                if let Some(slave) = &self.cascade {
+                    slave.borrow_mut().inta();
+                }
+                CALL
+            }
+            cycle => {
+                let value = match &self.cascade {
+                    Some(slave) => slave.borrow_mut().inta(),
+                    None => self.vector.to_le_bytes()[cycle - 2],
+                };
+                if cycle == 3 {
+                    self.inta_cycle = 0;
+                    if self.icw4 & AEOI != 0 {
+                        self.eoi(None, self.rotate_aeoi);
+                    }
+                }
+                value
+            }
+        }
+    }
+
+    // Cascaded slaves are saved with their master as they need not be attached.
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.state);
+        state.put(&self.icw1);
+        state.put(&self.icw2);
+        state.put(&self.icw3);
+        state.put(&self.icw4);
+        state.put(&self.irr);
+        state.put(&self.isr);
+        state.put(&self.imr);
+        state.put(&self.levels);
+        state.put(&self.lowest);
+        state.put(&self.rotate_aeoi);
+        state.put(&self.special_mask);
+        state.put(&self.read_isr);
+        state.put(&self.poll);
+        state.put(&self.inta_cycle);
+        state.put(&self.vector);
+        let cascade = self.cascade.as_ref().and_then(|cascade| {
+            self.slaves.iter().position(|slave| slave.as_ref().is_some_and(|slave| Rc::ptr_eq(slave, cascade)))
+        });
+        state.put(&cascade);
+        for slave in self.slaves.iter().flatten() {
+            slave.borrow().save(state);
+        }
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.state = state.get()?;
+        self.icw1 = state.get()?;
+        self.icw2 = state.get()?;
+        self.icw3 = state.get()?;
+        self.icw4 = state.get()?;
+        self.irr = state.get()?;
+        self.isr = state.get()?;
+        self.imr = state.get()?;
+        self.levels = state.get()?;
+        self.lowest = state.get()?;
+        self.rotate_aeoi = state.get()?;
+        self.special_mask = state.get()?;
+        self.read_isr = state.get()?;
+        self.poll = state.get()?;
+        self.inta_cycle = state.get()?;
+        self.vector = state.get()?;
+        let cascade: Option<usize> = state.get()?;
+        self.cascade = cascade.and_then(|line| self.slaves.get(line).cloned().flatten());
+        for slave in self.slaves.iter().flatten() {
+            slave.borrow_mut().load(state)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::I8080;
+
+    fn pic() -> I8259 {
+        let mut pic = I8259::new();
+        pic.output(0, 0x16); // single, interval 4, address 0000h
+        pic.output(1, 0x20); // routines at 2000h
+        pic
+    }
+
+    fn acknowledge(pic: &mut I8259) -> [u8; 3] {
+        [pic.inta(), pic.inta(), pic.inta()]
+    }
+
+    #[test]
+    fn call() {
+        let mut pic = pic();
+        assert!(!pic.intr());
+        pic.set_ir(3, true);
+        assert!(pic.intr());
+        assert_eq!(pic.input(0), 0x08);
+        assert_eq!(acknowledge(&mut pic), [0xCD, 0x0C, 0x20]);
+        assert!(!pic.intr());
+        pic.output(0, 0x0B); // read ISR
+        assert_eq!(pic.input(0), 0x08);
+        pic.output(0, 0x20); // non-specific EOI
+        assert_eq!(pic.input(0), 0x00);
+    }
+
+    #[test]
+    fn priority() {
+        let mut pic = pic();
+        pic.output(1, 0x04); // mask IR2
+        pic.set_ir(2, true);
+        pic.set_ir(5, true);
+        assert_eq!(acknowledge(&mut pic)[1], 0x14);
+        pic.set_ir(6, true);
+        assert!(!pic.intr());
+        pic.set_ir(1, true);
+        assert_eq!(acknowledge(&mut pic)[1], 0x04);
+        pic.output(0, 0x65); // specific EOI IR5
+        pic.output(0, 0x20);
+        assert_eq!(acknowledge(&mut pic)[1], 0x18);
+        pic.output(0, 0xA0); // rotate on non-specific EOI: IR6 lowest
+        pic.output(1, 0x00);
+        pic.set_ir(0, false);
+        pic.set_ir(0, true);
+        pic.set_ir(7, true);
+        assert_eq!(acknowledge(&mut pic)[1], 0x1C);
+    }
+
+    #[test]
+    fn automatic_eoi_and_poll() {
+        let mut pic = I8259::new();
+        pic.output(0, 0x1B); // level triggered, single, ICW4
+        pic.output(1, 0x00);
+        pic.output(1, 0x02); // AEOI
+        pic.set_ir(4, true);
+        acknowledge(&mut pic);
+        pic.output(0, 0x0B);
+        assert_eq!(pic.input(0), 0x00);
+        assert!(pic.intr());
+        pic.output(0, 0x0C); // poll
+        assert_eq!(pic.input(0), 0x84);
+        pic.set_ir(4, false);
+        pic.output(0, 0x20);
+        pic.output(0, 0x0C);
+        assert_eq!(pic.input(0), 0x00);
+    }
+
+    #[test]
+    fn cascade() {
+        let slave = Rc::new(RefCell::new(I8259::new()));
+        let mut master = I8259::new();
+        master.output(0, 0x14); // cascaded, interval 4
+        master.output(1, 0x20);
+        master.output(1, 0x80); // slave on IR7
+        slave.borrow_mut().output(0, 0x94); // cascaded, interval 4, address 0080h
+        slave.borrow_mut().output(1, 0x30);
+        slave.borrow_mut().output(1, 0x07); // slave ID 7
+        master.cascade(7, slave.clone());
+        slave.borrow_mut().set_ir(1, true);
+        assert!(!slave.borrow().intr());
+        master.clock(1);
+        assert!(master.intr());
+        assert_eq!(acknowledge(&mut master), [0xCD, 0x84, 0x30]);
+        assert_eq!(master.isr, 0x80);
+        assert_eq!(slave.borrow().isr, 0x02);
+    }
+
+    #[test]
+    fn cpu() {
+        // LXI SP,0200h; EI; HLT
+        let mut i8080 = I8080::new(0x400);
+        for (location, value) in [0x31, 0x00, 0x02, 0xFB, 0x76].into_iter().enumerate() {
+            i8080.poke(location as u16, value);
+        }
+        let pic = Rc::new(RefCell::new(I8259::new()));
+        pic.borrow_mut().output(0, 0x56); // single, interval 4, address 0040h
+        pic.borrow_mut().output(1, 0x00);
+        i8080.attach(0x20..=0x21, pic.clone());
+        for _ in 0..3 {
+            i8080.step();
+        }
+        assert!(i8080.halted());
+        pic.borrow_mut().set_ir(3, true);
+        assert_eq!(i8080.step(), 17);
+        assert_eq!(i8080.pc(), 0x004C);
+        assert_eq!([i8080.peek(0x1FE), i8080.peek(0x1FF)], [0x05, 0x00]);
+        assert_eq!(pic.borrow().isr, 0x08);
+    }
+}
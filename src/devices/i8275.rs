@@ -0,0 +1,751 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::devices::i8257::Peripheral;
+use crate::io::Device;
+use crate::state::{self, Field, Reader, Writer};
+
+// Status register
+pub const FO: u8 = 0x01;
+pub const DU: u8 = 0x02;
+pub const VE: u8 = 0x04;
+pub const IC: u8 = 0x08;
+pub const LP: u8 = 0x10;
+pub const IR: u8 = 0x20;
+pub const IE: u8 = 0x40;
+
+// Field attribute code 10UR GGBH
+pub const HIGHLIGHT: u8 = 0x01;
+pub const BLINK: u8 = 0x02;
+pub const GPA: u8 = 0x0C;
+pub const REVERSE: u8 = 0x10;
+pub const UNDERLINE: u8 = 0x20;
+
+// Special control characters
+const END_OF_ROW: u8 = 0xF0;
+const END_OF_ROW_STOP_DMA: u8 = 0xF1;
+const END_OF_SCREEN: u8 = 0xF2;
+const END_OF_SCREEN_STOP_DMA: u8 = 0xF3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Command {
+    Reset,
+    LoadCursor,
+    ReadLightPen,
+}
+
+impl Field for Command {
+    fn save(&self, state: &mut Writer) {
+        state.put(&(*self as u8));
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        match state.get::<u8>()? {
+            0 => Ok(Command::Reset),
+            1 => Ok(Command::LoadCursor),
+            2 => Ok(Command::ReadLightPen),
+            value => Err(state::invalid(format!("8275 command {value} in save state"))),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Cell {
+    pub code: u8, // character, or a character attribute code from C0h
+    pub attributes: u8,
+}
+
+impl Field for Cell {
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.code);
+        state.put(&self.attributes);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(Self { code: state.get()?, attributes: state.get()? })
+    }
+}
+
+// One displayed frame.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Screen {
+    pub columns: usize,
+    pub rows: usize,
+    pub lines: usize, // scan lines per character row
+    pub cells: Vec<Cell>,
+    pub cursor: Option<(usize, usize)>, // column, row
+    pub cursor_format: u8,
+    pub underline: usize, // scan line of the underline
+    pub offset_lines: bool, // line counter mode 1
+    pub frame: u64,
+}
+
+impl Field for Screen {
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.columns);
+        state.put(&self.rows);
+        state.put(&self.lines);
+        state.put(&self.cells);
+        state.put(&self.cursor);
+        state.put(&self.cursor_format);
+        state.put(&self.underline);
+        state.put(&self.offset_lines);
+        state.put(&self.frame);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(Self {
+            columns: state.get()?,
+            rows: state.get()?,
+            lines: state.get()?,
+            cells: state.get()?,
+            cursor: state.get()?,
+            cursor_format: state.get()?,
+            underline: state.get()?,
+            offset_lines: state.get()?,
+            frame: state.get()?,
+        })
+    }
+}
+
+impl Screen {
+    pub fn cell(&self, column: usize, row: usize) -> Cell {
+        self.cells[row * self.columns + column]
+    }
+
+    // Renders the frame as an 8-bit grayscale PNG. `rom` holds 16 lines of 8
+    // dots per character, MSB first, addressed by character code and line count.
+    pub fn write_png<W: Write>(&self, rom: &[u8], writer: W) -> io::Result<()> {
+        let width = self.columns * 8;
+        let height = self.rows * self.lines;
+        let mut pixels = vec![0; width * height];
+        let blink_characters = self.frame & 0x10 != 0;
+        let blink_cursor = self.frame & 0x08 != 0;
+        for row in 0..self.rows {
+            for column in 0..self.columns {
+                let cell = self.cell(column, row);
+                let cursor = self.cursor == Some((column, row));
+                for line in 0..self.lines {
+                    let count = if self.offset_lines { (line + self.lines - 1) % self.lines } else { line };
+                    let mut dots = match cell.code {
+                        0x00..=0x7F => rom.get((cell.code as usize) << 4 | count).copied().unwrap_or(0),
+                        _ => 0,
+                    };
+                    if cell.attributes & BLINK != 0 && blink_characters {
+                        dots = 0;
+                    }
+                    if cell.attributes & UNDERLINE != 0 && line == self.underline {
+                        dots = 0xFF;
+                    }
+                    if cell.attributes & REVERSE != 0 {
+                        dots = !dots;
+                    }
+                    if cursor {
+                        let blinking = self.cursor_format & 0x02 == 0;
+                        let underline = self.cursor_format & 0x01 != 0;
+                        if !(blinking && blink_cursor) && (!underline || line == self.underline) {
+                            dots = !dots;
+                        }
+                    }
+                    let level = if cell.attributes & HIGHLIGHT != 0 { 0xFF } else { 0xAA };
+                    let start = (row * self.lines + line) * width + column * 8;
+                    for (dot, pixel) in pixels[start..start + 8].iter_mut().enumerate() {
+                        *pixel = if dots & (0x80 >> dot) != 0 { level } else { 0x00 };
+                    }
+                }
+            }
+        }
+        png::write(writer, width, height, &pixels)
+    }
+}
+
+impl fmt::Display for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for row in self.cells.chunks(self.columns.max(1)) {
+            let line: String = row
+                .iter()
+                .map(|cell| match cell.code {
+                    0x20..=0x7E => cell.code as char,
+                    _ => ' ',
+                })
+                .collect();
+            writeln!(f, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+// Intel 8275 programmable CRT controller. Port 0 is the parameter register and
+// port 1 the command/status register. Character rows are fetched through DMA
+// into a row buffer while the previous row is displayed, and each completed
+// frame is published as a `Screen`.
+pub struct I8275 {
+    cpu_hz: u64,
+    char_hz: u64,
+    phase: u64,
+    status: u8,
+    command: Option<Command>,
+    parameters: Vec<u8>,
+    reset: [u8; 4],
+    cursor: (u8, u8),
+    light_pen: (u8, u8),
+    burst_count: usize,
+    burst_space: usize,
+    interrupt: Option<u8>,
+    // Timing
+    column: usize, // character clock within the row, including horizontal retrace
+    line: usize,
+    row: usize, // including vertical retrace rows
+    // DMA
+    fill: Vec<u8>,
+    fetching: bool,
+    screen_stopped: bool,
+    burst: usize,
+    space: usize,
+    // Display
+    attributes: u8,
+    end_of_screen: bool,
+    composing: Screen,
+    screen: Screen,
+    frames: u64,
+}
+
+impl I8275 {
+    // `char_hz` is the character clock, the dot clock divided by the character width.
+    pub fn new(cpu_hz: u32, char_hz: u32) -> Self {
+        Self {
+            cpu_hz: cpu_hz.max(1) as u64,
+            char_hz: char_hz as u64,
+            phase: 0,
+            status: 0,
+            command: None,
+            parameters: Vec::new(),
+            reset: [0x4F, 0x18, 0x99, 0xDD], // 80x25, 10 lines
+            cursor: (0, 0),
+            light_pen: (0, 0),
+            burst_count: 1,
+            burst_space: 0,
+            interrupt: None,
+            column: 0,
+            line: 0,
+            row: 0,
+            fill: Vec::new(),
+            fetching: false,
+            screen_stopped: false,
+            burst: 0,
+            space: 0,
+            attributes: 0,
+            end_of_screen: false,
+            composing: Screen::default(),
+            screen: Screen::default(),
+            frames: 0,
+        }
+    }
+
+    // Raises INTR with IR while interrupts are enabled.
+    pub fn set_interrupt(&mut self, instruction: Option<u8>) {
+        self.interrupt = instruction;
+    }
+
+    // Pulses LPEN at a character position.
+    pub fn light_pen(&mut self, column: u8, row: u8) {
+        self.light_pen = (column, row);
+        self.status |= LP;
+    }
+
+    // The last completed frame.
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn hrtc(&self) -> bool {
+        self.column >= self.columns()
+    }
+
+    pub fn vrtc(&self) -> bool {
+        self.row >= self.rows()
+    }
+
+    fn columns(&self) -> usize {
+        (self.reset[0] & 0x7F) as usize + 1
+    }
+
+    fn rows(&self) -> usize {
+        (self.reset[1] & 0x3F) as usize + 1
+    }
+
+    fn retrace_rows(&self) -> usize {
+        (self.reset[1] >> 6) as usize + 1
+    }
+
+    fn lines(&self) -> usize {
+        (self.reset[2] & 0x0F) as usize + 1
+    }
+
+    fn transparent(&self) -> bool {
+        self.reset[3] & 0x40 == 0
+    }
+
+    fn row_clocks(&self) -> usize {
+        self.columns() + ((self.reset[3] & 0x0F) as usize + 1) * 2
+    }
+
+    fn field_attribute(code: u8) -> bool {
+        code & 0xC0 == 0x80
+    }
+
+    // Row buffer positions filled so far; transparent field attributes take none.
+    fn positions(&self) -> usize {
+        let transparent = self.transparent();
+        self.fill.iter().filter(|&&code| !(transparent && Self::field_attribute(code))).count()
+    }
+
+    fn start_fill(&mut self) {
+        self.fill.clear();
+        self.fetching = self.status & VE != 0 && !self.screen_stopped;
+        self.burst = self.burst_count;
+        self.space = 0;
+    }
+
+    fn preset(&mut self) {
+        // The first row is fetched during the last vertical retrace row.
+        self.row = self.rows() + self.retrace_rows() - 1;
+        self.line = 0;
+        self.column = 0;
+        self.screen_stopped = false;
+        self.start_fill();
+    }
+
+    fn compose_row(&mut self, row: usize) {
+        let columns = self.columns();
+        if self.fetching && self.positions() < columns {
+            self.status |= DU;
+        }
+        let transparent = self.transparent();
+        let mut codes = std::mem::take(&mut self.fill).into_iter();
+        let mut end_of_row = self.end_of_screen;
+        for column in 0..columns {
+            let mut cell = Cell::default();
+            while !end_of_row {
+                let Some(code) = codes.next() else {
+                    end_of_row = true;
+                    break;
+                };
+                match code {
+                    END_OF_ROW | END_OF_ROW_STOP_DMA => end_of_row = true,
+                    END_OF_SCREEN | END_OF_SCREEN_STOP_DMA => {
+                        end_of_row = true;
+                        self.end_of_screen = true;
+                    }
+                    _ if Self::field_attribute(code) => {
+                        self.attributes = code & 0x3F;
+                        if transparent {
+                            continue;
+                        }
+                    }
+                    _ => cell.code = code,
+                }
+                break;
+            }
+            cell.attributes = self.attributes;
+            self.composing.cells[row * columns + column] = cell;
+        }
+    }
+
+    fn next_row(&mut self) {
+        let rows = self.rows();
+        self.row += 1;
+        if self.row == rows + self.retrace_rows() {
+            self.row = 0;
+        }
+        if self.row == 0 {
+            self.composing = Screen {
+                columns: self.columns(),
+                rows,
+                lines: self.lines(),
+                cells: vec![Cell::default(); self.columns() * rows],
+                cursor: None,
+                cursor_format: (self.reset[3] >> 4) & 0x03,
+                underline: (self.reset[2] >> 4) as usize,
+                offset_lines: self.reset[3] & 0x80 != 0,
+                frame: self.frames,
+            };
+            self.attributes = 0;
+            self.end_of_screen = false;
+        }
+        if self.row < rows {
+            self.compose_row(self.row);
+            if self.row == rows - 1 {
+                self.status |= IR;
+                self.fetching = false;
+            } else {
+                self.start_fill();
+            }
+        } else if self.row == rows {
+            let (column, row) = (self.cursor.0 as usize, self.cursor.1 as usize);
+            if column < self.composing.columns && row < rows {
+                self.composing.cursor = Some((column, row));
+            }
+            self.screen = std::mem::take(&mut self.composing);
+            self.frames += 1;
+        }
+        if self.row == rows + self.retrace_rows() - 1 {
+            self.screen_stopped = false;
+            self.start_fill();
+        }
+    }
+
+    fn character_clock(&mut self) {
+        self.space = self.space.saturating_sub(1);
+        self.column += 1;
+        if self.column < self.row_clocks() {
+            return;
+        }
+        self.column = 0;
+        self.line += 1;
+        if self.line == self.lines() {
+            self.line = 0;
+            self.next_row();
+        }
+    }
+
+    fn command(&mut self, value: u8) {
+        self.parameters.clear();
+        self.command = None;
+        match value >> 5 {
+            0 => {
+                self.command = Some(Command::Reset);
+                self.status &= !(VE | IE);
+            }
+            1 => {
+                self.burst_count = 1 << (value & 0x03);
+                self.burst_space = match (value >> 2) & 0x07 {
+                    0 => 0,
+                    space => space as usize * 8 - 1,
+                };
+                self.status |= VE | IE;
+                self.preset();
+            }
+            2 => self.status &= !VE,
+            3 => self.command = Some(Command::ReadLightPen),
+            4 => self.command = Some(Command::LoadCursor),
+            5 => self.status |= IE,
+            6 => self.status &= !IE,
+            _ => self.preset(),
+        }
+    }
+
+    fn parameter(&mut self, value: u8) {
+        self.parameters.push(value);
+        match self.command {
+            Some(Command::Reset) if self.parameters.len() == 4 => {
+                self.reset.copy_from_slice(&self.parameters);
+                self.command = None;
+            }
+            Some(Command::LoadCursor) if self.parameters.len() == 2 => {
+                self.cursor = (self.parameters[0], self.parameters[1]);
+                self.command = None;
+            }
+            Some(Command::Reset) | Some(Command::LoadCursor) => {}
+            _ => self.status |= IC,
+        }
+    }
+}
+
+impl Device for I8275 {
+    fn input(&mut self, port: u8) -> u8 {
+        if port & 0x01 != 0 {
+            let status = self.status;
+            self.status &= VE | IE;
+            return status;
+        }
+        if self.command != Some(Command::ReadLightPen) {
+            self.status |= IC;
+            return 0x00;
+        }
+        self.parameters.push(0);
+        if self.parameters.len() == 1 {
+            return self.light_pen.0;
+        }
+        self.command = None;
+        self.light_pen.1
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port & 0x01 != 0 {
+            self.command(value);
+        } else {
+            self.parameter(value);
+        }
+    }
+
+    fn clock(&mut self, states: usize) {
+        self.phase += states as u64 * self.char_hz;
+        while self.phase >= self.cpu_hz {
+            self.phase -= self.cpu_hz;
+            self.character_clock();
+        }
+    }
+
+    fn intr(&self) -> bool {
+        self.interrupt.is_some() && self.status & (IE | IR) == IE | IR
+    }
+
+    fn inta(&mut self) -> u8 {
+        self.interrupt.unwrap_or(0xFF)
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.phase);
+        state.put(&self.status);
+        state.put(&self.command);
+        state.put(&self.parameters);
+        state.put(&self.reset);
+        state.put(&self.cursor);
+        state.put(&self.light_pen);
+        state.put(&self.burst_count);
+        state.put(&self.burst_space);
+        state.put(&self.column);
+        state.put(&self.line);
+        state.put(&self.row);
+        state.put(&self.fill);
+        state.put(&self.fetching);
+        state.put(&self.screen_stopped);
+        state.put(&self.burst);
+        state.put(&self.space);
+        state.put(&self.attributes);
+        state.put(&self.end_of_screen);
+        state.put(&self.composing);
+        state.put(&self.screen);
+        state.put(&self.frames);
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.phase = state.get()?;
+        self.status = state.get()?;
+        self.command = state.get()?;
+        self.parameters = state.get()?;
+        self.reset = state.get()?;
+        self.cursor = state.get()?;
+        self.light_pen = state.get()?;
+        self.burst_count = state.get()?;
+        self.burst_space = state.get()?;
+        self.column = state.get()?;
+        self.line = state.get()?;
+        self.row = state.get()?;
+        self.fill = state.get()?;
+        self.fetching = state.get()?;
+        self.screen_stopped = state.get()?;
+        self.burst = state.get()?;
+        self.space = state.get()?;
+        self.attributes = state.get()?;
+        self.end_of_screen = state.get()?;
+        self.composing = state.get()?;
+        self.screen = state.get()?;
+        self.frames = state.get()?;
+        Ok(())
+    }
+}
+
+impl Peripheral for I8275 {
+    fn dreq(&self) -> bool {
+        self.fetching && self.space == 0 && self.positions() < self.columns()
+    }
+
+    fn read(&mut self) -> u8 {
+        0xFF
+    }
+
+    fn write(&mut self, value: u8) {
+        if self.positions() >= self.columns() {
+            self.status |= FO;
+            return;
+        }
+        self.fill.push(value);
+        match value {
+            END_OF_ROW_STOP_DMA => self.fetching = false,
+            END_OF_SCREEN_STOP_DMA => {
+                self.fetching = false;
+                self.screen_stopped = true;
+            }
+            _ => {}
+        }
+        self.burst -= 1;
+        if self.burst == 0 {
+            self.burst = self.burst_count;
+            self.space = self.burst_space;
+        }
+    }
+}
+
+// Just enough PNG to write uncompressed grayscale images.
+mod png {
+    use std::io::{self, Write};
+
+    use crate::state::crc32;
+
+    fn adler32(bytes: &[u8]) -> u32 {
+        let (mut a, mut b) = (1u32, 0u32);
+        for &byte in bytes {
+            a = (a + byte as u32) % 65521;
+            b = (b + a) % 65521;
+        }
+        b << 16 | a
+    }
+
+    fn chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+        let mut body = kind.to_vec();
+        body.extend_from_slice(data);
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(&body)?;
+        writer.write_all(&crc32(&body).to_be_bytes())
+    }
+
+    pub fn write<W: Write>(mut writer: W, width: usize, height: usize, pixels: &[u8]) -> io::Result<()> {
+        writer.write_all(b"\x89PNG\r\n\x1A\n")?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&(width as u32).to_be_bytes());
+        header.extend_from_slice(&(height as u32).to_be_bytes());
+        header.extend_from_slice(&[8, 0, 0, 0, 0]); // 8-bit grayscale
+        chunk(&mut writer, b"IHDR", &header)?;
+        let mut scanlines = Vec::with_capacity((width + 1) * height);
+        for line in pixels.chunks(width.max(1)) {
+            scanlines.push(0); // no filter
+            scanlines.extend_from_slice(line);
+        }
+        let mut data = vec![0x78, 0x01];
+        let mut blocks = scanlines.chunks(0xFFFF).peekable();
+        if blocks.peek().is_none() {
+            data.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+        }
+        while let Some(block) = blocks.next() {
+            let length = block.len() as u16;
+            data.push(blocks.peek().is_none() as u8);
+            data.extend_from_slice(&length.to_le_bytes());
+            data.extend_from_slice(&(!length).to_le_bytes());
+            data.extend_from_slice(block);
+        }
+        data.extend_from_slice(&adler32(&scanlines).to_be_bytes());
+        chunk(&mut writer, b"IDAT", &data)?;
+        chunk(&mut writer, b"IEND", &[])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        #[test]
+        fn checksums() {
+            assert_eq!(super::crc32(b"IEND"), 0xAE42_6082);
+            assert_eq!(super::adler32(b"Wikipedia"), 0x11E6_0398);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::i8257::I8257;
+    use crate::I8080;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // 10 columns, 3 rows, 1 retrace row, 2 lines per row, non-transparent attributes:
+    // 12 character clocks per line and 96 per frame.
+    fn crtc(cpu_hz: u32) -> I8275 {
+        let mut crtc = I8275::new(cpu_hz, 1);
+        crtc.output(1, 0x00);
+        for parameter in [0x09, 0x02, 0x11, 0x40] {
+            crtc.output(0, parameter);
+        }
+        crtc.output(1, 0x80);
+        crtc.output(0, 2);
+        crtc.output(0, 1);
+        crtc
+    }
+
+    fn run(crtc: &mut I8275, memory: &[u8], address: &mut usize, clocks: usize) {
+        for _ in 0..clocks {
+            while crtc.dreq() {
+                crtc.write(memory[*address % memory.len()]);
+                *address += 1;
+            }
+            crtc.clock(1);
+        }
+    }
+
+    #[test]
+    fn screen() {
+        let mut crtc = crtc(1);
+        crtc.set_interrupt(Some(0xFF));
+        crtc.output(1, 0x23); // start display, bursts of 8
+        let mut memory = b"HELLO\xF0....WORLD \x91AB ".to_vec();
+        memory.extend_from_slice(b"SCREEN\xF2...");
+        let mut address = 0;
+        run(&mut crtc, &memory, &mut address, 24);
+        assert_eq!(address, 10);
+        run(&mut crtc, &memory, &mut address, 24 * 2);
+        assert!(crtc.intr());
+        assert_eq!(crtc.input(1) & (IR | VE | DU), IR | VE);
+        assert!(!crtc.intr());
+        run(&mut crtc, &memory, &mut address, 24);
+        assert_eq!(crtc.frames(), 1);
+        let screen = crtc.screen();
+        assert_eq!(screen.to_string(), "HELLO\nWORLD  AB\nSCREEN\n");
+        assert_eq!(screen.cell(6, 1), Cell { code: 0, attributes: 0x11 });
+        assert_eq!(screen.cell(0, 2).attributes, 0x11);
+        assert_eq!(screen.cursor, Some((2, 1)));
+        assert!(crtc.vrtc() && !crtc.hrtc());
+        // A CPU clock of 0 runs as 1 Hz.
+        let mut slow = self::crtc(0);
+        slow.output(1, 0x23);
+        let mut address = 0;
+        run(&mut slow, &memory, &mut address, 24 * 4);
+        assert_eq!(slow.frames(), 1);
+    }
+
+    #[test]
+    fn png() {
+        let mut crtc = crtc(1);
+        crtc.output(1, 0x20);
+        let mut address = 0;
+        run(&mut crtc, b"AB", &mut address, 96);
+        let mut rom = vec![0; 0x800];
+        rom[0x41 << 4] = 0x81;
+        let mut png = Vec::new();
+        crtc.screen().write_png(&rom, &mut png).unwrap();
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1A\n");
+        assert_eq!(png[16..24], [0, 0, 0, 80, 0, 0, 0, 6]);
+        // Stored deflate block after the zlib header; each scanline starts with a filter byte.
+        let scanlines = &png[33 + 8 + 2 + 5..];
+        assert_eq!(scanlines[..10], [0, 0xAA, 0, 0, 0, 0, 0, 0, 0xAA, 0]);
+    }
+
+    #[test]
+    fn dma() {
+        let mut i8080 = I8080::new(0x400);
+        for (offset, value) in b"0123456789abcdefghijABCDEFGHIJ".iter().enumerate() {
+            i8080.poke(0x200 + offset as u16, *value);
+        }
+        let crtc = Rc::new(RefCell::new(crtc(8)));
+        crtc.borrow_mut().output(1, 0x27); // start display, bursts of 8, 7 clocks apart
+        let dma = Rc::new(RefCell::new(I8257::new()));
+        dma.borrow_mut().output(8, 0x80);
+        for (port, value) in [(4, 0x00), (4, 0x02), (5, 29), (5, 0x80)] {
+            dma.borrow_mut().output(port, value);
+        }
+        dma.borrow_mut().output(8, 0x84); // autoload, channel 2
+        dma.borrow_mut().connect(2, crtc.clone());
+        i8080.attach(0xD0..=0xD1, crtc.clone());
+        i8080.attach(0xE0..=0xE8, dma.clone());
+        i8080.attach_dma(dma);
+        while crtc.borrow().frames() < 2 {
+            i8080.step();
+        }
+        let crtc = crtc.borrow();
+        assert_eq!(crtc.screen().to_string(), "0123456789\nabcdefghij\nABCDEFGHIJ\n");
+        assert_eq!(crtc.status & DU, 0);
+    }
+}
@@ -0,0 +1,12 @@
+pub mod cassette;
+pub mod clock;
+pub mod dcdd;
+pub mod endpoint;
+pub mod i8251;
+pub mod i8253;
+pub mod i8255;
+pub mod i8257;
+pub mod i8259;
+pub mod i8275;
+pub mod printer;
+pub mod tape;
@@ -0,0 +1,109 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::devices::tape::Status;
+use crate::io::Device;
+use crate::state::{Reader, Writer};
+
+// Line printer on a status/data port pair. Parity is stripped and the NUL and
+// DEL fill characters old software sends after a carriage return are dropped.
+pub struct LinePrinter<W: Write> {
+    writer: W,
+    status: Status,
+    online: bool,
+    lines: usize,
+    error: Option<io::Error>,
+}
+
+impl LinePrinter<BufWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> LinePrinter<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, status: Status::default(), online: true, lines: 0, error: None }
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    // An offline printer is never ready, as when it is out of paper.
+    pub fn set_online(&mut self, online: bool) {
+        self.online = online;
+    }
+
+    // Lines printed so far, counting form feeds as one.
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn print(&mut self, value: u8) -> io::Result<()> {
+        if value == 0x00 || value == 0x7F {
+            return Ok(());
+        }
+        self.writer.write_all(&[value])?;
+        // Flush finished lines so the file can be followed while the program runs.
+        if value == b'\n' || value == 0x0C {
+            self.lines += 1;
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Device for LinePrinter<W> {
+    fn input(&mut self, port: u8) -> u8 {
+        if port & 0x01 == 0 {
+            return self.status.encode(false, self.online && self.error.is_none());
+        }
+        0xFF
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port & 0x01 == 0 || !self.online || self.error.is_some() {
+            return;
+        }
+        if let Err(error) = self.print(value & 0x7F) {
+            self.error = Some(error);
+        }
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.lines);
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.lines = state.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn print() {
+        let mut printer = LinePrinter::new(Vec::new());
+        for &value in b"READY\r\n\x00\x00\x7FOK\xAE\r\n\x0C" {
+            printer.output(1, value);
+        }
+        printer.set_online(false);
+        assert_eq!(printer.input(0), 0x00);
+        printer.output(1, b'X');
+        assert_eq!(printer.lines(), 3);
+        assert_eq!(printer.finish().unwrap(), b"READY\r\nOK.\r\n\x0C");
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::io::Device;
+use crate::state::{Reader, Writer};
+
+// Ready bits of a status port. Port 0 of the file devices is status and port 1 data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Status {
+    pub input_ready: u8,
+    pub output_ready: u8,
+    pub active_low: bool,
+}
+
+impl Status {
+    // MITS 88-SIO: bit 0 input device ready and bit 7 output device ready, low when ready.
+    pub const SIO: Status = Status { input_ready: 0x01, output_ready: 0x80, active_low: true };
+    // 8251 status: TxRDY bit 0 and RxRDY bit 1.
+    pub const USART: Status = Status { input_ready: 0x02, output_ready: 0x01, active_low: false };
+
+    pub fn encode(&self, input: bool, output: bool) -> u8 {
+        let mut status = 0;
+        if input {
+            status |= self.input_ready;
+        }
+        if output {
+            status |= self.output_ready;
+        }
+        if self.active_low {
+            status ^= self.input_ready | self.output_ready;
+        }
+        status
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::USART
+    }
+}
+
+// What the reader does once it runs off the end of the tape.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Eof {
+    NotReady, // never ready again, as with a real reader
+    Byte(u8), // keep supplying a byte, e.g. 1Ah for CP/M or 00h trailer
+}
+
+pub struct PaperTapeReader {
+    tape: Vec<u8>,
+    position: usize,
+    status: Status,
+    eof: Eof,
+}
+
+impl PaperTapeReader {
+    pub fn new(tape: Vec<u8>) -> Self {
+        Self { tape, position: 0, status: Status::default(), eof: Eof::NotReady }
+    }
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(fs::read(path)?))
+    }
+
+    // Replaces the tape and starts at its beginning.
+    pub fn insert(&mut self, tape: Vec<u8>) {
+        self.tape = tape;
+        self.position = 0;
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0;
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    pub fn set_eof(&mut self, eof: Eof) {
+        self.eof = eof;
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn eof(&self) -> bool {
+        self.position >= self.tape.len()
+    }
+
+    fn ready(&self) -> bool {
+        !self.eof() || self.eof != Eof::NotReady
+    }
+}
+
+impl Device for PaperTapeReader {
+    fn input(&mut self, port: u8) -> u8 {
+        if port & 0x01 == 0 {
+            return self.status.encode(self.ready(), false);
+        }
+        match (self.tape.get(self.position), self.eof) {
+            (Some(&value), _) => {
+                self.position += 1;
+                value
+            }
+            (None, Eof::Byte(value)) => value,
+            (None, Eof::NotReady) => 0xFF,
+        }
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.position);
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.position = state.get()?;
+        Ok(())
+    }
+}
+
+pub struct PaperTapePunch<W: Write> {
+    writer: W,
+    status: Status,
+    punched: usize,
+    error: Option<io::Error>,
+}
+
+impl PaperTapePunch<BufWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> PaperTapePunch<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, status: Status::default(), punched: 0, error: None }
+    }
+
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+    }
+
+    pub fn punched(&self) -> usize {
+        self.punched
+    }
+
+    // Flushes the tape and returns the writer, or the first write error.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Device for PaperTapePunch<W> {
+    fn input(&mut self, port: u8) -> u8 {
+        if port & 0x01 == 0 {
+            // The punch stops being ready after a write error, as when it runs out of tape.
+            return self.status.encode(false, self.error.is_none());
+        }
+        0xFF
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        if port & 0x01 == 0 || self.error.is_some() {
+            return;
+        }
+        match self.writer.write_all(&[value]) {
+            Ok(()) => self.punched += 1,
+            Err(error) => self.error = Some(error),
+        }
+    }
+
+    fn save(&self, state: &mut Writer) {
+        state.put(&self.punched);
+    }
+
+    fn load(&mut self, state: &mut Reader) -> io::Result<()> {
+        self.punched = state.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reader() {
+        let mut reader = PaperTapeReader::new(b"10 PRINT".to_vec());
+        reader.set_status(Status::SIO);
+        assert_eq!(reader.input(0), 0x80);
+        let tape: Vec<u8> = (0..8).map(|_| reader.input(1)).collect();
+        assert_eq!(tape, b"10 PRINT");
+        assert!(reader.eof());
+        assert_eq!(reader.input(0), 0x81);
+        reader.set_eof(Eof::Byte(0x1A));
+        assert_eq!(reader.input(0), 0x80);
+        assert_eq!(reader.input(1), 0x1A);
+        reader.rewind();
+        assert_eq!(reader.input(1), b'1');
+        reader.insert(b"RUN".to_vec());
+        assert_eq!(reader.input(1), b'R');
+    }
+
+    #[test]
+    fn punch() {
+        let mut punch = PaperTapePunch::new(Vec::new());
+        assert_eq!(punch.input(0), 0x01);
+        for value in [0x00, 0x3A, 0xFF] {
+            punch.output(1, value);
+        }
+        punch.output(0, 0x55);
+        assert_eq!(punch.punched(), 3);
+        assert_eq!(punch.finish().unwrap(), [0x00, 0x3A, 0xFF]);
+    }
+}
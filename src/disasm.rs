@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+use crate::symbols::Symbols;
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+const PAIRS: [&str; 4] = ["B", "D", "H", "SP"];
+const CONDITIONS: [&str; 8] = ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBB", "ANA", "XRA", "ORA", "CMP"];
+const ALU_IMMEDIATE: [&str; 8] = ["ADI", "ACI", "SUI", "SBI", "ANI", "XRI", "ORI", "CPI"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub length: usize,
+    pub text: String,
+}
+
+// Bytes taken by the instruction starting with `opcode`.
+pub fn length(opcode: u8) -> usize {
+    match opcode {
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2A | 0x32 | 0x3A => 3,
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => 2,
+        0xC3 | 0xCB | 0xCD | 0xDD | 0xED | 0xFD => 3,
+        0xD3 | 0xDB => 2,
+        _ if opcode >= 0xC0 && opcode & 0x07 == 0x02 => 3, // Jcc
+        _ if opcode >= 0xC0 && opcode & 0x07 == 0x04 => 3, // Ccc
+        _ if opcode >= 0xC0 && opcode & 0x07 == 0x06 => 2, // ADI..CPI
+        _ => 1,
+    }
+}
+
+// Numbers as Intel's assemblers write them: hex with an H suffix and a leading
+// 0 when the first digit is a letter.
+pub fn hex(value: u16, digits: usize) -> String {
+    let text = format!("{value:0digits$X}H");
+    if text.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        format!("0{text}")
+    } else {
+        text
+    }
+}
+
+// The documented opcode an undocumented one behaves as.
+pub fn documented(opcode: u8) -> u8 {
+    match opcode {
+        0x08 | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 => 0x00,
+        0xCB => 0xC3,
+        0xD9 => 0xC9,
+        0xDD | 0xED | 0xFD => 0xCD,
+        _ => opcode,
+    }
+}
+
+// Opcodes whose stack writes are a call and whose stack reads are a return.
+// DDh, EDh and FDh are undocumented copies of CALL and D9h of RET.
+pub fn is_call(opcode: u8) -> bool {
+    matches!(opcode, 0xCD | 0xDD | 0xED | 0xFD) || opcode & 0xC7 == 0xC4 || opcode & 0xC7 == 0xC7
+}
+
+pub fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC9 | 0xD9) || opcode & 0xC7 == 0xC0
+}
+
+// Disassembles the instruction in `bytes`, naming addresses found in
+// `symbols`. Undocumented opcodes come out as DB so that the text assembles
+// back to the same bytes, with what they do as a comment.
+pub fn decode(bytes: [u8; 3], symbols: Option<&Symbols>) -> Instruction {
+    let opcode = bytes[0];
+    let length = length(opcode);
+    let byte = hex(bytes[1] as u16, 2);
+    let word = u16::from_le_bytes([bytes[1], bytes[2]]);
+    let address = symbols.and_then(|symbols| symbols.name(word)).map_or_else(|| hex(word, 4), str::to_string);
+    let text = mnemonic(documented(opcode), &byte, &address);
+    if documented(opcode) != opcode {
+        let bytes: Vec<_> = bytes[..length].iter().map(|&byte| hex(byte as u16, 2)).collect();
+        return Instruction { length, text: format!("DB {} ; {text}", bytes.join(",")) };
+    }
+    Instruction { length, text }
+}
+
+fn mnemonic(opcode: u8, byte: &str, address: &str) -> String {
+    let destination = REGISTERS[(opcode >> 3 & 7) as usize];
+    let source = REGISTERS[(opcode & 7) as usize];
+    let pair = PAIRS[(opcode >> 4 & 3) as usize];
+    let condition = CONDITIONS[(opcode >> 3 & 7) as usize];
+    match opcode >> 6 {
+        0 => match opcode {
+            0x00 => "NOP".to_string(),
+            0x02 | 0x12 => format!("STAX {pair}"),
+            0x0A | 0x1A => format!("LDAX {pair}"),
+            0x22 => format!("SHLD {address}"),
+            0x2A => format!("LHLD {address}"),
+            0x32 => format!("STA {address}"),
+            0x3A => format!("LDA {address}"),
+            0x07 => "RLC".to_string(),
+            0x0F => "RRC".to_string(),
+            0x17 => "RAL".to_string(),
+            0x1F => "RAR".to_string(),
+            0x27 => "DAA".to_string(),
+            0x2F => "CMA".to_string(),
+            0x37 => "STC".to_string(),
+            0x3F => "CMC".to_string(),
+            _ => match opcode & 0x0F {
+                0x01 => format!("LXI {pair},{address}"),
+                0x03 => format!("INX {pair}"),
+                0x09 => format!("DAD {pair}"),
+                0x0B => format!("DCX {pair}"),
+                _ => match opcode & 7 {
+                    4 => format!("INR {destination}"),
+                    5 => format!("DCR {destination}"),
+                    _ => format!("MVI {destination},{byte}"),
+                },
+            },
+        },
+        1 if opcode == 0x76 => "HLT".to_string(),
+        1 => format!("MOV {destination},{source}"),
+        2 => format!("{} {source}", ALU[(opcode >> 3 & 7) as usize]),
+        _ => match opcode {
+            0xC3 => format!("JMP {address}"),
+            0xC9 => "RET".to_string(),
+            0xCD => format!("CALL {address}"),
+            0xD3 => format!("OUT {byte}"),
+            0xDB => format!("IN {byte}"),
+            0xE3 => "XTHL".to_string(),
+            0xE9 => "PCHL".to_string(),
+            0xEB => "XCHG".to_string(),
+            0xF3 => "DI".to_string(),
+            0xF9 => "SPHL".to_string(),
+            0xFB => "EI".to_string(),
+            0xF1 => "POP PSW".to_string(),
+            0xF5 => "PUSH PSW".to_string(),
+            _ => match opcode & 0x0F {
+                0x01 => format!("POP {pair}"),
+                0x05 => format!("PUSH {pair}"),
+                _ => match opcode & 7 {
+                    0 => format!("R{condition}"),
+                    2 => format!("J{condition} {address}"),
+                    4 => format!("C{condition} {address}"),
+                    6 => format!("{} {byte}", ALU_IMMEDIATE[(opcode >> 3 & 7) as usize]),
+                    _ => format!("RST {}", opcode >> 3 & 7),
+                },
+            },
+        },
+    }
+}
+
+// Source for `bytes` loaded at `origin` that assembles back to the same bytes.
+// From `entries`, code is traced through jumps, calls and restarts and
+// whatever is not reached is data; with no entries, every byte outside the
+// `data` ranges is taken as code. Addresses used by instructions are labelled
+// with names from `symbols`, or Lxxxx inside the program, and names that do
+// not fall at the start of an instruction or data come out as EQUs.
+pub fn source(bytes: &[u8], origin: u16, entries: &[u16], data: &[RangeInclusive<u16>], symbols: &Symbols) -> String {
+    let bytes = &bytes[..bytes.len().min(0x10000 - origin as usize)];
+    let end = origin as usize + bytes.len();
+    let inside = |address: u16| (origin as usize..end).contains(&(address as usize));
+    let peek = |address: u16| bytes.get(address.wrapping_sub(origin) as usize).copied().unwrap_or(0);
+    let instruction = |address: u16| [0, 1, 2].map(|offset| peek(address.wrapping_add(offset)));
+
+    // Offsets of instruction starts, and of every byte they cover.
+    let mut starts = BTreeSet::new();
+    let mut covered = vec![false; bytes.len()];
+    let mut take = |address: u16, covered: &mut Vec<bool>| {
+        let length = length(peek(address));
+        let offset = address.wrapping_sub(origin) as usize;
+        let free = offset + length <= bytes.len()
+            && covered[offset..offset + length].iter().all(|&taken| !taken)
+            && (0..length as u16).all(|index| !data.iter().any(|range| range.contains(&address.wrapping_add(index))));
+        if free {
+            covered[offset..offset + length].iter_mut().for_each(|taken| *taken = true);
+            starts.insert(address);
+        }
+        free
+    };
+    if entries.is_empty() {
+        let mut address = origin as usize;
+        while address < end {
+            address += if take(address as u16, &mut covered) { length(peek(address as u16)) } else { 1 };
+        }
+    }
+    let mut pending: Vec<u16> = entries.iter().copied().filter(|&entry| inside(entry)).collect();
+    while let Some(mut address) = pending.pop() {
+        while inside(address) && take(address, &mut covered) {
+            let [opcode, low, high] = instruction(address);
+            let target = u16::from_le_bytes([low, high]);
+            match documented(opcode) {
+                0xC3 | 0xCD => pending.push(target),
+                opcode if opcode >= 0xC0 && matches!(opcode & 7, 2 | 4) => pending.push(target),
+                opcode if opcode & 0xC7 == 0xC7 => pending.push((opcode & 0x38) as u16),
+                _ => {}
+            }
+            if matches!(documented(opcode), 0xC3 | 0xC9 | 0xE9 | 0x76) {
+                break;
+            }
+            address = address.wrapping_add(length(opcode) as u16);
+        }
+    }
+
+    // Names for the program's entries, for its symbols, and for addresses its
+    // instructions use.
+    let mut names: BTreeMap<u16, String> = symbols.iter().map(|(address, name)| (address, name.to_string())).collect();
+    let used = starts.iter().filter(|&&address| length(peek(address)) == 3).map(|&address| {
+        let [_, low, high] = instruction(address);
+        u16::from_le_bytes([low, high])
+    });
+    for address in entries.iter().copied().chain(used).filter(|&address| inside(address)) {
+        names.entry(address).or_insert_with(|| format!("L{address:04X}"));
+    }
+    let mut labels = Symbols::new();
+    for (&address, name) in &names {
+        labels.insert(name, address);
+    }
+    let placed = |address: u16| inside(address) && (starts.contains(&address) || !covered[address.wrapping_sub(origin) as usize]);
+
+    let mut text = String::new();
+    for (&address, name) in names.iter().filter(|&(&address, _)| !placed(address)) {
+        writeln!(text, "{name}\tEQU\t{}", hex(address, 4)).unwrap();
+    }
+    writeln!(text, "\tORG\t{}", hex(origin, 4)).unwrap();
+    let label = |address: u16| names.get(&address).map_or(String::new(), |name| format!("{name}:"));
+    let mut address = origin as usize;
+    while address < end {
+        let current = address as u16;
+        if starts.contains(&current) {
+            let instruction = decode(instruction(current), Some(&labels));
+            writeln!(text, "{}\t{}", label(current), instruction.text.replacen(' ', "\t", 1)).unwrap();
+            address += instruction.length;
+            continue;
+        }
+        // Data up to 8 bytes, the next instruction or the next label.
+        let mut run = vec![hex(peek(current) as u16, 2)];
+        address += 1;
+        while address < end && run.len() < 8 && !starts.contains(&(address as u16)) && !names.contains_key(&(address as u16)) {
+            run.push(hex(peek(address as u16) as u16, 2));
+            address += 1;
+        }
+        writeln!(text, "{}\tDB\t{}", label(current), run.join(",")).unwrap();
+    }
+    match entries.first() {
+        Some(entry) => writeln!(text, "\tEND\t{}", names.get(entry).cloned().unwrap_or_else(|| hex(*entry, 4))),
+        None => writeln!(text, "\tEND"),
+    }
+    .unwrap();
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8]) -> String {
+        let mut padded = [0; 3];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        let instruction = decode(padded, None);
+        assert_eq!(instruction.length, bytes.len(), "{:02X}", bytes[0]);
+        instruction.text
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(text(&[0x00]), "NOP");
+        assert_eq!(text(&[0x31, 0x00, 0xF0]), "LXI SP,0F000H");
+        assert_eq!(text(&[0x3E, 0x42]), "MVI A,42H");
+        assert_eq!(text(&[0x36, 0xFF]), "MVI M,0FFH");
+        assert_eq!(text(&[0x7E]), "MOV A,M");
+        assert_eq!(text(&[0x76]), "HLT");
+        assert_eq!(text(&[0x9E]), "SBB M");
+        assert_eq!(text(&[0x32, 0x34, 0x12]), "STA 1234H");
+        assert_eq!(text(&[0x1A]), "LDAX D");
+        assert_eq!(text(&[0x39]), "DAD SP");
+        assert_eq!(text(&[0xF5]), "PUSH PSW");
+        assert_eq!(text(&[0xC1]), "POP B");
+        assert_eq!(text(&[0xE8]), "RPE");
+        assert_eq!(text(&[0xFA, 0x00, 0x01]), "JM 0100H");
+        assert_eq!(text(&[0xD4, 0x05, 0x00]), "CNC 0005H");
+        assert_eq!(text(&[0xFE, 0x0D]), "CPI 0DH");
+        assert_eq!(text(&[0xDB, 0x10]), "IN 10H");
+        assert_eq!(text(&[0xEF]), "RST 5");
+        assert_eq!(text(&[0xDD, 0x00, 0x01]), "DB 0DDH,00H,01H ; CALL 0100H");
+        assert_eq!(text(&[0x38]), "DB 38H ; NOP");
+    }
+
+    #[test]
+    fn symbols() {
+        let symbols = Symbols::parse("0005 BDOS").unwrap();
+        assert_eq!(decode([0xCD, 0x05, 0x00], Some(&symbols)).text, "CALL BDOS");
+        assert_eq!(decode([0x21, 0x05, 0x00], Some(&symbols)).text, "LXI H,BDOS");
+        assert_eq!(decode([0x3E, 0x05, 0x00], Some(&symbols)).text, "MVI A,05H");
+    }
+
+    #[test]
+    fn opcodes() {
+        assert!(is_call(0xCD) && is_call(0xFC) && is_call(0xFF) && is_call(0xDD));
+        assert!(!is_call(0xC5) && !is_call(0xC3));
+        assert!(is_return(0xC9) && is_return(0xF8) && is_return(0xD9));
+        assert!(!is_return(0xC1) && !is_return(0xE9));
+    }
+
+    #[test]
+    fn source() {
+        // 0100: LXI D,0111h; MVI C,9; CALL 5; JMP 0110h; DB 0; 0110: RET;
+        // 0111: DB 'Hi$', then an undocumented CALL 0110h.
+        let bytes = [
+            0x11, 0x11, 0x01, 0x0E, 0x09, 0xCD, 0x05, 0x00, 0xC3, 0x10, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC9, b'H',
+            b'i', b'$', 0xDD, 0x10, 0x01,
+        ];
+        let symbols = Symbols::parse("0005 BDOS 0111 MESSAGE").unwrap();
+        let text = super::source(&bytes, 0x0100, &[0x0100], &[], &symbols);
+        assert_eq!(
+            text,
+            "BDOS\tEQU\t0005H\n\tORG\t0100H\nL0100:\tLXI\tD,MESSAGE\n\tMVI\tC,09H\n\tCALL\tBDOS\n\tJMP\tL0110\n\
+             \tDB\t00H,00H,00H,00H,00H\nL0110:\tRET\nMESSAGE:\tDB\t48H,69H,24H,0DDH,10H,01H\n\tEND\tL0100\n"
+        );
+        let linear = super::source(&bytes, 0x0100, &[], &[0x0111..=0x0113], &Symbols::new());
+        assert!(linear.contains("L0111:\tDB\t48H,69H,24H\n\tDB\t0DDH,10H,01H ; CALL L0110\n"));
+        for text in [text, linear] {
+            let assembly = crate::asm::assemble(&text, 0, &Symbols::new()).unwrap();
+            let image: Vec<u8> = assembly.image.segments.into_iter().flat_map(|(_, bytes)| bytes).collect();
+            assert_eq!(image, bytes);
+        }
+    }
+}
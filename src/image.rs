@@ -0,0 +1,158 @@
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::state::invalid;
+use crate::I8080;
+
+// A program to load into memory: runs of bytes at their addresses and,
+// if the file gave one, where to start it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<(u16, Vec<u8>)>,
+    pub entry: Option<u16>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_binary(origin: u16, bytes: &[u8]) -> Self {
+        Self { segments: vec![(origin, bytes.to_vec())], entry: None }
+    }
+
+    // Intel HEX with data (00) and end of file (01) records. A nonzero address
+    // in the end record is the entry point, as CP/M's tools write it.
+    pub fn from_hex(text: &str) -> io::Result<Self> {
+        let mut image = Self::new();
+        for (number, line) in text.lines().enumerate().map(|(index, line)| (index + 1, line.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+            let bytes = line
+                .strip_prefix(':')
+                .filter(|digits| digits.is_ascii() && digits.len() % 2 == 0)
+                .and_then(|digits| (0..digits.len()).step_by(2).map(|index| u8::from_str_radix(&digits[index..index + 2], 16).ok()).collect::<Option<Vec<u8>>>())
+                .filter(|bytes| bytes.len() >= 5 && bytes.len() == 5 + bytes[0] as usize)
+                .ok_or_else(|| invalid(format!("line {number} is not a HEX record")))?;
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+                return Err(invalid(format!("line {number} has a bad checksum")));
+            }
+            let address = u16::from_be_bytes([bytes[1], bytes[2]]);
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => image.insert(address, data),
+                0x01 => {
+                    image.entry = (address != 0).then_some(address);
+                    break;
+                }
+                kind => return Err(invalid(format!("line {number} has record type {kind:02X}h"))),
+            }
+        }
+        Ok(image)
+    }
+
+    // Intel HEX, 16 bytes to a record.
+    pub fn to_hex(&self) -> String {
+        let mut text = String::new();
+        let mut record = |kind: u8, address: u16, data: &[u8]| {
+            let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+            bytes.extend_from_slice(data);
+            let checksum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte));
+            bytes.push(checksum);
+            text.push(':');
+            for byte in bytes {
+                write!(text, "{byte:02X}").unwrap();
+            }
+            text.push_str("\r\n");
+        };
+        for (origin, bytes) in &self.segments {
+            for (index, chunk) in bytes.chunks(16).enumerate() {
+                record(0x00, origin.wrapping_add(index as u16 * 16), chunk);
+            }
+        }
+        record(0x01, self.entry.unwrap_or(0), &[]);
+        text
+    }
+
+    // A .HEX file, or any other file as a binary image at `origin`. Addresses
+    // in a .HEX file are moved by `offset`.
+    pub fn load(path: &Path, origin: u16, offset: u16) -> io::Result<Self> {
+        let hex = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("hex"));
+        let mut image = match hex {
+            true => Self::from_hex(&fs::read_to_string(path)?)?,
+            false => Self::from_binary(origin, &fs::read(path)?),
+        };
+        if hex && offset != 0 {
+            for (address, _) in &mut image.segments {
+                *address = address.wrapping_add(offset);
+            }
+            image.entry = image.entry.map(|entry| entry.wrapping_add(offset));
+        }
+        Ok(image)
+    }
+
+    // Adds bytes at `address`, joining them to the segment they follow.
+    pub fn insert(&mut self, address: u16, bytes: &[u8]) {
+        match self.segments.last_mut() {
+            Some((origin, data)) if origin.wrapping_add(data.len() as u16) == address && data.len() + bytes.len() <= 0x10000 => {
+                data.extend_from_slice(bytes)
+            }
+            _ => self.segments.push((address, bytes.to_vec())),
+        }
+    }
+
+    // One past the highest address loaded.
+    pub fn end(&self) -> u32 {
+        self.segments.iter().map(|(origin, bytes)| *origin as u32 + bytes.len() as u32).max().unwrap_or(0)
+    }
+
+    // The bytes from the lowest address loaded to the highest, with zeros in
+    // any gaps, and the address they start at.
+    pub fn to_binary(&self) -> (u16, Vec<u8>) {
+        let start = self.segments.iter().map(|(origin, _)| *origin).min().unwrap_or(0);
+        let mut binary = vec![0; self.end().saturating_sub(start as u32) as usize];
+        for (origin, bytes) in &self.segments {
+            let offset = (origin - start) as usize;
+            binary[offset..offset + bytes.len()].copy_from_slice(bytes);
+        }
+        (start, binary)
+    }
+
+    // Copies the image into memory, failing if it does not fit.
+    pub fn write_to(&self, i8080: &mut I8080) -> io::Result<()> {
+        if self.end() as usize > i8080.memory_size() {
+            return Err(invalid(format!("image ends at {:04X}h, past the end of memory", self.end())));
+        }
+        for (origin, bytes) in &self.segments {
+            for (offset, &value) in bytes.iter().enumerate() {
+                i8080.poke(origin + offset as u16, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex() {
+        let mut image = Image::from_binary(0x0100, &(0..20).collect::<Vec<u8>>());
+        image.insert(0x0200, &[0xC3, 0x00, 0x01]);
+        image.entry = Some(0x0100);
+        let text = image.to_hex();
+        assert!(text.starts_with(":10010000000102030405060708090A0B0C0D0E0F77\r\n:0401100010111213A5\r\n"));
+        assert!(text.ends_with(":03020000C3000137\r\n:00010001FE\r\n"));
+        assert_eq!(Image::from_hex(&text).unwrap(), Image { segments: vec![(0x0100, (0..20).collect()), (0x0200, vec![0xC3, 0x00, 0x01])], entry: Some(0x0100) });
+        assert!(Image::from_hex(":0300000000000001\r\n").unwrap_err().to_string().contains("checksum"));
+        assert!(Image::from_hex("0100").is_err());
+        assert!(Image::from_hex(":aé0").unwrap_err().to_string().contains("not a HEX record"));
+        assert_eq!(image.end(), 0x0203);
+        let (start, binary) = image.to_binary();
+        assert_eq!((start, binary.len(), &binary[0x13..0x15], &binary[0x100..]), (0x0100, 0x0103, &[0x13, 0x00][..], &[0xC3, 0x00, 0x01][..]));
+    }
+}
@@ -0,0 +1,196 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+use crate::state::{self, Reader, Writer};
+
+// Ports are passed to a device relative to the first port it was attached at.
+pub trait Device {
+    fn input(&mut self, port: u8) -> u8;
+    fn output(&mut self, port: u8, value: u8);
+
+    fn wait_states(&self, _port: u8) -> usize {
+        0
+    }
+
+    // Advances the device by `states` CPU T-states.
+    fn clock(&mut self, _states: usize) {}
+
+    // Level of the device's INTR output.
+    fn intr(&self) -> bool {
+        false
+    }
+
+    // Byte placed on the data bus during an interrupt acknowledge cycle. If it
+    // is a CALL, this is called again for each of the two address bytes.
+    // Devices with a `set_interrupt` place the instruction given there, and
+    // RST 7 without one.
+    fn inta(&mut self) -> u8 {
+        0xFF // RST 7 from the bus pull-ups
+    }
+
+    // Internal state for save states. `load` reads back what `save` wrote;
+    // host resources like files and callbacks stay as they are.
+    fn save(&self, _state: &mut Writer) {}
+
+    fn load(&mut self, _state: &mut Reader) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct Io {
+    devices: Vec<Rc<RefCell<dyn Device>>>,
+    ports: [Option<(usize, u8)>; 256], // device index, base port
+    acknowledged: Option<usize>,
+    replay: VecDeque<u8>,
+}
+
+impl Io {
+    pub fn new() -> Self {
+        Self {
+            devices: Vec::new(),
+            ports: [None; 256],
+            acknowledged: None,
+            replay: VecDeque::new(),
+        }
+    }
+
+    pub fn attach(&mut self, ports: RangeInclusive<u8>, device: Rc<RefCell<dyn Device>>) {
+        let index = match self.devices.iter().position(|attached| Rc::ptr_eq(attached, &device)) {
+            Some(index) => index,
+            None => {
+                self.devices.push(device);
+                self.devices.len() - 1
+            }
+        };
+        let base = *ports.start();
+        for port in ports {
+            self.ports[port as usize] = Some((index, base));
+        }
+    }
+
+    pub fn clock(&mut self, states: usize) {
+        for device in &self.devices {
+            device.borrow_mut().clock(states);
+        }
+    }
+
+    pub fn intr(&self) -> bool {
+        self.devices.iter().any(|device| device.borrow().intr())
+    }
+
+    // Acknowledges the first device, in attach order, with INTR high. Further
+    // INTA cycles go to the same device until `end_acknowledge`.
+    pub fn inta(&mut self) -> u8 {
+        let index = self.acknowledged.or_else(|| self.devices.iter().position(|device| device.borrow().intr()));
+        self.acknowledged = index;
+        match index {
+            Some(index) => self.devices[index].borrow_mut().inta(),
+            None => 0xFF,
+        }
+    }
+
+    pub fn acknowledging(&self) -> bool {
+        self.acknowledged.is_some()
+    }
+
+    pub fn end_acknowledge(&mut self) {
+        self.acknowledged = None;
+    }
+
+    // Each device's state goes in its own block so a device reading more or
+    // less than it saved is caught.
+    pub fn save(&self, state: &mut Writer) {
+        state.put(&self.acknowledged);
+        state.put(&self.devices.len());
+        for device in &self.devices {
+            let mut block = Writer::new();
+            device.borrow().save(&mut block);
+            state.put(&block.into_bytes());
+        }
+    }
+
+    pub fn load(&mut self, state: &mut Reader) -> std::io::Result<()> {
+        let acknowledged = state.get()?;
+        let count: usize = state.get()?;
+        if count != self.devices.len() {
+            return Err(state::invalid(format!("save state has {count} devices, machine has {}", self.devices.len())));
+        }
+        for device in &self.devices {
+            let block: Vec<u8> = state.get()?;
+            let mut block = Reader::new(&block);
+            device.borrow_mut().load(&mut block)?;
+            block.finish()?;
+        }
+        self.acknowledged = acknowledged;
+        Ok(())
+    }
+
+    // Values for the next inputs, in place of what the devices return. The
+    // devices are still read so their state moves on as it did the first time.
+    pub fn replay(&mut self, values: impl IntoIterator<Item = u8>) {
+        self.replay.extend(values);
+    }
+
+    pub fn clear_replay(&mut self) {
+        self.replay.clear();
+    }
+
+    pub fn input(&mut self, port: u8) -> u8 {
+        let value = match self.ports[port as usize] {
+            Some((index, base)) => self.devices[index].borrow_mut().input(port - base),
+            None => 0xFF, // floating data bus
+        };
+        self.replay.pop_front().unwrap_or(value)
+    }
+
+    pub fn wait_states(&self, port: u8) -> usize {
+        match self.ports[port as usize] {
+            Some((index, base)) => self.devices[index].borrow().wait_states(port - base),
+            None => 0,
+        }
+    }
+
+    pub fn output(&mut self, port: u8, value: u8) {
+        if let Some((index, base)) = self.ports[port as usize] {
+            self.devices[index].borrow_mut().output(port - base, value);
+        }
+    }
+}
+
+impl Default for Io {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Latch {
+        ports: [u8; 2],
+    }
+
+    impl Device for Latch {
+        fn input(&mut self, port: u8) -> u8 {
+            self.ports[port as usize]
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.ports[port as usize] = value;
+        }
+    }
+
+    #[test]
+    fn attach() {
+        let latch = Rc::new(RefCell::new(Latch { ports: [0; 2] }));
+        let mut io = Io::new();
+        io.attach(0x10..=0x11, latch.clone());
+        io.output(0x11, 0x5A);
+        assert_eq!(latch.borrow().ports, [0x00, 0x5A]);
+        assert_eq!(io.input(0x11), 0x5A);
+        assert_eq!(io.input(0x12), 0xFF);
+    }
+}
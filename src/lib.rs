@@ -1,3 +1,33 @@
+pub mod asm;
+pub mod bus;
+pub mod callstack;
+pub mod coverage;
+pub mod devices;
+pub mod disasm;
+pub mod image;
+pub mod io;
+pub mod monitor;
+pub mod panel;
+pub mod profile;
+pub mod replay;
+pub mod rewind;
+pub mod runner;
+pub mod state;
+pub mod symbols;
+pub mod tui;
+pub mod vcd;
+pub mod wav;
+
+use std::cell::RefCell;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::rc::Rc;
+
+use bus::{BusObserver, DmaMaster, MachineCycle, Ready};
+use io::{Device, Io};
+use state::{Reader, Writer};
+
 enum Flag {
     C = 0,
     P = 2,
@@ -11,6 +41,7 @@ const CONSTANT_FLAGS: u8 = 0b00101010;
 #[derive(Clone, Copy)]
 enum Register {
     A,
+    #[allow(dead_code)]
     F,
     B,
     C,
@@ -21,6 +52,7 @@ enum Register {
 }
 
 #[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
 enum RegisterPair {
     B,
     D,
@@ -28,6 +60,29 @@ enum RegisterPair {
     PSW,
 }
 
+// The programmer-visible registers, for debuggers and test harnesses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub a: u8,
+    pub flags: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+}
+
+impl Registers {
+    pub const CARRY: u8 = 1 << Flag::C as u8;
+    pub const PARITY: u8 = 1 << Flag::P as u8;
+    pub const AUXILIARY_CARRY: u8 = 1 << Flag::A as u8;
+    pub const ZERO: u8 = 1 << Flag::Z as u8;
+    pub const SIGN: u8 = 1 << Flag::S as u8;
+}
+
 pub struct I8080 {
     pc: u16,
     sp: u16,
@@ -40,8 +95,24 @@ pub struct I8080 {
     l: u8,
     flags: u8,
     cycles: usize,
+    ticks: u64,
+    instructions: u64,
     inte: bool,
+    halted: bool,
+    interrupt: Option<u8>,
+    interrupt_operands: Vec<u8>,
+    device_interrupts: bool,
+    acknowledging: bool, // operand bytes come from INTA cycles
+    hold_states: usize,
+    bus: MachineCycle,
+    machine_cycles: Vec<MachineCycle>,
+    machine_cycle: usize, // next machine cycle of the current instruction
+    machine_cycle_states: usize,
+    observers: Vec<Rc<RefCell<dyn BusObserver>>>,
+    dma: Vec<Rc<RefCell<dyn DmaMaster>>>,
+    ready: Option<Box<dyn Ready>>,
     memory: Box<[u8]>,
+    io: Io,
 }
 
 impl I8080 {
@@ -58,18 +129,301 @@ impl I8080 {
             l: 0,
             flags: 0b00000010, // always: bit-1 = 1, bit-5 = 0
             cycles: 0,
+            ticks: 0,
+            instructions: 0,
             inte: false,
+            halted: false,
+            interrupt: None,
+            interrupt_operands: Vec::new(),
+            device_interrupts: true,
+            acknowledging: false,
+            hold_states: 0,
+            bus: MachineCycle::default(),
+            machine_cycles: Vec::new(),
+            machine_cycle: 0,
+            machine_cycle_states: 0,
+            observers: Vec::new(),
+            dma: Vec::new(),
+            ready: None,
             memory: vec![0; memory_size].into_boxed_slice(),
+            io: Io::new(),
+        }
+    }
+
+    pub fn attach(&mut self, ports: RangeInclusive<u8>, device: Rc<RefCell<dyn Device>>) {
+        self.io.attach(ports, device);
+    }
+
+    // Breaks every instruction into machine cycles and reports them to `observer`.
+    pub fn observe(&mut self, observer: Rc<RefCell<dyn BusObserver>>) {
+        self.observers.push(observer);
+    }
+
+    pub fn unobserve(&mut self, observer: &Rc<RefCell<dyn BusObserver>>) {
+        self.observers.retain(|attached| !Rc::ptr_eq(attached, observer));
+    }
+
+    // Connects a bus master to HOLD. It is granted the bus at the end of the
+    // current machine cycle and the CPU waits for the T-states the transfer took.
+    pub fn attach_dma(&mut self, master: Rc<RefCell<dyn DmaMaster>>) {
+        self.dma.push(master);
+    }
+
+    // Pulls READY low to insert wait states into memory and I/O cycles. Devices
+    // add their own through `Device::wait_states`.
+    pub fn set_ready(&mut self, ready: Box<dyn Ready>) {
+        self.ready = Some(ready);
+    }
+
+    // Raises INTR; `instruction` is placed on the data bus when it is acknowledged.
+    pub fn interrupt(&mut self, instruction: u8) {
+        self.interrupt = Some(instruction);
+        self.interrupt_operands.clear();
+    }
+
+    // Like `interrupt`, for an instruction with operands such as the CALL an 8259 supplies.
+    pub fn interrupt_sequence(&mut self, bytes: &[u8]) {
+        self.interrupt = bytes.first().copied();
+        self.interrupt_operands = bytes.iter().skip(1).copied().collect();
+    }
+
+    // Ignores INTR from the attached devices, leaving only `interrupt`, as when
+    // replaying recorded interrupts.
+    pub fn set_device_interrupts(&mut self, enabled: bool) {
+        self.device_interrupts = enabled;
+    }
+
+    pub fn reset(&mut self) {
+        self.pc = 0;
+        self.inte = false;
+        self.halted = false;
+        self.cycles = 0;
+    }
+
+    // T-states elapsed since power on.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    // Instructions started since power on, interrupt acknowledges included.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    // Makes the next IN instructions read `values`, as when re-running recorded input.
+    pub fn replay_inputs(&mut self, values: impl IntoIterator<Item = u8>) {
+        self.io.replay(values);
+    }
+
+    // Drops the replayed inputs not read yet.
+    pub fn clear_replay_inputs(&mut self) {
+        self.io.clear_replay();
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            flags: self.flags,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            sp: self.sp,
+            pc: self.pc,
+        }
+    }
+
+    // The fixed bits of the flags keep their values, as with POP PSW.
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.a = registers.a;
+        self.flags = (self.flags & CONSTANT_FLAGS) | (registers.flags & !CONSTANT_FLAGS);
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.sp = registers.sp;
+        self.pc = registers.pc;
+    }
+
+    pub fn inte(&self) -> bool {
+        self.inte
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    // Leaves or enters the halt state, as a debugger restarting a program does.
+    pub fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    pub fn hlda(&self) -> bool {
+        self.hold_states > 0
+    }
+
+    // Status, address and data of the most recent machine cycle.
+    pub fn bus(&self) -> MachineCycle {
+        self.bus
+    }
+
+    // Memory access from outside the CPU, without running a bus cycle. Above
+    // the memory fitted, reads float to 0FFh and writes are lost.
+    pub fn peek(&self, location: u16) -> u8 {
+        self.memory.get(location as usize).copied().unwrap_or(0xFF)
+    }
+
+    pub fn poke(&mut self, location: u16, value: u8) {
+        if let Some(byte) = self.memory.get_mut(location as usize) {
+            *byte = value;
+        }
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    // Snapshot of the CPU, including an instruction in progress, memory and the
+    // attached devices. Observers, DMA masters and READY are the host's and
+    // are not saved; a DMA controller is saved as a device.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Writer::new();
+        self.save_cpu(&mut state);
+        self.io.save(&mut state);
+        state::seal(state.bytes())
+    }
+
+    // Hash of the CPU and memory, without the devices.
+    pub fn state_hash(&self) -> u64 {
+        let mut state = Writer::new();
+        self.save_cpu(&mut state);
+        state::hash(state.bytes())
+    }
+
+    fn save_cpu(&self, state: &mut Writer) {
+        state.put(&[self.pc, self.sp]);
+        state.put(&[self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.flags]);
+        state.put(&self.cycles);
+        state.put(&self.ticks);
+        state.put(&self.instructions);
+        state.put(&self.inte);
+        state.put(&self.halted);
+        state.put(&self.interrupt);
+        state.put(&self.interrupt_operands);
+        state.put(&self.acknowledging);
+        state.put(&self.hold_states);
+        state.put(&self.bus);
+        state.put(&self.machine_cycles);
+        state.put(&self.machine_cycle);
+        state.put(&self.machine_cycle_states);
+        state.put(&self.memory.to_vec());
+    }
+
+    // Restores a snapshot taken by `save_state` on a machine built the same
+    // way. Nothing changes if the snapshot is rejected.
+    pub fn load_state(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        let payload = state::open(bytes)?;
+        let backup = self.save_state();
+        let result = self.restore(payload);
+        if result.is_err() {
+            self.restore(state::open(&backup)?)?;
+        }
+        result
+    }
+
+    pub fn write_state(&self, path: &Path) -> std::io::Result<()> {
+        fs::write(path, self.save_state())
+    }
+
+    pub fn read_state(&mut self, path: &Path) -> std::io::Result<()> {
+        self.load_state(&fs::read(path)?)
+    }
+
+    fn restore(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        let mut state = Reader::new(payload);
+        [self.pc, self.sp] = state.get()?;
+        [self.a, self.b, self.c, self.d, self.e, self.h, self.l, self.flags] = state.get()?;
+        self.cycles = state.get()?;
+        self.ticks = state.get()?;
+        self.instructions = state.get()?;
+        self.inte = state.get()?;
+        self.halted = state.get()?;
+        self.interrupt = state.get()?;
+        self.interrupt_operands = state.get()?;
+        self.acknowledging = state.get()?;
+        self.hold_states = state.get()?;
+        self.bus = state.get()?;
+        self.machine_cycles = state.get()?;
+        self.machine_cycle = state.get()?;
+        self.machine_cycle_states = state.get()?;
+        let memory: Vec<u8> = state.get()?;
+        if memory.len() != self.memory.len() {
+            return Err(state::invalid(format!("save state has {} bytes of memory, machine has {}", memory.len(), self.memory.len())));
+        }
+        self.memory = memory.into_boxed_slice();
+        self.io.load(&mut state)?;
+        state.finish()
+    }
+
+    // Runs the rest of the current instruction and the next one, returning its T-states.
+    // Bus time stolen by DMA is included.
+    pub fn step(&mut self) -> usize {
+        while self.cycles > 0 || self.hold_states > 0 {
+            self.cycle();
+        }
+        let start = self.ticks;
+        let instructions = self.instructions;
+        self.cycle();
+        while self.hold_states > 0 || (self.instructions == instructions && !self.halted) {
+            self.cycle();
         }
+        while self.cycles > 0 || self.hold_states > 0 {
+            self.cycle();
+        }
+        (self.ticks - start) as usize
     }
 
     pub fn cycle(&mut self) {
+        self.ticks += 1;
+        self.io.clock(1);
+        if self.hold_states == 0 && self.machine_cycle_states == 0 {
+            self.hold();
+        }
+        if self.hold_states > 0 {
+            self.hold_states -= 1;
+            return;
+        }
         if self.cycles > 0 {
+            if self.machine_cycle_states == 0 {
+                self.next_machine_cycle();
+            }
+            self.machine_cycle_states -= 1;
             self.cycles -= 1;
             return;
         }
 
-        let opcode = self.next_u8();
+        self.machine_cycles.clear();
+        self.acknowledging = false;
+        self.io.end_acknowledge();
+        let opcode = if self.inte && (self.interrupt.is_some() || (self.device_interrupts && self.io.intr())) {
+            self.acknowledge()
+        } else if self.halted {
+            return;
+        } else {
+            self.fetch()
+        };
         let cycles = match opcode {
             0x00 | 0x10 | 0x20 | 0x30 | 0x08 | 0x18 | 0x28 | 0x38 => 4, // NOP
             0x01 => {self.lxi(RegisterPair::B); 10},                    // LXI B,d16
@@ -359,45 +713,169 @@ impl I8080 {
             0xFB => {self.inte = true; 4},                              // EI
             0xF3 => {self.inte = false; 4},                             // DI
 
-            _ => {eprintln!("Invalid opcode: {opcode}"); 0}
+            0xDB => {self.inp(); 10},                                   // IN d8
+            0xD3 => {self.out(); 10},                                   // OUT d8
+            0x76 => {self.hlt(); 7},                                    // HLT
         };
 
         self.cycles += cycles;
+        self.instructions += 1;
+        self.schedule(opcode);
+        self.next_machine_cycle();
+        self.machine_cycle_states -= 1; // this call was the first T-state
+        self.cycles -= 1;
+    }
+
+    // Internal T-states at the end of an instruction are added to its last machine cycle.
+    fn schedule(&mut self, opcode: u8) {
+        self.cycles += self.machine_cycles.iter().map(|cycle| cycle.wait).sum::<usize>();
+        let mut states = self.cycles;
+        let last = self.machine_cycles.len() - 1;
+        for (index, cycle) in self.machine_cycles.iter_mut().enumerate() {
+            cycle.number = index as u8 + 1;
+            cycle.states = match index {
+                _ if index == last => states,
+                0 => bus::fetch_states(opcode) + cycle.wait,
+                _ => 3 + cycle.wait,
+            };
+            states -= cycle.states;
+        }
+        self.machine_cycle = 0;
+    }
+
+    fn next_machine_cycle(&mut self) {
+        let cycle = &mut self.machine_cycles[self.machine_cycle];
+        cycle.start = self.ticks - 1;
+        self.machine_cycle_states = cycle.states;
+        self.machine_cycle += 1;
+        for observer in &self.observers {
+            observer.borrow_mut().machine_cycle(cycle);
+        }
+    }
+
+    fn hold(&mut self) {
+        let Some(master) = self.dma.iter().find(|master| master.borrow().hold()) else {
+            return;
+        };
+        let states = master.borrow_mut().transfer(&mut self.memory);
+        for observer in &self.observers {
+            observer.borrow_mut().hold(self.ticks - 1, states);
+        }
+        self.hold_states = states;
+    }
+
+    fn bus_cycle(&mut self, status: u8, address: u16, data: u8) {
+        let mut cycle = MachineCycle { status, address, data, inte: self.inte, ..Default::default() };
+        if let Some(ready) = &mut self.ready {
+            cycle.wait = ready.wait_states(&cycle);
+        }
+        if status & (bus::INP | bus::OUT) != 0 {
+            cycle.wait += self.io.wait_states(address as u8);
+        }
+        self.bus = cycle;
+        self.machine_cycles.push(cycle);
+    }
+
+    fn bus_read(&mut self, status: u8, address: u16) -> u8 {
+        let data = self.memory[address as usize];
+        self.bus_cycle(status, address, data);
+        data
+    }
+
+    fn bus_write(&mut self, status: u8, address: u16, data: u8) {
+        self.memory[address as usize] = data;
+        self.bus_cycle(status, address, data);
     }
 
-    fn read_u8(&self, location: u16) -> u8 {
-        self.memory[location as usize]
+    fn read_u8(&mut self, location: u16) -> u8 {
+        self.bus_read(bus::MEMORY_READ, location)
     }
 
-    fn read_u16(&self, location: u16) -> u16 {
-        ((self.memory[(location + 1) as usize] as u16) << 8) | self.memory[location as usize] as u16
+    fn read_u16(&mut self, location: u16) -> u16 {
+        let low = self.read_u8(location);
+        let high = self.read_u8(location + 1);
+        u16::from_le_bytes([low, high])
     }
 
-    fn read_m(&self) -> u8 {
-        self.memory[self.get_register_pair(RegisterPair::H) as usize]
+    fn read_m(&mut self) -> u8 {
+        self.read_u8(self.get_register_pair(RegisterPair::H))
+    }
+
+    fn read_stack(&mut self) -> u16 {
+        let low = self.bus_read(bus::STACK_READ, self.sp);
+        let high = self.bus_read(bus::STACK_READ, self.sp + 1);
+        u16::from_le_bytes([low, high])
     }
 
     fn write_u8(&mut self, location: u16, value: u8) {
-        self.memory[location as usize] = value;
+        self.bus_write(bus::MEMORY_WRITE, location, value);
     }
 
     fn write_u16(&mut self, location: u16, value: u16) {
         let value = value.to_le_bytes();
-        self.memory[location as usize] = value[0];
-        self.memory[(location + 1) as usize] = value[1];
+        self.write_u8(location, value[0]);
+        self.write_u8(location + 1, value[1]);
     }
 
     fn write_m(&mut self, value: u8) {
-        self.memory[self.get_register_pair(RegisterPair::H) as usize] = value;
+        self.write_u8(self.get_register_pair(RegisterPair::H), value);
+    }
+
+    fn write_stack(&mut self, value: u16) {
+        let value = value.to_le_bytes();
+        self.bus_write(bus::STACK_WRITE, self.sp + 1, value[1]);
+        self.bus_write(bus::STACK_WRITE, self.sp, value[0]);
+    }
+
+    // The instruction comes from the interrupting device and PC is not incremented.
+    fn acknowledge(&mut self) -> u8 {
+        let status = if self.halted { bus::INTERRUPT_ACKNOWLEDGE_WHILE_HALT } else { bus::INTERRUPT_ACKNOWLEDGE };
+        let instruction = match self.interrupt.take() {
+            Some(instruction) => instruction,
+            None => self.io.inta(),
+        };
+        self.inte = false;
+        self.halted = false;
+        self.acknowledging = true;
+        self.bus_cycle(status, self.pc, instruction);
+        instruction
+    }
+
+    // Operands of an acknowledged CALL are read with the memory read status,
+    // which the 8228 system controller turns into further INTA pulses.
+    fn acknowledge_operand(&mut self) -> u8 {
+        let value = if self.io.acknowledging() {
+            self.io.inta()
+        } else if !self.interrupt_operands.is_empty() {
+            self.interrupt_operands.remove(0)
+        } else {
+            0xFF
+        };
+        self.bus_cycle(bus::MEMORY_READ, self.pc, value);
+        value
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let value = self.bus_read(bus::INSTRUCTION_FETCH, self.pc);
+        self.pc += 1;
+        value
     }
 
     fn next_u8(&mut self) -> u8 {
+        if self.acknowledging {
+            return self.acknowledge_operand();
+        }
         let value = self.read_u8(self.pc);
         self.pc += 1;
         value
     }
 
     fn next_u16(&mut self) -> u16 {
+        if self.acknowledging {
+            let low = self.acknowledge_operand();
+            let high = self.acknowledge_operand();
+            return u16::from_le_bytes([low, high]);
+        }
         let value = self.read_u16(self.pc);
         self.pc += 2;
         value
@@ -518,7 +996,7 @@ impl I8080 {
     }
 
     fn pop(&mut self, pair: RegisterPair) {
-        let value = self.read_u16(self.sp);
+        let value = self.read_stack();
         self.set_register_pair(pair, value);
         self.sp += 2;
     }
@@ -526,7 +1004,7 @@ impl I8080 {
     fn push(&mut self, pair: RegisterPair) {
         let value = self.get_register_pair(pair);
         self.sp -= 2;
-        self.write_u16(self.sp, value);
+        self.write_stack(value);
     }
 
     fn shld(&mut self) {
@@ -543,9 +1021,9 @@ impl I8080 {
 
     fn xthl(&mut self) {
         let register = self.get_register_pair(RegisterPair::H);
-        let stack = self.read_u16(self.sp);
+        let stack = self.read_stack();
         self.set_register_pair(RegisterPair::H, stack);
-        self.write_u16(self.sp, register);
+        self.write_stack(register);
     }
 
     fn sphl(&mut self) {
@@ -841,7 +1319,7 @@ impl I8080 {
     }
 
     fn ret(&mut self) {
-        self.pc = self.read_u16(self.sp);
+        self.pc = self.read_stack();
         self.sp += 2;
     }
 
@@ -913,7 +1391,7 @@ impl I8080 {
         if self.get_flag(Flag::C) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
  
@@ -921,7 +1399,7 @@ impl I8080 {
         if !self.get_flag(Flag::C) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
  
@@ -929,7 +1407,7 @@ impl I8080 {
         if self.get_flag(Flag::Z) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
  
@@ -937,7 +1415,7 @@ impl I8080 {
         if !self.get_flag(Flag::Z) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
  
@@ -945,7 +1423,7 @@ impl I8080 {
         if self.get_flag(Flag::S) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
  
@@ -953,7 +1431,7 @@ impl I8080 {
         if !self.get_flag(Flag::S) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -961,7 +1439,7 @@ impl I8080 {
         if self.get_flag(Flag::P) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -969,14 +1447,15 @@ impl I8080 {
         if !self.get_flag(Flag::P) {
             self.jmp();
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
     fn call(&mut self) {
         self.sp -= 2;
-        self.write_u16(self.sp, self.pc + 2);
-        self.jmp();
+        let location = self.next_u16();
+        self.write_stack(self.pc);
+        self.pc = location;
     }
 
     fn cc(&mut self) {
@@ -984,7 +1463,7 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -993,7 +1472,7 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
  
@@ -1002,7 +1481,7 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -1011,7 +1490,7 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -1020,7 +1499,7 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -1029,7 +1508,7 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -1038,7 +1517,7 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
@@ -1047,18 +1526,36 @@ impl I8080 {
             self.call();
             self.cycles += 6;
         } else {
-            self.pc += 2;
+            self.next_u16();
         }
     }
 
     fn rst(&mut self, value: u8) {
         self.sp -= 2;
-        self.write_u16(self.sp, self.pc);
+        self.write_stack(self.pc);
         self.pc = (value << 3) as u16;
     }
+
+    fn inp(&mut self) {
+        let port = self.next_u8();
+        self.a = self.io.input(port);
+        self.bus_cycle(bus::INPUT_READ, u16::from_le_bytes([port, port]), self.a);
+    }
+
+    fn out(&mut self) {
+        let port = self.next_u8();
+        self.io.output(port, self.a);
+        self.bus_cycle(bus::OUTPUT_WRITE, u16::from_le_bytes([port, port]), self.a);
+    }
+
+    fn hlt(&mut self) {
+        self.halted = true;
+        self.bus_cycle(bus::HALT_ACKNOWLEDGE, self.pc, 0xFF);
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -1768,4 +2265,201 @@ mod tests {
             assert_eq!(i8080.get_flag(Flag::C), false);
         }
     }
+
+    mod cycle_tests {
+        use super::*;
+
+        struct Recorder {
+            cycles: Vec<MachineCycle>,
+        }
+
+        impl BusObserver for Recorder {
+            fn machine_cycle(&mut self, cycle: &MachineCycle) {
+                self.cycles.push(*cycle);
+            }
+        }
+
+        #[test]
+        fn step() {
+            let mut i8080 = i8080![0x00, 0x09, 0xE3];
+            assert_eq!(i8080.step(), 4);
+            assert_eq!(i8080.step(), 10);
+            assert_eq!(i8080.step(), 18);
+            assert_eq!(i8080.ticks(), 32);
+        }
+        #[test]
+        fn machine_cycles() {
+            // CALL 0010h; ...; PUSH B
+            let mut i8080 = i8080![0xCD, 0x10, 0x00];
+            i8080.write_u8(0x10, 0xC5);
+            let recorder = Rc::new(RefCell::new(Recorder { cycles: Vec::new() }));
+            i8080.observe(recorder.clone());
+            i8080.step();
+            i8080.step();
+            let cycles = &recorder.borrow().cycles;
+            let summary: Vec<_> = cycles.iter().map(|c| (c.number, c.start, c.states, c.status, c.address, c.data)).collect();
+            let sp = TESTS_DEFAULT_SP;
+            assert_eq!(summary, [
+                (1, 0, 5, bus::INSTRUCTION_FETCH, 0x0000, 0xCD),
+                (2, 5, 3, bus::MEMORY_READ, 0x0001, 0x10),
+                (3, 8, 3, bus::MEMORY_READ, 0x0002, 0x00),
+                (4, 11, 3, bus::STACK_WRITE, sp - 1, 0x00),
+                (5, 14, 3, bus::STACK_WRITE, sp - 2, 0x03),
+                (1, 17, 5, bus::INSTRUCTION_FETCH, 0x0010, 0xC5),
+                (2, 22, 3, bus::STACK_WRITE, sp - 3, 0x00),
+                (3, 25, 3, bus::STACK_WRITE, sp - 4, 0x00),
+            ]);
+            assert!(cycles[0].dbin() && !cycles[0].wr());
+            assert!(cycles[3].wr() && !cycles[3].dbin());
+        }
+
+        struct Dma {
+            requests: usize,
+        }
+
+        impl DmaMaster for Dma {
+            fn hold(&self) -> bool {
+                self.requests > 0
+            }
+
+            fn transfer(&mut self, memory: &mut [u8]) -> usize {
+                self.requests -= 1;
+                memory[0x200] = 0xAA;
+                8
+            }
+        }
+
+        #[test]
+        fn hold() {
+            // LXI D,0200h; LDAX D
+            let mut i8080 = i8080![0x11, 0x00, 0x02, 0x1A];
+            let dma = Rc::new(RefCell::new(Dma { requests: 0 }));
+            let recorder = Rc::new(RefCell::new(Recorder { cycles: Vec::new() }));
+            i8080.attach_dma(dma.clone());
+            i8080.observe(recorder.clone());
+            i8080.cycle();
+            dma.borrow_mut().requests = 1;
+            i8080.cycle();
+            i8080.cycle();
+            i8080.cycle();
+            assert!(!i8080.hlda());
+            i8080.cycle();
+            assert!(i8080.hlda());
+            assert_eq!(i8080.step(), 7);
+            assert_eq!(i8080.ticks(), 10 + 8 + 7);
+            assert_eq!(i8080.a, 0xAA);
+            let starts: Vec<_> = recorder.borrow().cycles.iter().map(|c| c.start).collect();
+            assert_eq!(starts, [0, 4 + 8, 7 + 8, 10 + 8, 14 + 8]);
+        }
+
+        struct SlowPort;
+
+        impl Device for SlowPort {
+            fn input(&mut self, _port: u8) -> u8 {
+                0x00
+            }
+
+            fn output(&mut self, _port: u8, _value: u8) {}
+
+            fn wait_states(&self, _port: u8) -> usize {
+                2
+            }
+        }
+
+        #[test]
+        fn wait_states() {
+            // MVI M,00h; OUT 10h
+            let mut i8080 = i8080![0x36, 0x00, 0xD3, 0x10];
+            i8080.set_register_pair(RegisterPair::H, 0x0300);
+            let mut wait_states = bus::WaitStates::new();
+            wait_states.add_memory(0x0300..=0x03FF, 1);
+            i8080.set_ready(Box::new(wait_states));
+            i8080.attach(0x10..=0x10, Rc::new(RefCell::new(SlowPort)));
+            let recorder = Rc::new(RefCell::new(Recorder { cycles: Vec::new() }));
+            i8080.observe(recorder.clone());
+            assert_eq!(i8080.step(), 10 + 1);
+            assert_eq!(i8080.step(), 10 + 2);
+            let timing: Vec<_> = recorder.borrow().cycles.iter().map(|c| (c.start, c.states, c.wait)).collect();
+            assert_eq!(timing, [(0, 4, 0), (4, 3, 0), (7, 4, 1), (11, 4, 0), (15, 3, 0), (18, 5, 2)]);
+        }
+        #[test]
+        fn interrupt() {
+            // EI; HLT
+            let mut i8080 = i8080![0xFB, 0x76];
+            i8080.step();
+            i8080.step();
+            assert!(i8080.halted());
+            i8080.step();
+            assert_eq!(i8080.pc, 0x0002);
+            i8080.interrupt(0xFF);
+            assert_eq!(i8080.step(), 11);
+            assert_eq!(i8080.bus().status, bus::STACK_WRITE);
+            assert!(!i8080.halted());
+            assert!(!i8080.inte());
+            assert_eq!(i8080.pc, 0x0038);
+            assert_eq!(i8080.read_stack(), 0x0002);
+        }
+    }
+
+    mod state_tests {
+        use super::*;
+        use devices::i8253::I8253;
+
+        // Counter 0 in mode 2 at ports 40h-43h; then INR B; JMP 000Ch.
+        fn machine() -> (I8080, Rc<RefCell<I8253>>) {
+            let mut i8080 = i8080![
+                0x3E, 0x34, 0xD3, 0x43, 0x3E, 0x0A, 0xD3, 0x40, 0xAF, 0xD3, 0x40, 0x00,
+                0x04, 0xC3, 0x0C, 0x00
+            ];
+            let timer = Rc::new(RefCell::new(I8253::new(2_000_000, 1_000_000)));
+            i8080.attach(0x40..=0x43, timer.clone());
+            (i8080, timer)
+        }
+
+        #[test]
+        fn round_trip() {
+            let (mut i8080, _) = machine();
+            for _ in 0..1000 {
+                i8080.cycle();
+            }
+            // Part way through an instruction.
+            i8080.cycle();
+            assert!(i8080.cycles > 0);
+            let saved = i8080.save_state();
+            assert_eq!(&saved[..8], b"I8080SAV");
+            for _ in 0..777 {
+                i8080.cycle();
+            }
+
+            let (mut restored, _) = machine();
+            restored.load_state(&saved).unwrap();
+            assert_eq!(restored.save_state(), saved);
+            for _ in 0..777 {
+                restored.cycle();
+            }
+            assert_eq!(restored.save_state(), i8080.save_state());
+            assert_eq!(restored.b, i8080.b);
+        }
+
+        #[test]
+        fn rejected() {
+            let (mut i8080, _) = machine();
+            i8080.step();
+            let saved = i8080.save_state();
+            let (mut other, _) = machine();
+            let before = other.save_state();
+
+            let mut corrupt = saved.clone();
+            *corrupt.last_mut().unwrap() ^= 0xFF;
+            assert!(other.load_state(&corrupt).unwrap_err().to_string().contains("checksum"));
+            assert!(other.load_state(&saved[..20]).is_err());
+
+            let mut bigger = I8080::new(2048);
+            assert!(bigger.load_state(&saved).unwrap_err().to_string().contains("memory"));
+            let mut bare = I8080::new(TESTS_DEFAULT_MEMORY_SIZE);
+            assert!(bare.load_state(&saved).unwrap_err().to_string().contains("devices"));
+            assert_eq!(bare.save_state(), I8080::new(TESTS_DEFAULT_MEMORY_SIZE).save_state());
+            assert_eq!(other.save_state(), before);
+        }
+    }
 }
@@ -1,3 +1,294 @@
-fn main() {
-    println!("Hello, world!");
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
+
+use i8080_rs::asm::assemble;
+use i8080_rs::devices::endpoint::Buffer;
+use i8080_rs::devices::i8251::I8251;
+use i8080_rs::disasm::source;
+use i8080_rs::image::Image;
+use i8080_rs::monitor::Monitor;
+use i8080_rs::runner::{Outcome, Runner};
+use i8080_rs::symbols::Symbols;
+use i8080_rs::tui::Tui;
+use i8080_rs::I8080;
+
+const USAGE: &str = "usage: i8080_rs [file]
+       i8080_rs tui [file]
+       i8080_rs run file [options]
+       i8080_rs asm source [options]
+       i8080_rs disasm file [options]
+
+Starts the monitor, loading file (.COM, .HEX or binary) first if given.
+Type Q to leave it.
+
+tui starts the full-screen debugger instead, with an 8251 console on ports
+00h and 01h. Press q to leave it.
+
+run runs file without a user interface until it halts. Options:
+  --org address       where a binary or .COM file loads (0100h)
+  --entry address     where to start (the .HEX entry point, else the origin)
+  --max-cycles n      stop after n T-states
+  --exit-port port    stop when the program writes to port
+  --console-port port read stdin and write stdout through port
+  --cpm               trap CP/M BDOS console calls (the default for .COM)
+  --undocumented      run undocumented opcodes instead of stopping
+Numbers are decimal, or hex with 0x or h. The exit status is 0 after HLT or
+a return to CP/M, the value written to the exit port, 124 on timeout and
+132 on an undocumented opcode.
+
+asm assembles source into .bin, .hex, .lst and .sym files. Options:
+  --org address       where assembly starts before any ORG (0)
+  --entry address     entry point for the .hex file (the END operand)
+  --symbols file      .sym file of names to use as if given with EQU
+  -o base             path of the output files without extension (the
+                      source's)
+
+disasm writes a binary, .COM or .HEX file as source that assembles back to
+it. Options:
+  --org address       where a binary or .COM file loads (0100h)
+  --entry address     trace code from here; can be given more than once.
+                      Without it, everything outside --data is code
+  --symbols file      .sym file of names for addresses
+  --data start-end    bytes to write as DB; can be given more than once
+  -o file             where to write the source (standard output)";
+
+// The monitor, with 64K of memory and the program counter at 0100h as for a
+// CP/M program.
+fn load(file: Option<&str>, output: &mut dyn Write) -> io::Result<Monitor> {
+    let mut i8080 = I8080::new(0x10000);
+    i8080.set_pc(0x0100);
+    let mut monitor = Monitor::new(i8080);
+    if let Some(file) = file {
+        for command in [format!("I{file}"), "R".to_string()] {
+            if let Err(error) = monitor.command(&command, &mut io::empty(), output) {
+                writeln!(output, "? {error}")?;
+                break;
+            }
+        }
+    }
+    Ok(monitor)
+}
+
+fn monitor(file: Option<&str>) -> io::Result<()> {
+    let mut output = io::stdout().lock();
+    writeln!(output, "i8080_rs monitor")?;
+    let mut monitor = load(file, &mut output)?;
+    monitor.run(&mut io::stdin().lock(), &mut output)
+}
+
+// Runs stty on the terminal, returning what it prints.
+fn stty(arguments: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(arguments).stdin(Stdio::inherit()).stderr(Stdio::inherit()).output()?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => Err(io::Error::other("standard input is not a terminal")),
+    }
+}
+
+fn tui(file: Option<&str>) -> io::Result<()> {
+    let mut loaded = Vec::new();
+    let mut monitor = load(file, &mut loaded)?;
+    if let Some(error) = String::from_utf8_lossy(&loaded).lines().find(|line| line.starts_with('?')) {
+        return Err(io::Error::other(error[1..].trim().to_string()));
+    }
+    let console = Buffer::new();
+    let usart = I8251::new(Box::new(console.clone()), 2_000_000, 9600 * 16);
+    monitor.i8080_mut().attach(0x00..=0x01, Rc::new(RefCell::new(usart)));
+
+    // Keys arrive one at a time, without echo; ^C still ends the program.
+    let saved = stty(&["-g"])?;
+    stty(&["-icanon", "-echo", "min", "1"])?;
+    let (sender, keys) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) if sender.send(byte).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+    let result = Tui::new(monitor, console).run(&keys, &mut io::stdout().lock());
+    stty(&[&saved])?;
+    result
+}
+
+fn usage(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Decimal, 0x1234 or 1234h.
+fn number(text: &str, max: u64) -> io::Result<u64> {
+    let lower = text.to_ascii_lowercase();
+    let value = match (lower.strip_prefix("0x"), lower.strip_suffix('h')) {
+        (Some(digits), _) | (None, Some(digits)) => u64::from_str_radix(digits, 16),
+        (None, None) => lower.parse(),
+    };
+    value.ok().filter(|&value| value <= max).ok_or_else(|| usage(format!("bad number {text}")))
+}
+
+fn address(text: &str) -> io::Result<u16> {
+    Ok(number(text, 0xFFFF)? as u16)
+}
+
+// Options with their values, in the order given.
+type Options<'a> = Vec<(&'a str, &'a str)>;
+
+// Splits arguments into files and options. Options in `flags` take no value.
+fn options<'a>(arguments: &[&'a str], flags: &[&str]) -> io::Result<(Vec<&'a str>, Options<'a>)> {
+    let (mut files, mut options) = (Vec::new(), Vec::new());
+    let mut arguments = arguments.iter();
+    while let Some(&argument) = arguments.next() {
+        if !argument.starts_with('-') {
+            files.push(argument);
+        } else if flags.contains(&argument) {
+            options.push((argument, ""));
+        } else {
+            let value = arguments.next().ok_or_else(|| usage(format!("{argument} needs a value")))?;
+            options.push((argument, *value));
+        }
+    }
+    Ok((files, options))
+}
+
+fn one<'a>(command: &str, files: &[&'a str]) -> io::Result<&'a str> {
+    match files {
+        [file] => Ok(file),
+        _ => Err(usage(format!("{command} takes one file"))),
+    }
+}
+
+fn run(arguments: &[&str]) -> io::Result<Outcome> {
+    let (files, options) = options(arguments, &["--cpm", "--undocumented"])?;
+    let path = Path::new(one("run", &files)?);
+    let (mut origin, mut entry, mut max_cycles) = (0x0100, None, None);
+    let (mut exit_port, mut console_port) = (None, None);
+    let mut cpm = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("com"));
+    let mut undocumented = false;
+    for (option, value) in options {
+        match option {
+            "--org" => origin = address(value)?,
+            "--entry" => entry = Some(address(value)?),
+            "--max-cycles" => max_cycles = Some(number(value, u64::MAX)?),
+            "--exit-port" => exit_port = Some(number(value, 0xFF)? as u8),
+            "--console-port" => console_port = Some(number(value, 0xFF)? as u8),
+            "--cpm" => cpm = true,
+            "--undocumented" => undocumented = true,
+            _ => return Err(usage(format!("unknown option {option}"))),
+        }
+    }
+
+    let image = Image::load(path, origin, 0)?;
+    let mut i8080 = I8080::new(0x10000);
+    image.write_to(&mut i8080)?;
+    i8080.set_pc(entry.or(image.entry).unwrap_or(origin));
+    let mut runner = Runner::new(i8080, Box::new(io::stdin()), Box::new(io::stdout()));
+    runner.set_max_cycles(max_cycles);
+    runner.set_undocumented(undocumented);
+    if let Some(port) = exit_port {
+        runner.set_exit_port(port);
+    }
+    if let Some(port) = console_port {
+        runner.set_console_port(port);
+    }
+    if cpm {
+        runner.set_cpm();
+    }
+    runner.run()
+}
+
+fn asm(arguments: &[&str]) -> io::Result<()> {
+    let (files, options) = options(arguments, &[])?;
+    let file = one("asm", &files)?;
+    let (mut origin, mut entry, mut predefined) = (0, None, Symbols::new());
+    let mut base = Path::new(file).with_extension("");
+    for (option, value) in options {
+        match option {
+            "--org" => origin = address(value)?,
+            "--entry" => entry = Some(address(value)?),
+            "--symbols" => predefined = Symbols::load(Path::new(value))?,
+            "-o" => base = PathBuf::from(value),
+            _ => return Err(usage(format!("unknown option {option}"))),
+        }
+    }
+    let source = fs::read_to_string(file)?;
+    let mut assembly = assemble(&source, origin, &predefined).map_err(|error| {
+        let lines: Vec<_> = error.to_string().lines().map(|line| format!("{file} {line}")).collect();
+        io::Error::new(error.kind(), lines.join("\n"))
+    })?;
+    assembly.image.entry = entry.or(assembly.image.entry);
+    fs::write(base.with_extension("bin"), assembly.image.to_binary().1)?;
+    fs::write(base.with_extension("hex"), assembly.image.to_hex())?;
+    fs::write(base.with_extension("lst"), &assembly.listing)?;
+    fs::write(base.with_extension("sym"), assembly.symbols.to_sym())
+}
+
+fn disasm(arguments: &[&str]) -> io::Result<()> {
+    let (files, options) = options(arguments, &[])?;
+    let file = one("disasm", &files)?;
+    let (mut origin, mut entries, mut data) = (0x0100, Vec::new(), Vec::new());
+    let (mut symbols, mut output) = (Symbols::new(), None);
+    for (option, value) in options {
+        match option {
+            "--org" => origin = address(value)?,
+            "--entry" => entries.push(address(value)?),
+            "--symbols" => symbols = Symbols::load(Path::new(value))?,
+            "--data" => {
+                let (start, end) = value.split_once('-').ok_or_else(|| usage(format!("bad range {value}")))?;
+                data.push(address(start)?..=address(end)?);
+            }
+            "-o" => output = Some(value),
+            _ => return Err(usage(format!("unknown option {option}"))),
+        }
+    }
+    let image = Image::load(Path::new(file), origin, 0)?;
+    if entries.is_empty() {
+        entries.extend(image.entry);
+    }
+    let (start, bytes) = image.to_binary();
+    let text = source(&bytes, start, &entries, &data, &symbols);
+    match output {
+        Some(path) => fs::write(path, text),
+        None => io::stdout().write_all(text.as_bytes()),
+    }
+}
+
+fn main() -> ExitCode {
+    let arguments: Vec<String> = env::args().skip(1).collect();
+    let result = match arguments.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => monitor(None).map(|_| ExitCode::SUCCESS),
+        ["-h" | "--help"] => {
+            println!("{USAGE}");
+            Ok(ExitCode::SUCCESS)
+        }
+        ["tui"] => tui(None).map(|_| ExitCode::SUCCESS),
+        ["tui", file] => tui(Some(file)).map(|_| ExitCode::SUCCESS),
+        ["run", ref arguments @ ..] => run(arguments).map(|outcome| {
+            if matches!(outcome, Outcome::Timeout(_) | Outcome::InvalidOpcode { .. }) {
+                eprintln!("i8080_rs: {outcome}");
+            }
+            ExitCode::from(outcome.code())
+        }),
+        ["asm", ref arguments @ ..] => asm(arguments).map(|_| ExitCode::SUCCESS),
+        ["disasm", ref arguments @ ..] => disasm(arguments).map(|_| ExitCode::SUCCESS),
+        [file] if !file.starts_with('-') => monitor(Some(file)).map(|_| ExitCode::SUCCESS),
+        _ => Err(usage("bad arguments".to_string())),
+    };
+    match result {
+        Ok(code) => code,
+        Err(error) if error.kind() == io::ErrorKind::InvalidInput => {
+            eprintln!("i8080_rs: {error}\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(error) => {
+            eprintln!("i8080_rs: {error}");
+            ExitCode::FAILURE
+        }
+    }
 }
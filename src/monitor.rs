@@ -0,0 +1,490 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use crate::asm;
+use crate::disasm;
+use crate::image::Image;
+use crate::symbols::Symbols;
+use crate::{Registers, I8080};
+
+const FLAGS: [(char, u8); 5] = [
+    ('C', Registers::CARRY),
+    ('Z', Registers::ZERO),
+    ('M', Registers::SIGN),
+    ('E', Registers::PARITY),
+    ('I', Registers::AUXILIARY_CARRY),
+];
+
+fn bad(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// A line-oriented monitor in the manner of CP/M's DDT and SID. Numbers are
+// hex and .NAME is the value of a symbol. Commands:
+//   A[s]        assemble at s, a line at a time, until an empty line or .
+//   D[s][,e]    dump memory in hex and ASCII
+//   F s,e,v     fill s to e with v
+//   G[s][,b..]  go from s until a pass point, a temporary breakpoint b or HLT
+//   H a,b       a+b and a-b
+//   I name      name the file for R
+//   R[bias]     read the file: .HEX at its addresses, .SYM as symbols, any
+//               other as a .COM program at 0100h, all moved up by bias
+//   L[s][,e]    list disassembly
+//   M s,e,d     move s to e to d
+//   P[a] -P[a]  set, or with -, clear a pass point; P alone lists them
+//   S[s]        substitute memory a byte at a time, until .
+//   T[n] U[n]   trace n instructions, showing the registers before each; U
+//               shows them only before the first
+//   X[r]        show the registers, or change register or flag r
+//   Q           quit
+pub struct Monitor {
+    i8080: I8080,
+    symbols: Symbols,
+    pass_points: BTreeSet<u16>,
+    file: Option<PathBuf>,
+    assemble: u16, // where the A, D, L and S commands carry on from
+    dump: u16,
+    list: u16,
+    substitute: u16,
+}
+
+impl Monitor {
+    pub fn new(i8080: I8080) -> Self {
+        let pc = i8080.pc();
+        Self {
+            i8080,
+            symbols: Symbols::new(),
+            pass_points: BTreeSet::new(),
+            file: None,
+            assemble: pc,
+            dump: pc,
+            list: pc,
+            substitute: pc,
+        }
+    }
+
+    pub fn i8080(&self) -> &I8080 {
+        &self.i8080
+    }
+
+    pub fn i8080_mut(&mut self) -> &mut I8080 {
+        &mut self.i8080
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn pass_points(&self) -> &BTreeSet<u16> {
+        &self.pass_points
+    }
+
+    // Reads commands until Q or the end of the input.
+    pub fn run(&mut self, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+        loop {
+            write!(output, "-")?;
+            output.flush()?;
+            let Some(line) = read_line(input)? else {
+                return Ok(());
+            };
+            match self.command(&line, input, output) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(error) if error.kind() == io::ErrorKind::InvalidInput => writeln!(output, "? {error}")?,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    // Runs one command line, reading any further lines it asks for from
+    // `input`. False once the command was Q.
+    pub fn command(&mut self, line: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<bool> {
+        let line = line.trim();
+        let (clear, line) = match line.strip_prefix('-') {
+            Some(line) => (true, line.trim_start()),
+            None => (false, line),
+        };
+        let Some(letter) = line.chars().next() else {
+            return Ok(true);
+        };
+        let rest = line[letter.len_utf8()..].trim();
+        match (letter.to_ascii_uppercase(), clear) {
+            ('A', false) => self.assemble(rest, input, output)?,
+            ('D', false) => self.dump(rest, output)?,
+            ('F', false) => {
+                let [start, end, value] = self.required::<3>(rest)?;
+                let value = asm::byte(value).map_err(bad)?;
+                for address in start..=end {
+                    self.i8080.poke(address, value);
+                }
+            }
+            ('G', false) => self.go(rest, output)?,
+            ('H', false) => {
+                let [a, b] = self.required::<2>(rest)?;
+                writeln!(output, "{:04X} {:04X}", a.wrapping_add(b), a.wrapping_sub(b))?;
+            }
+            ('I', false) if !rest.is_empty() => self.file = Some(PathBuf::from(rest)),
+            ('R', false) => self.read(rest, output)?,
+            ('L', false) => self.disassemble(rest, output)?,
+            ('M', false) => {
+                let [start, end, destination] = self.required::<3>(rest)?;
+                let bytes: Vec<u8> = (start..=end).map(|address| self.i8080.peek(address)).collect();
+                for (offset, value) in bytes.into_iter().enumerate() {
+                    self.i8080.poke(destination.wrapping_add(offset as u16), value);
+                }
+            }
+            ('P', false) if rest.is_empty() => {
+                for &address in &self.pass_points {
+                    writeln!(output, "{address:04X} {}", self.symbols.name(address).unwrap_or(""))?;
+                }
+            }
+            ('P', false) => {
+                self.pass_points.insert(self.value(rest)?);
+            }
+            ('P', true) if rest.is_empty() => self.pass_points.clear(),
+            ('P', true) => {
+                let address = self.value(rest)?;
+                if !self.pass_points.remove(&address) {
+                    return Err(bad(format!("no pass point at {address:04X}")));
+                }
+            }
+            ('S', false) => self.substitute(rest, input, output)?,
+            ('T', false) | ('U', false) => {
+                let count = match rest {
+                    "" => 1,
+                    rest => self.value(rest)?,
+                };
+                self.trace(count, letter.eq_ignore_ascii_case(&'T'), output)?;
+            }
+            ('X', false) if rest.is_empty() => writeln!(output, "{}", self.registers())?,
+            ('X', false) => self.examine(rest, input, output)?,
+            ('Q', false) => return Ok(false),
+            _ => return Err(bad(format!("unknown command {line}"))),
+        }
+        Ok(true)
+    }
+
+    // The registers as DDT shows them, with the next instruction.
+    pub fn registers(&self) -> String {
+        let registers = self.i8080.registers();
+        let flags: String = FLAGS.iter().map(|&(name, mask)| format!("{name}{}", (registers.flags & mask != 0) as u8)).collect();
+        let pair = |high: u8, low: u8| u16::from_be_bytes([high, low]);
+        format!(
+            "{flags} A={:02X} B={:04X} D={:04X} H={:04X} S={:04X} P={:04X} {}",
+            registers.a,
+            pair(registers.b, registers.c),
+            pair(registers.d, registers.e),
+            pair(registers.h, registers.l),
+            registers.sp,
+            registers.pc,
+            self.instruction(registers.pc).text
+        )
+    }
+
+    fn instruction(&self, address: u16) -> disasm::Instruction {
+        let bytes = [0, 1, 2].map(|offset| self.i8080.peek(address.wrapping_add(offset)));
+        disasm::decode(bytes, Some(&self.symbols))
+    }
+
+    // A number in hex or a symbol, .NAME, or a sum of them.
+    fn value(&self, text: &str) -> io::Result<u16> {
+        let lookup = |name: &str| self.symbols.address(name).or_else(|| u16::from_str_radix(name, 16).ok());
+        asm::evaluate(&text.replace('.', ""), 0, 16, &lookup).map_err(bad)
+    }
+
+    fn arguments(&self, text: &str) -> io::Result<Vec<Option<u16>>> {
+        if text.is_empty() {
+            return Ok(Vec::new());
+        }
+        text.split(',').map(|text| if text.trim().is_empty() { Ok(None) } else { self.value(text).map(Some) }).collect()
+    }
+
+    fn required<const N: usize>(&self, text: &str) -> io::Result<[u16; N]> {
+        let arguments: Option<Vec<u16>> = self.arguments(text)?.into_iter().collect();
+        arguments.and_then(|arguments| arguments.try_into().ok()).ok_or_else(|| bad(format!("{N} values needed")))
+    }
+
+    fn assemble(&mut self, text: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+        if !text.is_empty() {
+            self.assemble = self.value(text)?;
+        }
+        loop {
+            write!(output, "{:04X} ", self.assemble)?;
+            output.flush()?;
+            let line = match read_line(input)? {
+                Some(line) if !line.trim().is_empty() && line.trim() != "." => line,
+                _ => return Ok(()),
+            };
+            let lookup = |name: &str| self.symbols.address(name).or_else(|| u16::from_str_radix(name, 16).ok());
+            match asm::assemble_line(&line, self.assemble, 16, &lookup) {
+                Ok(bytes) => {
+                    for value in bytes {
+                        self.i8080.poke(self.assemble, value);
+                        self.assemble = self.assemble.wrapping_add(1);
+                    }
+                }
+                Err(error) => writeln!(output, "? {error}")?,
+            }
+        }
+    }
+
+    fn dump(&mut self, text: &str, output: &mut dyn Write) -> io::Result<()> {
+        let arguments = self.arguments(text)?;
+        let start = arguments.first().copied().flatten().unwrap_or(self.dump);
+        let end = arguments.get(1).copied().flatten().unwrap_or(start.saturating_add(0xBF));
+        let mut address = start;
+        loop {
+            let last = end.min(address.saturating_add(15));
+            let bytes: Vec<u8> = (address..=last).map(|address| self.i8080.peek(address)).collect();
+            let hex: Vec<_> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+            let ascii: String = bytes.iter().map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+            writeln!(output, "{address:04X} {:<47} {ascii}", hex.join(" "))?;
+            if last >= end {
+                self.dump = last.wrapping_add(1);
+                return Ok(());
+            }
+            address = last + 1;
+        }
+    }
+
+    fn disassemble(&mut self, text: &str, output: &mut dyn Write) -> io::Result<()> {
+        let arguments = self.arguments(text)?;
+        let mut address = arguments.first().copied().flatten().unwrap_or(self.list);
+        let end = arguments.get(1).copied().flatten();
+        for count in 0.. {
+            match end {
+                Some(end) if address > end => break,
+                None if count == 12 => break,
+                _ => {}
+            }
+            if let Some(name) = self.symbols.name(address) {
+                writeln!(output, "{name}:")?;
+            }
+            let instruction = self.instruction(address);
+            writeln!(output, "  {address:04X}  {}", instruction.text)?;
+            let next = address.wrapping_add(instruction.length as u16);
+            if next < address {
+                break;
+            }
+            address = next;
+        }
+        self.list = address;
+        Ok(())
+    }
+
+    fn go(&mut self, text: &str, output: &mut dyn Write) -> io::Result<()> {
+        let arguments = self.arguments(text)?;
+        if let Some(start) = arguments.first().copied().flatten() {
+            self.i8080.set_pc(start);
+            self.i8080.set_halted(false);
+        }
+        let breakpoints: Vec<u16> = arguments.iter().skip(1).flatten().copied().collect();
+        loop {
+            self.i8080.step();
+            let pc = self.i8080.pc();
+            if self.i8080.halted() {
+                writeln!(output, "*{:04X} HLT", pc.wrapping_sub(1))?;
+                break;
+            }
+            if breakpoints.contains(&pc) || self.pass_points.contains(&pc) {
+                writeln!(output, "*{}", self.location(pc))?;
+                break;
+            }
+        }
+        self.carry_on();
+        Ok(())
+    }
+
+    fn trace(&mut self, count: u16, every: bool, output: &mut dyn Write) -> io::Result<()> {
+        for step in 0..count {
+            if step == 0 || every {
+                writeln!(output, "{}", self.registers())?;
+            }
+            self.i8080.step();
+            if self.i8080.halted() {
+                writeln!(output, "*{:04X} HLT", self.i8080.pc().wrapping_sub(1))?;
+                self.carry_on();
+                return Ok(());
+            }
+            if self.pass_points.contains(&self.i8080.pc()) {
+                break;
+            }
+        }
+        writeln!(output, "*{}", self.location(self.i8080.pc()))?;
+        self.carry_on();
+        Ok(())
+    }
+
+    // After running, D and L carry on from where the program stopped.
+    fn carry_on(&mut self) {
+        self.list = self.i8080.pc();
+        self.dump = self.i8080.pc();
+    }
+
+    fn location(&self, address: u16) -> String {
+        match self.symbols.name(address) {
+            Some(name) => format!("{address:04X} .{name}"),
+            None => format!("{address:04X}"),
+        }
+    }
+
+    fn read(&mut self, text: &str, output: &mut dyn Write) -> io::Result<()> {
+        let path = self.file.clone().ok_or_else(|| bad("no file named; use I first".to_string()))?;
+        let bias = if text.is_empty() { 0 } else { self.value(text)? };
+        let failed = |error: io::Error| bad(format!("{}: {error}", path.display()));
+        if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("sym")) {
+            let symbols = Symbols::load(&path).map_err(failed)?;
+            for (address, name) in symbols.iter() {
+                self.symbols.insert(name, address.wrapping_add(bias));
+            }
+            writeln!(output, "SYMBOLS")?;
+            return Ok(());
+        }
+        let image = Image::load(&path, 0x0100u16.wrapping_add(bias), bias).map_err(failed)?;
+        image.write_to(&mut self.i8080).map_err(failed)?;
+        let pc = image.entry.or(image.segments.first().map(|(origin, _)| *origin)).unwrap_or(0);
+        self.i8080.set_pc(pc);
+        self.i8080.set_halted(false);
+        self.assemble = pc;
+        self.carry_on();
+        writeln!(output, "NEXT  PC")?;
+        writeln!(output, "{:04X} {pc:04X}", image.end())?;
+        Ok(())
+    }
+
+    fn substitute(&mut self, text: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+        if !text.is_empty() {
+            self.substitute = self.value(text)?;
+        }
+        loop {
+            write!(output, "{:04X} {:02X} ", self.substitute, self.i8080.peek(self.substitute))?;
+            output.flush()?;
+            let line = match read_line(input)? {
+                Some(line) if line.trim() != "." => line,
+                _ => return Ok(()),
+            };
+            if !line.trim().is_empty() {
+                match self.value(&line).and_then(|value| asm::byte(value).map_err(bad)) {
+                    Ok(value) => self.i8080.poke(self.substitute, value),
+                    Err(error) => {
+                        writeln!(output, "? {error}")?;
+                        continue;
+                    }
+                }
+            }
+            self.substitute = self.substitute.wrapping_add(1);
+        }
+    }
+
+    fn examine(&mut self, name: &str, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<()> {
+        let name = name.to_ascii_uppercase();
+        let mut registers = self.i8080.registers();
+        if let Some(&(flag, mask)) = FLAGS.iter().find(|(flag, _)| name.len() == 1 && name.starts_with(*flag)) {
+            write!(output, "{flag}{} ", (registers.flags & mask != 0) as u8)?;
+            output.flush()?;
+            match read_line(input)?.as_deref().map(str::trim) {
+                None | Some("") => {}
+                Some("0") => registers.flags &= !mask,
+                Some("1") => registers.flags |= mask,
+                Some(value) => return Err(bad(format!("{value} is not 0 or 1"))),
+            }
+            self.i8080.set_registers(registers);
+            return Ok(());
+        }
+        let current = match name.as_str() {
+            "A" => registers.a as u16,
+            "B" => u16::from_be_bytes([registers.b, registers.c]),
+            "D" => u16::from_be_bytes([registers.d, registers.e]),
+            "H" => u16::from_be_bytes([registers.h, registers.l]),
+            "S" => registers.sp,
+            "P" => registers.pc,
+            _ => return Err(bad(format!("no register {name}"))),
+        };
+        match name.as_str() {
+            "A" => write!(output, "A={current:02X} ")?,
+            _ => write!(output, "{name}={current:04X} ")?,
+        }
+        output.flush()?;
+        let value = match read_line(input)? {
+            Some(line) if !line.trim().is_empty() => self.value(&line)?,
+            _ => return Ok(()),
+        };
+        let [high, low] = value.to_be_bytes();
+        match name.as_str() {
+            "A" => registers.a = asm::byte(value).map_err(bad)?,
+            "B" => (registers.b, registers.c) = (high, low),
+            "D" => (registers.d, registers.e) = (high, low),
+            "H" => (registers.h, registers.l) = (high, low),
+            "S" => registers.sp = value,
+            _ => registers.pc = value,
+        }
+        self.i8080.set_registers(registers);
+        Ok(())
+    }
+}
+
+fn read_line(input: &mut dyn BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    match input.read_line(&mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line.trim_end_matches(['\r', '\n']).to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn session(monitor: &mut Monitor, commands: &str) -> String {
+        let mut output = Vec::new();
+        monitor.run(&mut Cursor::new(commands), &mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn assemble_and_go() {
+        let mut monitor = Monitor::new(I8080::new(0x10000));
+        monitor.set_symbols(Symbols::parse("0110 COUNT").unwrap());
+        let output = session(
+            &mut monitor,
+            "A100\nMVI B,3\nLOOP: DCR B\nDCR B\nJNZ 102\nHLT\n.\nL100,107\nP.COUNT-0E\nG100\nX\nT2\n-P102\nG\nD.COUNT,113\n",
+        );
+        let expected = [
+            "-0100 0102 ? unknown instruction LOOP:",
+            "0102 0103 0106 0107 -  0100  MVI B,03H",
+            "  0102  DCR B",
+            "  0103  JNZ 0102H",
+            "  0106  HLT",
+            "  0107  NOP",
+            "--*0102",
+            "-C0Z0M0E0I0 A=00 B=0300 D=0000 H=0000 S=0000 P=0102 DCR B",
+            "-C0Z0M0E0I0 A=00 B=0300 D=0000 H=0000 S=0000 P=0102 DCR B",
+            "C0Z0M0E0I0 A=00 B=0200 D=0000 H=0000 S=0000 P=0103 JNZ 0102H",
+            "*0102",
+            "--*0106 HLT",
+            "-0110 00 00 00 00                                     ....",
+            "-",
+        ];
+        assert_eq!(output.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn substitute_and_registers() {
+        let mut monitor = Monitor::new(I8080::new(0x10000));
+        let output = session(&mut monitor, "S200\n12\n\nZZ\n34\n.\nXB\n1234\nXC\n1\nXA\n100\nF300,302,AA\nM300,302,301\nH10,20\nQ\nX\n");
+        assert_eq!(monitor.i8080().peek(0x0200), 0x12);
+        assert_eq!(monitor.i8080().peek(0x0202), 0x34);
+        assert_eq!([0x300, 0x301, 0x302, 0x303].map(|address| monitor.i8080().peek(address)), [0xAA, 0xAA, 0xAA, 0xAA]);
+        let registers = monitor.i8080().registers();
+        assert_eq!((registers.b, registers.c, registers.flags & Registers::CARRY), (0x12, 0x34, Registers::CARRY));
+        assert!(output.contains("0202 00 ? undefined symbol ZZ\n0202 00 0203 00 "));
+        assert!(output.contains("A=00 ? 0100h does not fit in a byte"));
+        assert!(output.ends_with("0030 FFF0\n-"));
+    }
+}
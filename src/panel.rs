@@ -0,0 +1,261 @@
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::bus::{self, MachineCycle};
+use crate::io::Device;
+use crate::I8080;
+
+const STATUS_LEDS: [(&str, u8); 8] = [
+    ("MEMR", bus::MEMR),
+    ("INP", bus::INP),
+    ("M1", bus::M1),
+    ("OUT", bus::OUT),
+    ("HLTA", bus::HLTA),
+    ("STACK", bus::STACK),
+    ("WO", bus::WO),
+    ("INT", bus::INTA),
+];
+
+// Altair 8800 front panel. The switches are shared with `SenseSwitches` so
+// programs can read the upper eight of them with IN 0FFh.
+pub struct FrontPanel {
+    switches: Rc<Cell<u16>>,
+    running: bool,
+    leds: MachineCycle,
+    inte: bool,
+    hlda: bool,
+}
+
+impl FrontPanel {
+    pub fn new() -> Self {
+        Self {
+            switches: Rc::new(Cell::new(0)),
+            running: false,
+            leds: MachineCycle::default(),
+            inte: false,
+            hlda: false,
+        }
+    }
+
+    pub fn switches(&self) -> u16 {
+        self.switches.get()
+    }
+
+    pub fn set_switches(&mut self, switches: u16) {
+        self.switches.set(switches);
+    }
+
+    pub fn sense_switches(&self) -> SenseSwitches {
+        SenseSwitches { switches: self.switches.clone() }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn examine(&mut self, i8080: &mut I8080) {
+        if !self.running {
+            i8080.set_pc(self.switches());
+            self.latch(i8080);
+        }
+    }
+
+    pub fn examine_next(&mut self, i8080: &mut I8080) {
+        if !self.running {
+            i8080.set_pc(i8080.pc().wrapping_add(1));
+            self.latch(i8080);
+        }
+    }
+
+    pub fn deposit(&mut self, i8080: &mut I8080) {
+        if !self.running {
+            i8080.poke(i8080.pc(), self.switches() as u8);
+            self.latch(i8080);
+        }
+    }
+
+    pub fn deposit_next(&mut self, i8080: &mut I8080) {
+        if !self.running {
+            i8080.set_pc(i8080.pc().wrapping_add(1));
+            self.deposit(i8080);
+        }
+    }
+
+    pub fn run(&mut self) {
+        self.running = true;
+    }
+
+    pub fn stop(&mut self, i8080: &mut I8080) {
+        self.running = false;
+        self.latch(i8080);
+    }
+
+    pub fn single_step(&mut self, i8080: &mut I8080) {
+        if !self.running {
+            i8080.step();
+            self.latch(i8080);
+        }
+    }
+
+    pub fn reset(&mut self, i8080: &mut I8080) {
+        i8080.reset();
+        self.latch(i8080);
+    }
+
+    // Runs the CPU for `cycles` T-states while RUN is on, lighting the LEDs from its bus.
+    pub fn clock(&mut self, i8080: &mut I8080, cycles: usize) {
+        if self.running {
+            for _ in 0..cycles {
+                i8080.cycle();
+            }
+            self.leds = i8080.bus();
+            self.inte = i8080.inte();
+            self.hlda = i8080.hlda();
+        }
+    }
+
+    pub fn address_leds(&self) -> u16 {
+        self.leds.address
+    }
+
+    pub fn data_leds(&self) -> u8 {
+        self.leds.data
+    }
+
+    pub fn status_leds(&self) -> u8 {
+        self.leds.status
+    }
+
+    pub fn inte_led(&self) -> bool {
+        self.inte
+    }
+
+    pub fn wait_led(&self) -> bool {
+        !self.running
+    }
+
+    pub fn hlda_led(&self) -> bool {
+        self.hlda
+    }
+
+    // A stopped 8080 sits in the fetch of the next instruction with READY low.
+    fn latch(&mut self, i8080: &I8080) {
+        let pc = i8080.pc();
+        let status = if i8080.halted() { bus::HALT_ACKNOWLEDGE } else { bus::INSTRUCTION_FETCH };
+        self.leds = MachineCycle { status, address: pc, data: i8080.peek(pc), ..Default::default() };
+        self.inte = i8080.inte();
+        self.hlda = i8080.hlda();
+    }
+}
+
+impl Default for FrontPanel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn led(on: bool) -> char {
+    if on { '*' } else { '.' }
+}
+
+impl fmt::Display for FrontPanel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "INTE WAIT HLDA ")?;
+        for (name, _) in STATUS_LEDS {
+            write!(f, "{name} ")?;
+        }
+        writeln!(f, "  D7 D6 D5 D4 D3 D2 D1 D0")?;
+        write!(f, "{:^4} {:^4} {:^4} ", led(self.inte_led()), led(self.wait_led()), led(self.hlda_led()))?;
+        for (name, bit) in STATUS_LEDS {
+            write!(f, "{:^width$} ", led(self.status_leds() & bit != 0), width = name.len())?;
+        }
+        write!(f, " ")?;
+        for bit in (0..8).rev() {
+            write!(f, " {} ", led(self.data_leds() & (1 << bit) != 0))?;
+        }
+        writeln!(f)?;
+        writeln!(f, "A15 A14 A13 A12 A11 A10 A9  A8  A7  A6  A5  A4  A3  A2  A1  A0")?;
+        for bit in (0..16).rev() {
+            write!(f, " {}  ", led(self.address_leds() & (1 << bit) != 0))?;
+        }
+        writeln!(f)?;
+        for bit in (0..16).rev() {
+            write!(f, " {}  ", if self.switches() & (1 << bit) != 0 { '^' } else { 'v' })?;
+        }
+        writeln!(f)
+    }
+}
+
+pub struct SenseSwitches {
+    switches: Rc<Cell<u16>>,
+}
+
+impl Device for SenseSwitches {
+    fn input(&mut self, _port: u8) -> u8 {
+        (self.switches.get() >> 8) as u8
+    }
+
+    fn output(&mut self, _port: u8, _value: u8) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn examine_deposit() {
+        let mut i8080 = I8080::new(0x100);
+        let mut panel = FrontPanel::new();
+        panel.set_switches(0x0040);
+        panel.examine(&mut i8080);
+        panel.set_switches(0x00DB);
+        panel.deposit(&mut i8080);
+        panel.set_switches(0x00FF);
+        panel.deposit_next(&mut i8080);
+        assert_eq!(i8080.peek(0x40), 0xDB);
+        assert_eq!(i8080.peek(0x41), 0xFF);
+        assert_eq!(panel.address_leds(), 0x41);
+        assert_eq!(panel.data_leds(), 0xFF);
+        assert_eq!(panel.status_leds(), bus::MEMR | bus::M1 | bus::WO);
+        panel.set_switches(0x0040);
+        panel.examine(&mut i8080);
+        panel.examine_next(&mut i8080);
+        assert_eq!(panel.data_leds(), 0xFF);
+
+        // Nothing is fitted above 0100h, and FFFFh wraps to 0000h.
+        panel.set_switches(0xFFFF);
+        panel.examine(&mut i8080);
+        panel.deposit(&mut i8080);
+        assert_eq!(panel.data_leds(), 0xFF);
+        panel.set_switches(0x0012);
+        panel.deposit_next(&mut i8080);
+        assert_eq!((panel.address_leds(), panel.data_leds()), (0x0000, 0x12));
+    }
+
+    #[test]
+    fn run_sense_switches() {
+        // IN 0FFh; OUT 10h; HLT
+        let mut i8080 = I8080::new(0x100);
+        for (location, value) in [0xDB, 0xFF, 0xD3, 0x10, 0x76].into_iter().enumerate() {
+            i8080.poke(location as u16, value);
+        }
+        let mut panel = FrontPanel::new();
+        i8080.attach(0xFF..=0xFF, Rc::new(RefCell::new(panel.sense_switches())));
+        panel.set_switches(0xA500);
+        panel.single_step(&mut i8080);
+        assert_eq!(panel.address_leds(), 0x02);
+        panel.run();
+        panel.clock(&mut i8080, 10);
+        assert_eq!(panel.status_leds(), bus::OUT);
+        assert_eq!(panel.address_leds(), 0x1010);
+        assert_eq!(panel.data_leds(), 0xA5);
+        panel.clock(&mut i8080, 20);
+        assert_eq!(panel.status_leds(), bus::MEMR | bus::HLTA | bus::WO);
+        panel.stop(&mut i8080);
+        assert!(panel.wait_led());
+        panel.reset(&mut i8080);
+        assert_eq!(panel.address_leds(), 0x0000);
+    }
+}
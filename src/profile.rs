@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::bus::{self, BusObserver, MachineCycle};
+use crate::disasm::{is_call, is_return};
+use crate::symbols::Symbols;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Routine {
+    pub address: u16,
+    pub name: String,
+    pub calls: u64,
+    pub inclusive: u64, // T-states, with the routines it calls
+    pub exclusive: u64,
+}
+
+struct Frame {
+    routine: u16,
+    return_address: u16,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Stats {
+    calls: u64,
+    inclusive: u64,
+    exclusive: u64,
+}
+
+// The instruction in progress as seen on the bus.
+#[derive(Default)]
+struct Instruction {
+    opcode: u8,
+    stack_writes: Vec<u8>,
+    stack_reads: Vec<u8>,
+}
+
+// Counts executions and T-states for every address, and follows CALL, RST,
+// interrupts and RET to add up time per subroutine. A return pops back to
+// the frame it returns into, so code that drops or fakes return addresses
+// only upsets the routines involved. Bus time taken by DMA is charged to the
+// instruction it held up.
+pub struct Profiler {
+    counts: Vec<u64>,
+    states: Vec<u64>,
+    pc: Option<u16>,
+    instruction: Instruction,
+    frames: Vec<Frame>,
+    routines: HashMap<u16, Stats>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; 0x10000],
+            states: vec![0; 0x10000],
+            pc: None,
+            instruction: Instruction::default(),
+            frames: Vec::new(),
+            routines: HashMap::new(),
+        }
+    }
+
+    pub fn count(&self, pc: u16) -> u64 {
+        self.counts[pc as usize]
+    }
+
+    pub fn states(&self, pc: u16) -> u64 {
+        self.states[pc as usize]
+    }
+
+    pub fn total_states(&self) -> u64 {
+        self.states.iter().sum()
+    }
+
+    // Addresses by T-states used, most first.
+    pub fn hottest(&self) -> Vec<(u16, u64, u64)> {
+        let mut hottest: Vec<_> = (0..=0xFFFF)
+            .filter(|&pc| self.counts[pc as usize] > 0)
+            .map(|pc| (pc, self.counts[pc as usize], self.states[pc as usize]))
+            .collect();
+        hottest.sort_by_key(|&(pc, _, states)| (std::cmp::Reverse(states), pc));
+        hottest
+    }
+
+    // Routines by inclusive time, most first. Without symbols a routine is
+    // the address it was called at; with them calls are put down to the symbol
+    // they land in and exclusive time is whatever ran under each symbol.
+    pub fn routines(&self, symbols: Option<&Symbols>) -> Vec<Routine> {
+        let mut routines: Vec<Routine> = match symbols {
+            None => self
+                .routines
+                .iter()
+                .map(|(&address, stats)| Routine {
+                    address,
+                    name: format!("{address:04X}h"),
+                    calls: stats.calls,
+                    inclusive: stats.inclusive,
+                    exclusive: stats.exclusive,
+                })
+                .collect(),
+            Some(symbols) => {
+                let mut routines: HashMap<u16, Routine> = HashMap::new();
+                fn routine<'a>(routines: &'a mut HashMap<u16, Routine>, symbols: &Symbols, address: u16) -> &'a mut Routine {
+                    let (start, name) = symbols.containing(address).unwrap_or((0, "(none)"));
+                    routines.entry(start).or_insert_with(|| Routine { address: start, name: name.to_string(), ..Default::default() })
+                }
+                for (&address, stats) in &self.routines {
+                    let routine = routine(&mut routines, symbols, address);
+                    routine.calls += stats.calls;
+                    routine.inclusive = routine.inclusive.max(stats.inclusive);
+                }
+                for (pc, &states) in self.states.iter().enumerate().filter(|(_, &states)| states > 0) {
+                    let routine = routine(&mut routines, symbols, pc as u16);
+                    routine.exclusive += states;
+                    routine.inclusive = routine.inclusive.max(routine.exclusive);
+                }
+                routines.into_values().collect()
+            }
+        };
+        routines.sort_by_key(|routine| (std::cmp::Reverse(routine.inclusive), routine.address));
+        routines
+    }
+
+    // Text tables of the `limit` hottest routines, inclusive and exclusive, and addresses.
+    pub fn report(&self, symbols: Option<&Symbols>, limit: usize) -> String {
+        let total = self.total_states().max(1);
+        let percent = |states: u64| states as f64 * 100.0 / total as f64;
+        let mut routines = self.routines(symbols);
+        let mut report = String::new();
+        writeln!(report, "{} T-states", self.total_states()).unwrap();
+        for exclusive in [false, true] {
+            if exclusive {
+                routines.sort_by_key(|routine| (std::cmp::Reverse(routine.exclusive), routine.address));
+            }
+            writeln!(report, "\nHottest routines, {}:", if exclusive { "exclusive" } else { "inclusive" }).unwrap();
+            writeln!(report, "   Inclusive        Exclusive         Calls  Routine").unwrap();
+            for routine in routines.iter().take(limit) {
+                writeln!(
+                    report,
+                    "{:>12} {:5.1}% {:>10} {:5.1}% {:>8}  {}",
+                    routine.inclusive,
+                    percent(routine.inclusive),
+                    routine.exclusive,
+                    percent(routine.exclusive),
+                    routine.calls,
+                    routine.name
+                )
+                .unwrap();
+            }
+        }
+        writeln!(report, "\nHottest addresses:").unwrap();
+        writeln!(report, "    T-states         Count  Address").unwrap();
+        for (pc, count, states) in self.hottest().into_iter().take(limit) {
+            let name = symbols.map_or_else(|| format!("{pc:04X}h"), |symbols| symbols.format(pc));
+            writeln!(report, "{states:>12} {:5.1}% {count:>8}  {name}", percent(states)).unwrap();
+        }
+        report
+    }
+
+    fn charge(&mut self, states: usize) {
+        let Some(pc) = self.pc else {
+            return;
+        };
+        let states = states as u64;
+        self.states[pc as usize] += states;
+        let Some(top) = self.frames.last() else {
+            return;
+        };
+        self.routines.entry(top.routine).or_default().exclusive += states;
+        // A recursive routine's inclusive time is counted once.
+        let mut seen: Vec<u16> = Vec::with_capacity(self.frames.len());
+        for frame in &self.frames {
+            if !seen.contains(&frame.routine) {
+                seen.push(frame.routine);
+                self.routines.entry(frame.routine).or_default().inclusive += states;
+            }
+        }
+    }
+
+    // Applies the call or return the last instruction made, landing at `pc`.
+    fn follow(&mut self, pc: u16) {
+        let instruction = std::mem::take(&mut self.instruction);
+        if let [high, low] = instruction.stack_writes[..] {
+            if is_call(instruction.opcode) {
+                self.frames.push(Frame { routine: pc, return_address: u16::from_le_bytes([low, high]) });
+                self.routines.entry(pc).or_default().calls += 1;
+            }
+        }
+        if let [low, high] = instruction.stack_reads[..] {
+            let return_address = u16::from_le_bytes([low, high]);
+            if is_return(instruction.opcode) {
+                if let Some(frame) = self.frames.iter().rposition(|frame| frame.return_address == return_address) {
+                    // The bottom frame is the code that was running when profiling began.
+                    self.frames.truncate(frame.max(1));
+                }
+            }
+        }
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BusObserver for Profiler {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) {
+        if cycle.number == 1 {
+            if self.frames.is_empty() {
+                self.frames.push(Frame { routine: cycle.address, return_address: 0 });
+                self.routines.entry(cycle.address).or_default().calls += 1;
+            } else {
+                self.follow(cycle.address);
+            }
+            self.pc = Some(cycle.address);
+            self.instruction.opcode = cycle.data;
+            self.counts[cycle.address as usize] += 1;
+        }
+        match cycle.status {
+            bus::STACK_WRITE => self.instruction.stack_writes.push(cycle.data),
+            bus::STACK_READ => self.instruction.stack_reads.push(cycle.data),
+            _ => {}
+        }
+        self.charge(cycle.states);
+    }
+
+    fn hold(&mut self, _start: u64, states: usize) {
+        self.charge(states);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::I8080;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // 0000: LXI SP,0400h; CALL 0010h; CALL 0020h; HLT
+    // 0010: CALL 0020h; NOP; RET
+    // 0020: NOP; NOP; RET
+    fn run() -> Rc<RefCell<Profiler>> {
+        let mut i8080 = I8080::new(1024);
+        let code: [(u16, &[u8]); 3] = [
+            (0x0000, &[0x31, 0x00, 0x04, 0xCD, 0x10, 0x00, 0xCD, 0x20, 0x00, 0x76]),
+            (0x0010, &[0xCD, 0x20, 0x00, 0x00, 0xC9]),
+            (0x0020, &[0x00, 0x00, 0xC9]),
+        ];
+        for (start, bytes) in code {
+            for (offset, &value) in bytes.iter().enumerate() {
+                i8080.poke(start + offset as u16, value);
+            }
+        }
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        i8080.observe(profiler.clone());
+        while !i8080.halted() {
+            i8080.step();
+        }
+        profiler
+    }
+
+    #[test]
+    fn routines() {
+        let profiler = run();
+        let profiler = profiler.borrow();
+        assert_eq!(profiler.count(0x0020), 2);
+        assert_eq!(profiler.states(0x0022), 20);
+        assert_eq!(profiler.total_states(), 10 + 17 * 3 + 4 * 5 + 10 * 3 + 7);
+        let routines = profiler.routines(None);
+        let summary: Vec<_> = routines.iter().map(|r| (r.address, r.calls, r.inclusive, r.exclusive)).collect();
+        assert_eq!(summary, [(0x0000, 1, 118, 10 + 17 * 2 + 7), (0x0010, 1, 49, 17 + 4 + 10), (0x0020, 2, 36, 36)]);
+    }
+
+    #[test]
+    fn symbols() {
+        let profiler = run();
+        let symbols = Symbols::parse("0000 MAIN 0010 OUTER 0020 INNER 0022 TAIL").unwrap();
+        let routines = profiler.borrow().routines(Some(&symbols));
+        let summary: Vec<_> = routines.iter().map(|r| (r.name.as_str(), r.calls, r.inclusive, r.exclusive)).collect();
+        assert_eq!(summary, [("MAIN", 1, 118, 51), ("OUTER", 1, 49, 31), ("INNER", 2, 36, 16), ("TAIL", 0, 20, 20)]);
+        let report = profiler.borrow().report(Some(&symbols), 2);
+        assert!(report.contains("Hottest routines, exclusive:"));
+        assert!(report.contains("TAIL"));
+    }
+}
@@ -0,0 +1,378 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::bus::{self, BusObserver, MachineCycle};
+use crate::state::{self, Field, Reader, Writer};
+use crate::I8080;
+
+const MAGIC: &[u8; 8] = b"I8080REC";
+const VERSION: u16 = 1;
+
+// Something from outside the CPU, timed by the T-state its machine cycle began.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Input { ticks: u64, port: u8, value: u8 },
+    // The instruction and operands read during the acknowledge.
+    Interrupt { ticks: u64, bytes: Vec<u8> },
+}
+
+impl Field for Event {
+    fn save(&self, state: &mut Writer) {
+        match self {
+            Event::Input { ticks, port, value } => {
+                state.put(&0u8);
+                state.put(ticks);
+                state.put(port);
+                state.put(value);
+            }
+            Event::Interrupt { ticks, bytes } => {
+                state.put(&1u8);
+                state.put(ticks);
+                state.put(bytes);
+            }
+        }
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        match state.get::<u8>()? {
+            0 => Ok(Event::Input { ticks: state.get()?, port: state.get()?, value: state.get()? }),
+            1 => Ok(Event::Interrupt { ticks: state.get()?, bytes: state.get()? }),
+            kind => Err(state::invalid(format!("event type {kind} in recording"))),
+        }
+    }
+}
+
+// A recorded run: the save state it started from, everything the CPU took in
+// and hashes of the CPU and memory every `hash_states` T-states.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recording {
+    pub start: Vec<u8>,
+    pub end: u64, // T-state the recording stopped
+    pub hash_states: u64,
+    pub events: Vec<Event>,
+    pub hashes: Vec<(u64, u64)>, // T-state, hash
+}
+
+impl Recording {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut state = Writer::new();
+        state.put(&self.start);
+        state.put(&self.end);
+        state.put(&self.hash_states);
+        state.put(&self.events);
+        state.put(&self.hashes);
+        state::seal_as(MAGIC, VERSION, state.bytes())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut state = Reader::new(state::open_as(MAGIC, VERSION, "recording", bytes)?);
+        let recording = Self {
+            start: state.get()?,
+            end: state.get()?,
+            hash_states: state.get()?,
+            events: state.get()?,
+            hashes: state.get()?,
+        };
+        state.finish()?;
+        Ok(recording)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+// Inputs and interrupt acknowledges seen on the bus.
+#[derive(Default)]
+struct Journal {
+    events: Vec<Event>,
+    acknowledge: Option<u16>, // address of an acknowledge in progress
+}
+
+impl BusObserver for Journal {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) {
+        if cycle.number == 1 {
+            self.acknowledge = None;
+        }
+        if cycle.status & bus::INTA != 0 {
+            self.acknowledge = Some(cycle.address);
+            self.events.push(Event::Interrupt { ticks: cycle.start, bytes: vec![cycle.data] });
+        } else if cycle.status == bus::MEMORY_READ && self.acknowledge == Some(cycle.address) {
+            // Operand read by an INTA cycle: PC does not move during an acknowledge.
+            if let Some(Event::Interrupt { bytes, .. }) = self.events.last_mut() {
+                bytes.push(cycle.data);
+            }
+        } else if cycle.status == bus::INPUT_READ {
+            self.events.push(Event::Input { ticks: cycle.start, port: cycle.address as u8, value: cycle.data });
+        }
+    }
+}
+
+// Records a run driven through `run`. The CPU is clocked one T-state at a
+// time so that state hashes fall on exact T-states.
+pub struct Recorder {
+    journal: Rc<RefCell<Journal>>,
+    start: Vec<u8>,
+    hash_states: u64,
+    hashes: Vec<(u64, u64)>,
+}
+
+impl Recorder {
+    pub fn new(i8080: &mut I8080, hash_states: u64) -> Self {
+        let journal = Rc::new(RefCell::new(Journal::default()));
+        i8080.observe(journal.clone());
+        Self { journal, start: i8080.save_state(), hash_states: hash_states.max(1), hashes: Vec::new() }
+    }
+
+    pub fn run(&mut self, i8080: &mut I8080, states: u64) {
+        for _ in 0..states {
+            i8080.cycle();
+            if i8080.ticks().is_multiple_of(self.hash_states) {
+                self.hashes.push((i8080.ticks(), i8080.state_hash()));
+            }
+        }
+    }
+
+    pub fn finish(self, i8080: &I8080) -> Recording {
+        let events = std::mem::take(&mut self.journal.borrow_mut().events);
+        Recording { start: self.start, end: i8080.ticks(), hash_states: self.hash_states, events, hashes: self.hashes }
+    }
+}
+
+// Where a replay stopped matching its recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub ticks: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "replay diverged at T-state {}: {}", self.ticks, self.reason)
+    }
+}
+
+impl Error for Divergence {}
+
+// Checks the bus against the recorded events.
+struct Check {
+    inputs: VecDeque<(u64, u8)>,
+    acknowledged: Option<u64>,
+    divergence: Option<Divergence>,
+}
+
+impl BusObserver for Check {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) {
+        if cycle.status & bus::INTA != 0 {
+            self.acknowledged = Some(cycle.start);
+        }
+        if cycle.status != bus::INPUT_READ || self.divergence.is_some() {
+            return;
+        }
+        let port = cycle.address as u8;
+        match self.inputs.pop_front() {
+            Some(expected) if expected == (cycle.start, port) => {}
+            Some((ticks, expected)) => {
+                let reason = format!("input from port {port:02X}h, recorded from {expected:02X}h at T-state {ticks}");
+                self.divergence = Some(Divergence { ticks: cycle.start, reason });
+            }
+            None => {
+                let reason = format!("input from port {port:02X}h after the recorded ones");
+                self.divergence = Some(Divergence { ticks: cycle.start, reason });
+            }
+        }
+    }
+}
+
+// Replays a recording on a machine built like the recorded one. The CPU reads
+// the recorded inputs and takes the recorded interrupts, the devices' own
+// INTR being ignored, so host input need not be supplied again. Devices are
+// still clocked and written to.
+pub struct Player {
+    check: Rc<RefCell<Check>>,
+    interrupts: VecDeque<(u64, Vec<u8>)>,
+    hashes: VecDeque<(u64, u64)>,
+    end: u64,
+}
+
+impl Player {
+    pub fn new(i8080: &mut I8080, recording: &Recording) -> io::Result<Self> {
+        i8080.load_state(&recording.start)?;
+        i8080.set_device_interrupts(false);
+        let mut inputs = VecDeque::new();
+        let mut interrupts = VecDeque::new();
+        for event in &recording.events {
+            match event {
+                Event::Input { ticks, port, value } => {
+                    inputs.push_back((*ticks, *port));
+                    i8080.replay_inputs([*value]);
+                }
+                Event::Interrupt { ticks, bytes } => interrupts.push_back((*ticks, bytes.clone())),
+            }
+        }
+        let check = Rc::new(RefCell::new(Check { inputs, acknowledged: None, divergence: None }));
+        i8080.observe(check.clone());
+        Ok(Self { check, interrupts, hashes: recording.hashes.iter().copied().collect(), end: recording.end })
+    }
+
+    pub fn finished(&self, i8080: &I8080) -> bool {
+        i8080.ticks() >= self.end
+    }
+
+    // Runs up to `states` T-states, stopping at the end of the recording. A
+    // divergence ends the replay as `finish` does.
+    pub fn run(&mut self, i8080: &mut I8080, states: u64) -> Result<(), Divergence> {
+        let result = self.play(i8080, states);
+        if result.is_err() {
+            self.release(i8080);
+        }
+        result
+    }
+
+    // Hands interrupts and inputs back to the devices once the replay is over.
+    // A player dropped without this leaves recorded inputs queued on the CPU.
+    pub fn finish(self, i8080: &mut I8080) {
+        self.release(i8080);
+    }
+
+    fn play(&mut self, i8080: &mut I8080, states: u64) -> Result<(), Divergence> {
+        for _ in 0..states {
+            if self.finished(i8080) {
+                break;
+            }
+            let ticks = i8080.ticks();
+            let interrupt = self.interrupts.front().is_some_and(|(at, _)| *at == ticks);
+            if interrupt {
+                let (_, bytes) = self.interrupts.pop_front().unwrap();
+                i8080.interrupt_sequence(&bytes);
+            }
+            i8080.cycle();
+            let mut check = self.check.borrow_mut();
+            if let Some(divergence) = check.divergence.take() {
+                return Err(divergence);
+            }
+            if interrupt && check.acknowledged != Some(ticks) {
+                return Err(Divergence { ticks, reason: "recorded interrupt not taken".to_string() });
+            }
+            drop(check);
+            if self.hashes.front().is_some_and(|(at, _)| *at == i8080.ticks()) {
+                let (at, hash) = self.hashes.pop_front().unwrap();
+                if i8080.state_hash() != hash {
+                    return Err(Divergence { ticks: at, reason: "CPU and memory differ from the recording".to_string() });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&self, i8080: &mut I8080) {
+        i8080.set_device_interrupts(true);
+        i8080.clear_replay_inputs();
+        let check: Rc<RefCell<dyn BusObserver>> = self.check.clone();
+        i8080.unobserve(&check);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::i8259::I8259;
+    use crate::io::Device;
+
+    // Host input: a different value every read.
+    struct Keyboard(u8);
+
+    impl Device for Keyboard {
+        fn input(&mut self, _port: u8) -> u8 {
+            self.0 = self.0.wrapping_mul(5).wrapping_add(3);
+            self.0
+        }
+
+        fn output(&mut self, _port: u8, _value: u8) {}
+    }
+
+    // 0000: LXI SP,0400h; LXI H,0200h; EI
+    // 0007: IN 01h; ADD L; MOV L,A; JMP 0007h
+    // 0100: INR H; EI; RET (interrupt service routine)
+    fn machine(keyboard: u8) -> (I8080, Rc<RefCell<I8259>>) {
+        let mut i8080 = I8080::new(1024);
+        let program = [0x31, 0x00, 0x04, 0x21, 0x00, 0x02, 0xFB, 0xDB, 0x01, 0x85, 0x6F, 0xC3, 0x07, 0x00];
+        for (location, &value) in program.iter().enumerate() {
+            i8080.poke(location as u16, value);
+        }
+        for (location, &value) in [0x24, 0xFB, 0xC9].iter().enumerate() {
+            i8080.poke(0x0100 + location as u16, value);
+        }
+        let pic = Rc::new(RefCell::new(I8259::new()));
+        i8080.attach(0x01..=0x01, Rc::new(RefCell::new(Keyboard(keyboard))));
+        i8080.attach(0x20..=0x21, pic.clone());
+        // ICW1 single, ICW2 vectors from 0100h, ICW4 AEOI; IR0 unmasked
+        for (port, value) in [(0, 0x13), (1, 0x01), (1, 0x02), (1, 0xFE)] {
+            pic.borrow_mut().output(port, value);
+        }
+        (i8080, pic)
+    }
+
+    fn record() -> (Recording, u64) {
+        let (mut i8080, pic) = machine(1);
+        let mut recorder = Recorder::new(&mut i8080, 1000);
+        for pulse in 0..10 {
+            recorder.run(&mut i8080, 700 + pulse * 13);
+            pic.borrow_mut().set_ir(0, true);
+            recorder.run(&mut i8080, 50);
+            pic.borrow_mut().set_ir(0, false);
+        }
+        let hash = i8080.state_hash();
+        (recorder.finish(&i8080), hash)
+    }
+
+    #[test]
+    fn replay() {
+        let (recording, hash) = record();
+        let interrupts = recording.events.iter().filter(|event| matches!(event, Event::Interrupt { .. })).count();
+        assert_eq!(interrupts, 10);
+        assert!(recording.events.contains(&Event::Interrupt { ticks: 701, bytes: vec![0xCD, 0x00, 0x01] }));
+        let recording = Recording::from_bytes(&recording.to_bytes()).unwrap();
+
+        // No interrupts are raised and the keyboard types something else.
+        let (mut i8080, _) = machine(200);
+        let mut player = Player::new(&mut i8080, &recording).unwrap();
+        while !player.finished(&i8080) {
+            player.run(&mut i8080, 1234).unwrap();
+        }
+        assert_eq!(i8080.ticks(), recording.end);
+        assert_eq!(i8080.state_hash(), hash);
+        player.finish(&mut i8080);
+    }
+
+    #[test]
+    fn divergence() {
+        let (recording, _) = record();
+        let (mut i8080, _) = machine(1);
+        let mut player = Player::new(&mut i8080, &recording).unwrap();
+        i8080.poke(0x0009, 0x84); // ADD H
+        let divergence = player.run(&mut i8080, recording.end).unwrap_err();
+        assert_eq!(divergence.ticks, 1000);
+        // IN 30h reads the device now, not what was recorded.
+        i8080.attach(0x30..=0x30, Rc::new(RefCell::new(Keyboard(0))));
+        i8080.set_pc(0x0300);
+        i8080.poke(0x0300, 0xDB);
+        i8080.poke(0x0301, 0x30);
+        i8080.step();
+        assert_eq!(i8080.registers().a, 3);
+
+        let mut corrupt = recording.to_bytes();
+        corrupt[30] ^= 0x01;
+        assert!(Recording::from_bytes(&corrupt).is_err());
+    }
+}
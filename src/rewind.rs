@@ -0,0 +1,255 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+
+use crate::bus::{self, BusObserver, MachineCycle};
+use crate::I8080;
+
+// What one step did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub pc: u16, // at the start of the step
+    pub ticks: u64,
+    pub inputs: Vec<u8>,
+    pub writes: Vec<(u16, u8)>, // address, value; DMA writes are not seen
+}
+
+impl Entry {
+    fn bytes(&self) -> usize {
+        mem::size_of::<Entry>() + self.inputs.len() + self.writes.len() * mem::size_of::<(u16, u8)>()
+    }
+}
+
+// Collects the inputs and writes of the step in progress from the bus.
+#[derive(Default)]
+struct Journal {
+    entry: Entry,
+    replaying: bool,
+}
+
+impl BusObserver for Journal {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) {
+        if self.replaying {
+            return;
+        }
+        match cycle.status {
+            bus::INPUT_READ => self.entry.inputs.push(cycle.data),
+            bus::MEMORY_WRITE | bus::STACK_WRITE => self.entry.writes.push((cycle.address, cycle.data)),
+            _ => {}
+        }
+    }
+}
+
+// Steps the CPU while keeping a save state at the start of every frame of
+// `frame_states` T-states and a log of each step since. Going back restores
+// the checkpoint before the target and re-runs the steps from there, feeding
+// IN instructions the logged input so that host input is seen the same way.
+// Interrupts raised by the host through `I8080::interrupt` are not replayed.
+// The oldest frames are dropped to keep the history within `budget` bytes.
+pub struct Rewind {
+    journal: Rc<RefCell<Journal>>,
+    frame_states: u64,
+    budget: usize,
+    first: u64, // step number of the first entry
+    history: VecDeque<Entry>,
+    checkpoints: VecDeque<(u64, Vec<u8>)>, // step number, save state
+    next_frame: u64,
+    bytes: usize,
+}
+
+impl Rewind {
+    pub fn new(i8080: &mut I8080, frame_states: u64, budget: usize) -> Self {
+        let journal = Rc::new(RefCell::new(Journal::default()));
+        i8080.observe(journal.clone());
+        Self {
+            journal,
+            frame_states: frame_states.max(1),
+            budget,
+            first: 0,
+            history: VecDeque::new(),
+            checkpoints: VecDeque::new(),
+            next_frame: 0,
+            bytes: 0,
+        }
+    }
+
+    // Steps the CPU as `I8080::step` does, recording the step.
+    pub fn step(&mut self, i8080: &mut I8080) -> usize {
+        if i8080.ticks() >= self.next_frame || self.checkpoints.is_empty() {
+            let state = i8080.save_state();
+            self.bytes += state.len();
+            self.checkpoints.push_back((self.position(), state));
+            self.next_frame = (i8080.ticks() / self.frame_states + 1) * self.frame_states;
+        }
+        let (pc, ticks) = (i8080.pc(), i8080.ticks());
+        let states = i8080.step();
+        let entry = Entry { pc, ticks, ..mem::take(&mut self.journal.borrow_mut().entry) };
+        self.bytes += entry.bytes();
+        self.history.push_back(entry);
+        self.trim();
+        states
+    }
+
+    // Steps taken since the CPU was attached.
+    pub fn position(&self) -> u64 {
+        self.first + self.history.len() as u64
+    }
+
+    // The earliest step that can be gone back to.
+    pub fn oldest(&self) -> u64 {
+        self.checkpoints.front().map_or(self.position(), |(step, _)| *step)
+    }
+
+    // The logged steps, oldest first, from step `oldest()`.
+    pub fn history(&self) -> impl DoubleEndedIterator<Item = &Entry> {
+        self.history.iter()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Goes back one instruction. False if the history does not go back further.
+    pub fn step_back(&mut self, i8080: &mut I8080) -> io::Result<bool> {
+        if self.position() == self.oldest() {
+            return Ok(false);
+        }
+        self.go_to(i8080, self.position() - 1)?;
+        Ok(true)
+    }
+
+    // Goes back to the start of the latest step `stop` returns true for, e.g.
+    // one starting at a breakpoint or writing a watched location.
+    pub fn run_back(&mut self, i8080: &mut I8080, mut stop: impl FnMut(&Entry) -> bool) -> io::Result<bool> {
+        let found = self.history().rev().position(&mut stop);
+        match found {
+            Some(back) => {
+                self.go_to(i8080, self.position() - 1 - back as u64)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Goes back to the start of the frame `frames` frames ago; 1 is the start
+    // of the current frame. Returns how many frames it went back.
+    pub fn rewind(&mut self, i8080: &mut I8080, frames: usize) -> io::Result<usize> {
+        let position = self.position();
+        let earlier = self.checkpoints.iter().filter(|(step, _)| *step < position).count();
+        let frames = frames.min(earlier);
+        if frames > 0 {
+            self.go_to(i8080, self.checkpoints[earlier - frames].0)?;
+        }
+        Ok(frames)
+    }
+
+    // Re-runs up to `step` from the checkpoint before it and forgets what came after.
+    fn go_to(&mut self, i8080: &mut I8080, step: u64) -> io::Result<()> {
+        let index = self.checkpoints.iter().rposition(|(start, _)| *start <= step).unwrap();
+        let start = self.checkpoints[index].0;
+        i8080.load_state(&self.checkpoints[index].1)?;
+        let replay = (start - self.first) as usize..(step - self.first) as usize;
+        i8080.replay_inputs(self.history.range(replay.clone()).flat_map(|entry| entry.inputs.iter().copied()));
+        self.journal.borrow_mut().replaying = true;
+        for _ in replay {
+            i8080.step();
+        }
+        let mut journal = self.journal.borrow_mut();
+        journal.replaying = false;
+        journal.entry = Entry::default();
+
+        for (_, state) in self.checkpoints.drain(index + 1..) {
+            self.bytes -= state.len();
+        }
+        for entry in self.history.drain((step - self.first) as usize..) {
+            self.bytes -= entry.bytes();
+        }
+        self.next_frame = (i8080.ticks() / self.frame_states + 1) * self.frame_states;
+        Ok(())
+    }
+
+    fn trim(&mut self) {
+        while self.bytes > self.budget && self.checkpoints.len() > 1 {
+            let (_, state) = self.checkpoints.pop_front().unwrap();
+            self.bytes -= state.len();
+            while self.first < self.oldest() {
+                let entry = self.history.pop_front().unwrap();
+                self.bytes -= entry.bytes();
+                self.first += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::Device;
+
+    // Reads differently every time and keeps no saved state, as host input does.
+    struct Counter(u8);
+
+    impl Device for Counter {
+        fn input(&mut self, _port: u8) -> u8 {
+            self.0 = self.0.wrapping_add(1);
+            self.0
+        }
+
+        fn output(&mut self, _port: u8, _value: u8) {}
+    }
+
+    // LXI B,0100h; loop: IN 10h; STAX B; INX B; JMP loop
+    fn machine() -> I8080 {
+        let mut i8080 = I8080::new(1024);
+        for (location, &value) in [0x01, 0x00, 0x01, 0xDB, 0x10, 0x02, 0x03, 0xC3, 0x03, 0x00].iter().enumerate() {
+            i8080.poke(location as u16, value);
+        }
+        i8080.attach(0x10..=0x10, Rc::new(RefCell::new(Counter(0))));
+        i8080
+    }
+
+    #[test]
+    fn step_back() {
+        let mut i8080 = machine();
+        let mut rewind = Rewind::new(&mut i8080, 100, 1 << 20);
+        let mut states = vec![i8080.save_state()];
+        for _ in 0..200 {
+            rewind.step(&mut i8080);
+            states.push(i8080.save_state());
+        }
+        assert!(rewind.step_back(&mut i8080).unwrap());
+        assert_eq!(rewind.position(), 199);
+        assert_eq!(i8080.save_state(), states[199]);
+
+        // Back to the store of the 16th input.
+        assert!(rewind.run_back(&mut i8080, |entry| entry.writes.contains(&(0x010F, 16))).unwrap());
+        assert_eq!(i8080.pc(), 0x0005);
+        assert_eq!(i8080.save_state(), states[rewind.position() as usize]);
+        assert_eq!(i8080.peek(0x010F), 0);
+        rewind.step(&mut i8080);
+        assert_eq!(i8080.peek(0x010F), 16);
+        assert!(!rewind.run_back(&mut i8080, |entry| entry.pc == 0x1234).unwrap());
+
+        assert_eq!(rewind.rewind(&mut i8080, 2).unwrap(), 2);
+        assert_eq!(i8080.save_state(), states[rewind.position() as usize]);
+        assert!(rewind.rewind(&mut i8080, 100).unwrap() > 0);
+        assert_eq!(rewind.position(), 0);
+        assert!(!rewind.step_back(&mut i8080).unwrap());
+    }
+
+    #[test]
+    fn budget() {
+        let mut i8080 = machine();
+        let mut rewind = Rewind::new(&mut i8080, 100, 8192);
+        for _ in 0..2000 {
+            rewind.step(&mut i8080);
+        }
+        assert!(rewind.bytes() <= 8192);
+        assert!(rewind.oldest() > 1900);
+        assert_eq!(rewind.history().count() as u64, 2000 - rewind.oldest());
+        while rewind.step_back(&mut i8080).unwrap() {}
+        assert_eq!(rewind.position(), rewind.oldest());
+    }
+}
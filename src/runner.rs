@@ -0,0 +1,364 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::disasm::documented;
+use crate::io::Device;
+use crate::I8080;
+
+// CP/M page zero and the top of the TPA as the runner sets them up.
+const WARM_BOOT: u16 = 0x0000;
+const BDOS: u16 = 0x0005;
+const BDOS_RETURN: u16 = 0xFE00;
+const EOF: u8 = 0x1A; // ^Z, what CP/M reads past the end of input
+
+// How a run ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Halted(u16),       // address of the HLT
+    WarmBoot,          // a CP/M program jumped to 0000h or called BDOS 0
+    Exit(u8),          // value written to the exit port
+    Timeout(u64),      // T-states run
+    InvalidOpcode { pc: u16, opcode: u8 },
+}
+
+impl Outcome {
+    // Process exit status: 0 for HLT or a warm boot, the exit port's value,
+    // 124 for a timeout as timeout(1) uses and 132 (128 + SIGILL) for an
+    // invalid opcode.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::Halted(_) | Self::WarmBoot => 0,
+            Self::Exit(value) => *value,
+            Self::Timeout(_) => 124,
+            Self::InvalidOpcode { .. } => 132,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Halted(pc) => write!(f, "halted at {pc:04X}h"),
+            Self::WarmBoot => write!(f, "warm boot"),
+            Self::Exit(value) => write!(f, "exit with {value}"),
+            Self::Timeout(ticks) => write!(f, "timed out after {ticks} T-states"),
+            Self::InvalidOpcode { pc, opcode } => write!(f, "invalid opcode {opcode:02X}h at {pc:04X}h"),
+        }
+    }
+}
+
+// Host input and output shared by the console port and the BDOS calls. The
+// first write error is kept for `run` to return.
+struct Terminal {
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+    error: Option<io::Error>,
+}
+
+impl Terminal {
+    // Blocks for the next byte; EOF after the end of input.
+    fn read(&mut self) -> u8 {
+        self.flush();
+        let mut byte = [0];
+        match self.input.read(&mut byte) {
+            Ok(1) => byte[0],
+            _ => EOF,
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if let Err(error) = self.output.write_all(bytes) {
+            self.error.get_or_insert(error);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(error) = self.output.flush() {
+            self.error.get_or_insert(error);
+        }
+    }
+}
+
+// A byte written is printed and a byte read waits for the next key.
+struct ConsolePort {
+    terminal: Rc<RefCell<Terminal>>,
+}
+
+impl Device for ConsolePort {
+    fn input(&mut self, _port: u8) -> u8 {
+        self.terminal.borrow_mut().read()
+    }
+
+    fn output(&mut self, _port: u8, value: u8) {
+        self.terminal.borrow_mut().write(&[value]);
+    }
+}
+
+struct ExitPort {
+    value: Rc<Cell<Option<u8>>>,
+}
+
+impl Device for ExitPort {
+    fn input(&mut self, _port: u8) -> u8 {
+        0xFF
+    }
+
+    fn output(&mut self, _port: u8, value: u8) {
+        self.value.set(Some(value));
+    }
+}
+
+// Runs a program without a user interface until it halts, writes to the exit
+// port, returns to CP/M or runs out of time. With CP/M calls on, BDOS console
+// functions 1, 2, 6, 9, 10, 11 and 12 go to the terminal.
+pub struct Runner {
+    i8080: I8080,
+    terminal: Rc<RefCell<Terminal>>,
+    exit: Rc<Cell<Option<u8>>>,
+    max_cycles: Option<u64>,
+    cpm: bool,
+    undocumented: bool,
+}
+
+impl Runner {
+    pub fn new(i8080: I8080, input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        Self {
+            i8080,
+            terminal: Rc::new(RefCell::new(Terminal { input, output, error: None })),
+            exit: Rc::new(Cell::new(None)),
+            max_cycles: None,
+            cpm: false,
+            undocumented: false,
+        }
+    }
+
+    pub fn i8080(&self) -> &I8080 {
+        &self.i8080
+    }
+
+    pub fn i8080_mut(&mut self) -> &mut I8080 {
+        &mut self.i8080
+    }
+
+    // Limit in T-states.
+    pub fn set_max_cycles(&mut self, max_cycles: Option<u64>) {
+        self.max_cycles = max_cycles;
+    }
+
+    pub fn set_exit_port(&mut self, port: u8) {
+        let device = ExitPort { value: self.exit.clone() };
+        self.i8080.attach(port..=port, Rc::new(RefCell::new(device)));
+    }
+
+    pub fn set_console_port(&mut self, port: u8) {
+        let device = ConsolePort { terminal: self.terminal.clone() };
+        self.i8080.attach(port..=port, Rc::new(RefCell::new(device)));
+    }
+
+    // Sets up page zero and the stack as CP/M would for a .COM file: BDOS
+    // at 0005h below FE00h and 0000h on the stack for a final RET. Needs
+    // 64K of memory.
+    pub fn set_cpm(&mut self) {
+        let jumps = [(WARM_BOOT, BDOS_RETURN + 3), (BDOS, BDOS_RETURN)];
+        for (address, target) in jumps {
+            let [low, high] = target.to_le_bytes();
+            for (offset, value) in [0xC3, low, high].into_iter().enumerate() {
+                self.i8080.poke(address + offset as u16, value);
+            }
+        }
+        self.i8080.poke(BDOS_RETURN, 0xC9); // RET
+        self.i8080.poke(BDOS_RETURN + 3, 0x76); // HLT
+        let mut registers = self.i8080.registers();
+        registers.sp = BDOS_RETURN - 2;
+        self.i8080.poke(registers.sp, 0x00);
+        self.i8080.poke(registers.sp + 1, 0x00);
+        self.i8080.set_registers(registers);
+        self.cpm = true;
+    }
+
+    // Lets undocumented opcodes run as their documented aliases instead of
+    // ending the run.
+    pub fn set_undocumented(&mut self, undocumented: bool) {
+        self.undocumented = undocumented;
+    }
+
+    pub fn run(&mut self) -> io::Result<Outcome> {
+        let outcome = loop {
+            let pc = self.i8080.pc();
+            if self.cpm && pc == WARM_BOOT {
+                break Outcome::WarmBoot;
+            }
+            if self.cpm && pc == BDOS {
+                if let Some(outcome) = self.bdos() {
+                    break outcome;
+                }
+            }
+            let opcode = self.i8080.peek(pc);
+            if !self.undocumented && documented(opcode) != opcode {
+                break Outcome::InvalidOpcode { pc, opcode };
+            }
+            self.i8080.step();
+            if let Some(value) = self.exit.take() {
+                break Outcome::Exit(value);
+            }
+            if self.i8080.halted() {
+                break Outcome::Halted(pc);
+            }
+            match self.max_cycles {
+                Some(max_cycles) if self.i8080.ticks() >= max_cycles => break Outcome::Timeout(self.i8080.ticks()),
+                _ => {}
+            }
+        };
+        let mut terminal = self.terminal.borrow_mut();
+        terminal.flush();
+        match terminal.error.take() {
+            Some(error) => Err(error),
+            None => Ok(outcome),
+        }
+    }
+
+    // A BDOS call with the function in C, its argument in E or DE, and
+    // results in A and L, and in HL for the version. The JMP and RET at
+    // 0005h then return from it.
+    fn bdos(&mut self) -> Option<Outcome> {
+        let mut registers = self.i8080.registers();
+        let de = u16::from_be_bytes([registers.d, registers.e]);
+        let mut terminal = self.terminal.borrow_mut();
+        let result = match registers.c {
+            0 => return Some(Outcome::WarmBoot),
+            1 => {
+                let byte = terminal.read();
+                terminal.write(&[byte]);
+                byte
+            }
+            2 => {
+                terminal.write(&[registers.e]);
+                0
+            }
+            6 if registers.e == 0xFF => terminal.read(),
+            6 => {
+                terminal.write(&[registers.e]);
+                0
+            }
+            9 => {
+                let text: Vec<u8> = (0..=0xFFFF).map(|offset| self.i8080.peek(de.wrapping_add(offset))).take_while(|&byte| byte != b'$').collect();
+                terminal.write(&text);
+                0
+            }
+            10 => {
+                // Buffer of the size at DE; the count read goes after it. The
+                // line is echoed as it is typed.
+                let size = self.i8080.peek(de);
+                let mut count = 0;
+                while count < size {
+                    match terminal.read() {
+                        b'\r' | b'\n' | EOF => break,
+                        byte => {
+                            terminal.write(&[byte]);
+                            self.i8080.poke(de.wrapping_add(2 + count as u16), byte);
+                            count += 1;
+                        }
+                    }
+                }
+                terminal.write(b"\r\n");
+                self.i8080.poke(de.wrapping_add(1), count);
+                0
+            }
+            11 => 0xFF, // a read waits for a key
+            12 => {
+                registers.h = 0x00;
+                0x22 // CP/M 2.2
+            }
+            _ => 0xFF,
+        };
+        registers.a = result;
+        registers.l = result;
+        if registers.c != 12 {
+            registers.h = 0;
+        }
+        registers.b = registers.h;
+        self.i8080.set_registers(registers);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn setup(program: &[u8], input: &'static [u8]) -> (Runner, Shared) {
+        let mut i8080 = I8080::new(0x10000);
+        for (offset, &value) in program.iter().enumerate() {
+            i8080.poke(0x0100 + offset as u16, value);
+        }
+        i8080.set_pc(0x0100);
+        let output = Shared::default();
+        (Runner::new(i8080, Box::new(input), Box::new(output.clone())), output)
+    }
+
+    #[test]
+    fn cpm() {
+        // MVI C,1; CALL 5; MOV E,A; INR E; MVI C,2; CALL 5;
+        // LXI D,MESSAGE; MVI C,9; CALL 5; RET; MESSAGE: DB 'OK$'
+        let program = [
+            0x0E, 0x01, 0xCD, 0x05, 0x00, 0x5F, 0x1C, 0x0E, 0x02, 0xCD, 0x05, 0x00, 0x11, 0x18, 0x01, 0x0E, 0x09, 0xCD,
+            0x05, 0x00, 0xC9, 0x00, 0x00, 0x00, b'O', b'K', b'$',
+        ];
+        let (mut runner, output) = setup(&program, b"a");
+        runner.set_cpm();
+        assert_eq!(runner.run().unwrap(), Outcome::WarmBoot);
+        assert_eq!(output.0.borrow().as_slice(), b"abOK");
+        assert_eq!(runner.i8080().registers().sp, BDOS_RETURN);
+
+        // LXI D,0200h; MVI C,10; CALL 5; RET
+        let (mut runner, output) = setup(&[0x11, 0x00, 0x02, 0x0E, 0x0A, 0xCD, 0x05, 0x00, 0xC9], b"hi\rx");
+        runner.i8080_mut().poke(0x0200, 8);
+        runner.set_cpm();
+        assert_eq!(runner.run().unwrap(), Outcome::WarmBoot);
+        assert_eq!(output.0.borrow().as_slice(), b"hi\r\n");
+        let buffer: Vec<u8> = (0x0201..0x0205).map(|address| runner.i8080().peek(address)).collect();
+        assert_eq!(buffer, [2, b'h', b'i', 0]);
+    }
+
+    #[test]
+    fn outcomes() {
+        // IN 10h; OUT 10h; MVI A,3; OUT 0FFh
+        let (mut runner, output) = setup(&[0xDB, 0x10, 0xD3, 0x10, 0x3E, 0x03, 0xD3, 0xFF], b"x");
+        runner.set_console_port(0x10);
+        runner.set_exit_port(0xFF);
+        let outcome = runner.run().unwrap();
+        assert_eq!((outcome, outcome.code()), (Outcome::Exit(3), 3));
+        assert_eq!(output.0.borrow().as_slice(), b"x");
+
+        let (mut runner, _) = setup(&[0x00, 0x76], b"");
+        assert_eq!(runner.run().unwrap(), Outcome::Halted(0x0101));
+
+        // JMP 0100h
+        let (mut runner, _) = setup(&[0xC3, 0x00, 0x01], b"");
+        runner.set_max_cycles(Some(100));
+        let outcome = runner.run().unwrap();
+        assert_eq!((outcome, outcome.code()), (Outcome::Timeout(100), 124));
+
+        let (mut runner, _) = setup(&[0x00, 0x08, 0x76], b"");
+        let outcome = runner.run().unwrap();
+        assert_eq!(outcome.to_string(), "invalid opcode 08h at 0101h");
+        assert_eq!(outcome.code(), 132);
+        runner.set_undocumented(true);
+        assert_eq!(runner.run().unwrap(), Outcome::Halted(0x0102));
+    }
+}
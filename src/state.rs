@@ -0,0 +1,303 @@
+use std::io;
+
+// Save state file layout, all little endian:
+//   magic "I8080SAV", version u16, payload length u32, CRC-32 of the payload u32
+// followed by the payload: the CPU, memory and each attached device in attach
+// order. Bump VERSION whenever any of them changes what it saves.
+pub const MAGIC: &[u8; 8] = b"I8080SAV";
+pub const VERSION: u16 = 2;
+pub const HEADER_BYTES: usize = 18;
+
+pub fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// 64-bit FNV-1a, for comparing states without keeping them.
+pub fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3))
+}
+
+// Wraps a payload in the header.
+pub fn seal(payload: &[u8]) -> Vec<u8> {
+    seal_as(MAGIC, VERSION, payload)
+}
+
+// Checks the header and returns the payload.
+pub fn open(bytes: &[u8]) -> io::Result<&[u8]> {
+    open_as(MAGIC, VERSION, "save state", bytes)
+}
+
+// The same layout for other files, told apart by their magic.
+pub fn seal_as(magic: &[u8; 8], version: u16, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_BYTES + payload.len());
+    bytes.extend_from_slice(magic);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&crc32(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+pub fn open_as<'a>(magic: &[u8; 8], expected: u16, name: &str, bytes: &'a [u8]) -> io::Result<&'a [u8]> {
+    if bytes.len() < HEADER_BYTES || &bytes[0..8] != magic {
+        return Err(invalid(format!("not a {name}")));
+    }
+    let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+    if version != expected {
+        return Err(invalid(format!("{name} version {version}, expected {expected}")));
+    }
+    let length = u32::from_le_bytes(bytes[10..14].try_into().unwrap()) as usize;
+    let payload = &bytes[HEADER_BYTES..];
+    if payload.len() != length {
+        return Err(invalid(format!("{name} payload is {} bytes, expected {length}", payload.len())));
+    }
+    if crc32(payload) != u32::from_le_bytes(bytes[14..18].try_into().unwrap()) {
+        return Err(invalid(format!("{name} checksum mismatch")));
+    }
+    Ok(payload)
+}
+
+#[derive(Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put<T: Field>(&mut self, value: &T) {
+        value.save(self);
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn get<T: Field>(&mut self) -> io::Result<T> {
+        T::load(self)
+    }
+
+    pub fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + count)
+            .ok_or_else(|| invalid("save state is truncated".to_string()))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    // Fails unless everything was read.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.bytes.len() - self.position {
+            0 => Ok(()),
+            left => Err(invalid(format!("{left} bytes left over in save state"))),
+        }
+    }
+}
+
+// A value that can go in a save state.
+pub trait Field: Sized {
+    fn save(&self, state: &mut Writer);
+    fn load(state: &mut Reader) -> io::Result<Self>;
+}
+
+impl Field for u8 {
+    fn save(&self, state: &mut Writer) {
+        state.bytes.push(*self);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(state.take(1)?[0])
+    }
+}
+
+impl Field for u16 {
+    fn save(&self, state: &mut Writer) {
+        state.bytes.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(u16::from_le_bytes(state.take(2)?.try_into().unwrap()))
+    }
+}
+
+impl Field for u32 {
+    fn save(&self, state: &mut Writer) {
+        state.bytes.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(u32::from_le_bytes(state.take(4)?.try_into().unwrap()))
+    }
+}
+
+impl Field for u64 {
+    fn save(&self, state: &mut Writer) {
+        state.bytes.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(u64::from_le_bytes(state.take(8)?.try_into().unwrap()))
+    }
+}
+
+// Saved as 64 bits so states move between hosts.
+impl Field for usize {
+    fn save(&self, state: &mut Writer) {
+        (*self as u64).save(state);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        usize::try_from(u64::load(state)?).map_err(|_| invalid("save state value too large".to_string()))
+    }
+}
+
+impl Field for i64 {
+    fn save(&self, state: &mut Writer) {
+        (*self as u64).save(state);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(u64::load(state)? as i64)
+    }
+}
+
+impl Field for bool {
+    fn save(&self, state: &mut Writer) {
+        (*self as u8).save(state);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        match u8::load(state)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid(format!("{value:#04X} in save state is not a boolean"))),
+        }
+    }
+}
+
+impl<T: Field> Field for Option<T> {
+    fn save(&self, state: &mut Writer) {
+        self.is_some().save(state);
+        if let Some(value) = self {
+            value.save(state);
+        }
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok(match bool::load(state)? {
+            true => Some(T::load(state)?),
+            false => None,
+        })
+    }
+}
+
+impl<A: Field, B: Field> Field for (A, B) {
+    fn save(&self, state: &mut Writer) {
+        self.0.save(state);
+        self.1.save(state);
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        Ok((A::load(state)?, B::load(state)?))
+    }
+}
+
+impl<T: Field, const N: usize> Field for [T; N] {
+    fn save(&self, state: &mut Writer) {
+        for value in self {
+            value.save(state);
+        }
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        let values = (0..N).map(|_| T::load(state)).collect::<io::Result<Vec<T>>>()?;
+        Ok(values.try_into().unwrap_or_else(|_| unreachable!()))
+    }
+}
+
+impl<T: Field> Field for Vec<T> {
+    fn save(&self, state: &mut Writer) {
+        self.len().save(state);
+        for value in self {
+            value.save(state);
+        }
+    }
+
+    fn load(state: &mut Reader) -> io::Result<Self> {
+        let length = usize::load(state)?;
+        // Every value takes at least a byte, which bounds a corrupt length.
+        if length > state.bytes.len() - state.position {
+            return Err(invalid("save state is truncated".to_string()));
+        }
+        (0..length).map(|_| T::load(state)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        let mut state = Writer::new();
+        state.put(&0x12u8);
+        state.put(&0x3456u16);
+        state.put(&Some(7usize));
+        state.put(&[true, false]);
+        state.put(&vec![(1u8, None::<u64>)]);
+        let bytes = state.into_bytes();
+        assert_eq!(bytes.len(), 1 + 2 + 9 + 2 + 8 + 2);
+        let mut state = Reader::new(&bytes);
+        assert_eq!(state.get::<u8>().unwrap(), 0x12);
+        assert_eq!(state.get::<u16>().unwrap(), 0x3456);
+        assert_eq!(state.get::<Option<usize>>().unwrap(), Some(7));
+        assert_eq!(state.get::<[bool; 2]>().unwrap(), [true, false]);
+        assert_eq!(state.get::<Vec<(u8, Option<u64>)>>().unwrap(), [(1, None)]);
+        assert!(state.finish().is_ok());
+        assert!(state.get::<u8>().is_err());
+        assert!(Reader::new(&[2]).get::<bool>().is_err());
+    }
+
+    #[test]
+    fn header() {
+        let bytes = seal(b"payload");
+        assert_eq!(open(&bytes).unwrap(), b"payload");
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_BYTES] ^= 0x01;
+        assert!(open(&corrupt).unwrap_err().to_string().contains("checksum"));
+        let mut newer = bytes.clone();
+        newer[8] = VERSION as u8 + 1;
+        assert!(open(&newer).unwrap_err().to_string().contains("version"));
+        assert!(open(&bytes[..bytes.len() - 1]).is_err());
+        assert!(open(b"RIFF").is_err());
+    }
+}
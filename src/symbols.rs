@@ -0,0 +1,94 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Names of addresses, as in the .SYM files CP/M assemblers and linkers write:
+// pairs of a hex address and a name, several to a line.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+    addresses: HashMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut symbols = Self::new();
+        let mut words = text.split_whitespace();
+        while let Some(address) = words.next() {
+            let name = words.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no name for {address}")))?;
+            let address = u16::from_str_radix(address.trim_end_matches(['h', 'H']), 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad symbol address {address}")))?;
+            symbols.insert(name, address);
+        }
+        Ok(symbols)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    // Writes the .SYM format, four symbols to a line.
+    pub fn to_sym(&self) -> String {
+        let entries: Vec<_> = self.names.iter().map(|(address, name)| format!("{address:04X} {name}")).collect();
+        entries.chunks(4).map(|line| line.join("\t") + "\r\n").collect()
+    }
+
+    // Only the first name given to an address is used for it.
+    pub fn insert(&mut self, name: &str, address: u16) {
+        self.names.entry(address).or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), address);
+    }
+
+    pub fn name(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(name).copied()
+    }
+
+    // The nearest symbol at or below `address`.
+    pub fn containing(&self, address: u16) -> Option<(u16, &str)> {
+        self.names.range(..=address).next_back().map(|(&address, name)| (address, name.as_str()))
+    }
+
+    // NAME, NAME+n or the address in hex.
+    pub fn format(&self, address: u16) -> String {
+        match self.containing(address) {
+            Some((start, name)) if start == address => name.to_string(),
+            Some((start, name)) if address - start < 0x100 => format!("{name}+{}", address - start),
+            _ => format!("{address:04X}h"),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.names.iter().map(|(&address, name)| (address, name.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sym_file() {
+        let symbols = Symbols::parse("0100 START\t0103 LOOP\r\n01A0 PRINT  0005 BDOS\r\n").unwrap();
+        assert_eq!(symbols.address("LOOP"), Some(0x0103));
+        assert_eq!(symbols.name(0x01A0), Some("PRINT"));
+        assert_eq!(symbols.format(0x0103), "LOOP");
+        assert_eq!(symbols.format(0x0110), "LOOP+13");
+        assert_eq!(symbols.format(0x0004), "0004h");
+        assert_eq!(Symbols::parse(&symbols.to_sym()).unwrap(), symbols);
+        assert!(Symbols::parse("0100").is_err());
+        assert!(Symbols::parse("START 0100").is_err());
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Cursor, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::devices::endpoint::Buffer;
+use crate::disasm::{self, is_call};
+use crate::monitor::Monitor;
+use crate::Registers;
+
+pub const WIDTH: usize = 80;
+pub const HEIGHT: usize = 24;
+const SPLIT: usize = 48; // first column of the right-hand panes
+const LISTING_ROWS: usize = 12;
+const MEMORY_ROWS: usize = 4;
+const CONSOLE_ROWS: usize = 4;
+const CONSOLE_LINES: usize = 100;
+const FRAME: Duration = Duration::from_millis(40);
+
+const TAB: u8 = 0x09;
+const ESCAPE: u8 = 0x1B;
+const LEAVE_CONSOLE: u8 = 0x1D; // ^]
+const ENTER: [u8; 2] = [b'\r', b'\n'];
+const BACKSPACE: [u8; 2] = [0x08, 0x7F];
+
+const HELP: &str = "s step  o over  c run  p pause  [ ] memory  : command  Tab console  q quit";
+
+// Full-screen debugger for an ANSI terminal: disassembly around PC,
+// registers, the stack, memory and the program's console, redrawn as the
+// program is stepped or run. Keys:
+//   s         step one instruction
+//   o         step over a CALL or RST
+//   c         run until a pass point or HLT; p pauses
+//   [ ]       move the memory pane back and forward
+//   :         a monitor command such as P100 or F200,2FF,0. G runs, M moves
+//             the memory pane and L the disassembly; for commands that ask
+//             for input, such as XA, give it after an =, as in XA=42
+//   Tab       type to the program's console until ^]
+//   q         quit
+// The console is the host side of whatever serial device the program uses,
+// given as a `Buffer`.
+pub struct Tui {
+    monitor: Monitor,
+    console: Buffer,
+    lines: VecDeque<String>,
+    top: u16, // of the disassembly pane
+    recent: VecDeque<u16>, // PCs of the last few instructions run
+    memory: u16,
+    message: String,
+    command: Option<String>,
+    typing: bool, // keys go to the console
+    escape: usize, // bytes of an escape sequence left to ignore
+    running: bool,
+    quit: bool,
+}
+
+impl Tui {
+    pub fn new(monitor: Monitor, console: Buffer) -> Self {
+        let pc = monitor.i8080().pc();
+        Self {
+            monitor,
+            console,
+            lines: VecDeque::from([String::new()]),
+            top: pc,
+            recent: VecDeque::new(),
+            memory: 0,
+            message: HELP.to_string(),
+            command: None,
+            typing: false,
+            escape: 0,
+            running: false,
+            quit: false,
+        }
+    }
+
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    // Takes keys from `keys` and draws on `output` until q or the keys end.
+    pub fn run(&mut self, keys: &Receiver<u8>, output: &mut dyn Write) -> io::Result<()> {
+        write!(output, "\x1b[?1049h\x1b[?25l")?;
+        let result = self.run_loop(keys, output);
+        write!(output, "\x1b[?25h\x1b[?1049l")?;
+        output.flush()?;
+        result
+    }
+
+    fn run_loop(&mut self, keys: &Receiver<u8>, output: &mut dyn Write) -> io::Result<()> {
+        while !self.quit {
+            output.write_all(self.render().as_bytes())?;
+            output.flush()?;
+            let key = match self.running {
+                true => {
+                    self.run_for(FRAME);
+                    keys.try_recv().ok()
+                }
+                false => match keys.recv_timeout(FRAME) {
+                    Ok(key) => Some(key),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                },
+            };
+            for key in key.into_iter().chain(keys.try_iter()) {
+                self.key(key);
+            }
+            self.collect_output();
+        }
+        Ok(())
+    }
+
+    // Runs until a pass point, HLT or `time` has passed.
+    pub fn run_for(&mut self, time: Duration) {
+        let start = Instant::now();
+        while self.running && start.elapsed() < time {
+            for _ in 0..1000 {
+                self.step();
+                let pc = self.monitor.i8080().pc();
+                if self.monitor.i8080().halted() || self.monitor.pass_points().contains(&pc) {
+                    self.stop();
+                    break;
+                }
+            }
+        }
+        self.collect_output();
+    }
+
+    pub fn key(&mut self, key: u8) {
+        if self.typing {
+            match key {
+                LEAVE_CONSOLE => {
+                    self.typing = false;
+                    self.message = HELP.to_string();
+                }
+                key => self.console.push_input(&[key]),
+            }
+            return;
+        }
+        if let Some(command) = self.command.as_mut() {
+            match key {
+                _ if ENTER.contains(&key) => {
+                    let command = self.command.take().unwrap();
+                    self.execute(&command);
+                }
+                _ if BACKSPACE.contains(&key) => {
+                    command.pop();
+                }
+                ESCAPE => self.command = None,
+                0x20..=0x7E => command.push(key as char),
+                _ => {}
+            }
+            return;
+        }
+        if self.escape > 0 {
+            self.escape -= 1;
+            return;
+        }
+        match key {
+            ESCAPE => self.escape = 2, // arrow and function keys
+            b'p' | b' ' if self.running => self.stop(),
+            _ if self.running => {}
+            b's' => self.step(),
+            b'o' => {
+                let i8080 = self.monitor.i8080();
+                let pc = i8080.pc();
+                let opcode = i8080.peek(pc);
+                if is_call(opcode) {
+                    self.run_to(pc.wrapping_add(disasm::length(opcode) as u16));
+                } else {
+                    self.step();
+                }
+            }
+            b'c' => self.start(),
+            b'[' => self.memory = self.memory.wrapping_sub(0x40),
+            b']' => self.memory = self.memory.wrapping_add(0x40),
+            b':' => self.command = Some(String::new()),
+            TAB => {
+                self.typing = true;
+                self.message = "Typing to the console; ^] to stop".to_string();
+            }
+            b'q' => self.quit = true,
+            _ => {}
+        }
+        self.collect_output();
+    }
+
+    fn step(&mut self) {
+        let pc = self.monitor.i8080().pc();
+        self.recent.push_back(pc);
+        if self.recent.len() > 3 {
+            self.recent.pop_front();
+        }
+        self.monitor.i8080_mut().step();
+    }
+
+    fn start(&mut self) {
+        self.monitor.i8080_mut().set_halted(false);
+        self.running = true;
+        self.message = "Running; p to pause".to_string();
+    }
+
+    fn stop(&mut self) {
+        self.running = false;
+        let i8080 = self.monitor.i8080();
+        self.message = match i8080.halted() {
+            true => format!("HLT at {:04X}", i8080.pc().wrapping_sub(1)),
+            false => format!("Stopped at {:04X}", i8080.pc()),
+        };
+    }
+
+    // Runs until PC reaches `address`, as for stepping over a call. A pass
+    // point or HLT on the way stops it too.
+    fn run_to(&mut self, address: u16) {
+        let start = Instant::now();
+        loop {
+            self.step();
+            let i8080 = self.monitor.i8080();
+            if i8080.pc() == address || i8080.halted() || self.monitor.pass_points().contains(&i8080.pc()) {
+                break;
+            }
+            if start.elapsed() > Duration::from_secs(1) {
+                // Probably not coming back; carry on as a run that can be paused.
+                self.start();
+                return;
+            }
+        }
+        self.stop();
+    }
+
+    fn execute(&mut self, command: &str) {
+        let command = command.trim();
+        let (line, answer) = command.split_once('=').unwrap_or((command, ""));
+        let letter = line.chars().next().map(|letter| letter.to_ascii_uppercase());
+        let mut output = Vec::new();
+        let result = match letter {
+            Some('G') | Some('M') | Some('L') => {
+                // Parsed by the monitor as an H command, for its syntax.
+                let argument = line[1..].trim();
+                let value = match argument {
+                    "" => Ok(None),
+                    argument => self.monitor.command(&format!("H{argument},0"), &mut Cursor::new(""), &mut output).map(|_| {
+                        let text = String::from_utf8_lossy(&output).to_string();
+                        u16::from_str_radix(text.split_whitespace().next().unwrap_or(""), 16).ok()
+                    }),
+                };
+                output.clear();
+                value.map(|value| match (letter, value) {
+                    (Some('G'), value) => {
+                        if let Some(pc) = value {
+                            self.monitor.i8080_mut().set_pc(pc);
+                        }
+                        self.start();
+                    }
+                    (Some('M'), Some(address)) => self.memory = address,
+                    (Some('L'), Some(address)) => self.top = address,
+                    _ => {}
+                })
+            }
+            _ => {
+                let mut input = Cursor::new(format!("{answer}\n"));
+                self.monitor.command(line, &mut input, &mut output).map(|_| ())
+            }
+        };
+        let output = String::from_utf8_lossy(&output).to_string();
+        self.message = match result {
+            Ok(()) => output.lines().rev().map(str::trim).find(|line| !line.is_empty()).unwrap_or("").to_string(),
+            Err(error) => format!("? {error}"),
+        };
+    }
+
+    fn collect_output(&mut self) {
+        for byte in self.console.take_output() {
+            let line = self.lines.back_mut().unwrap();
+            match byte {
+                b'\n' => {
+                    self.lines.push_back(String::new());
+                    if self.lines.len() > CONSOLE_LINES {
+                        self.lines.pop_front();
+                    }
+                }
+                0x08 => {
+                    line.pop();
+                }
+                0x20..=0x7E => line.push(byte as char),
+                _ => {}
+            }
+        }
+    }
+
+    // The whole screen, drawn from the top left.
+    pub fn render(&mut self) -> String {
+        let mut rows = vec![String::new(); HEIGHT];
+        let i8080 = self.monitor.i8080();
+        let registers = i8080.registers();
+        let state = match (self.running, i8080.halted()) {
+            (true, _) => "running",
+            (false, true) => "halted",
+            (false, false) => "stopped",
+        };
+        rows[0] = format!("\x1b[7m{:<WIDTH$}\x1b[0m", format!(" i8080_rs  {state}  {} T-states", i8080.ticks()));
+
+        for (row, text) in self.listing(registers.pc).into_iter().enumerate() {
+            rows[1 + row] = pad(&text, SPLIT);
+        }
+        for (row, text) in self.registers(&registers).into_iter().chain(self.stack(&registers)).enumerate().take(LISTING_ROWS) {
+            rows[1 + row] += &text;
+        }
+
+        let memory_top = 1 + LISTING_ROWS;
+        rows[memory_top] = heading(&format!("Memory {:04X}", self.memory));
+        for row in 0..MEMORY_ROWS {
+            let address = self.memory.wrapping_add(row as u16 * 16);
+            let bytes: Vec<u8> = (0..16).map(|offset| self.monitor.i8080().peek(address.wrapping_add(offset))).collect();
+            let hex: Vec<_> = bytes.iter().map(|byte| format!("{byte:02X}")).collect();
+            let ascii: String = bytes.iter().map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+            rows[memory_top + 1 + row] = format!("{address:04X}  {}  {ascii}", hex.join(" "));
+        }
+
+        let console_top = memory_top + 1 + MEMORY_ROWS;
+        rows[console_top] = heading(if self.typing { "Console (typing)" } else { "Console" });
+        let skip = self.lines.len().saturating_sub(CONSOLE_ROWS);
+        for (row, line) in self.lines.iter().skip(skip).enumerate() {
+            rows[console_top + 1 + row] = line.clone();
+        }
+        rows[HEIGHT - 1] = match &self.command {
+            Some(command) => format!(":{command}"),
+            None => self.message.clone(),
+        };
+
+        let mut screen = String::from("\x1b[H");
+        for (index, row) in rows.iter().enumerate() {
+            let visible = if index == 0 { row.clone() } else { truncate(row, WIDTH) };
+            screen += &visible;
+            screen += "\x1b[K";
+            if index + 1 < HEIGHT {
+                screen += "\r\n";
+            }
+        }
+        screen
+    }
+
+    // Disassembly from a little before PC, keeping the window still while PC
+    // stays in it.
+    fn listing(&mut self, pc: u16) -> Vec<String> {
+        let addresses = |top: u16| {
+            let mut address = top;
+            (0..LISTING_ROWS)
+                .map(|_| {
+                    let current = address;
+                    address = address.wrapping_add(disasm::length(self.monitor.i8080().peek(address)) as u16);
+                    current
+                })
+                .collect::<Vec<u16>>()
+        };
+        if !addresses(self.top)[..LISTING_ROWS - 2].contains(&pc) {
+            // Start at the earliest recent instruction that led straight to PC.
+            let mut top = pc;
+            for &address in self.recent.iter().rev() {
+                let length = disasm::length(self.monitor.i8080().peek(address)) as u16;
+                if address.wrapping_add(length) != top {
+                    break;
+                }
+                top = address;
+            }
+            self.top = top;
+        }
+        let i8080 = self.monitor.i8080();
+        let symbols = self.monitor.symbols();
+        addresses(self.top)
+            .into_iter()
+            .map(|address| {
+                let bytes = [0, 1, 2].map(|offset| i8080.peek(address.wrapping_add(offset)));
+                let instruction = disasm::decode(bytes, Some(symbols));
+                let hex: String = bytes[..instruction.length].iter().map(|byte| format!("{byte:02X}")).collect();
+                let marker = if address == pc { '>' } else { ' ' };
+                let pass = if self.monitor.pass_points().contains(&address) { '*' } else { ' ' };
+                let label = symbols.name(address).map_or(String::new(), |name| format!("{name}:"));
+                format!("{marker}{pass}{address:04X}  {hex:<6}  {label:<9} {}", instruction.text)
+            })
+            .collect()
+    }
+
+    fn registers(&self, registers: &Registers) -> Vec<String> {
+        let i8080 = self.monitor.i8080();
+        let flag = |name: char, mask: u8| format!("{name}{}", (registers.flags & mask != 0) as u8);
+        vec![
+            "Registers".to_string(),
+            format!("A  {:02X}    Flags  {:02X}", registers.a, registers.flags),
+            format!("BC {:02X}{:02X}  DE {:02X}{:02X}", registers.b, registers.c, registers.d, registers.e),
+            format!("HL {:02X}{:02X}  SP {:04X}", registers.h, registers.l, registers.sp),
+            format!("PC {:04X}  INTE {}", registers.pc, i8080.inte() as u8),
+            [
+                flag('S', Registers::SIGN),
+                flag('Z', Registers::ZERO),
+                flag('A', Registers::AUXILIARY_CARRY),
+                flag('P', Registers::PARITY),
+                flag('C', Registers::CARRY),
+            ]
+            .join(" "),
+            String::new(),
+        ]
+    }
+
+    fn stack(&self, registers: &Registers) -> Vec<String> {
+        let i8080 = self.monitor.i8080();
+        let symbols = self.monitor.symbols();
+        let mut lines = vec!["Stack".to_string()];
+        for index in 0..(LISTING_ROWS - 8) as u16 {
+            let address = registers.sp.wrapping_add(index * 2);
+            let value = u16::from_le_bytes([i8080.peek(address), i8080.peek(address.wrapping_add(1))]);
+            let mut line = format!("{address:04X}  {value:04X}");
+            if let Some((start, _)) = symbols.containing(value).filter(|_| !symbols.is_empty()) {
+                if value.wrapping_sub(start) < 0x100 {
+                    write!(line, "  {}", symbols.format(value)).unwrap();
+                }
+            }
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+fn heading(title: &str) -> String {
+    format!("\x1b[1m── {title} {}\x1b[0m", "─".repeat(WIDTH.saturating_sub(title.chars().count() + 4)))
+}
+
+// Pads or cuts `text` to `width` columns, leaving escape sequences whole.
+fn pad(text: &str, width: usize) -> String {
+    let mut text = truncate(text, width);
+    let visible = visible_width(&text);
+    text.extend(std::iter::repeat_n(' ', width - visible));
+    text
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    let mut result = String::new();
+    let (mut columns, mut escape) = (0, false);
+    for c in text.chars() {
+        if c == '\x1b' {
+            escape = true;
+        } else if escape {
+            escape = !c.is_ascii_alphabetic();
+        } else if columns == width {
+            continue;
+        } else {
+            columns += 1;
+        }
+        result.push(c);
+    }
+    result
+}
+
+fn visible_width(text: &str) -> usize {
+    let mut escape = false;
+    text.chars()
+        .filter(|&c| {
+            if c == '\x1b' {
+                escape = true;
+            } else if escape {
+                escape = !c.is_ascii_alphabetic();
+                return false;
+            }
+            !escape
+        })
+        .count()
+}
+
+// The terminal's rows with the escape sequences taken out, for tests.
+pub fn plain(screen: &str) -> Vec<String> {
+    let mut plain = String::new();
+    let mut escape = false;
+    for c in screen.chars() {
+        if c == '\x1b' {
+            escape = true;
+        } else if escape {
+            escape = !c.is_ascii_alphabetic();
+        } else if c != '\r' {
+            plain.push(c);
+        }
+    }
+    plain.lines().map(|line| line.trim_end().to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::endpoint::Endpoint;
+    use crate::symbols::Symbols;
+    use crate::I8080;
+
+    // 0100: LXI SP,0200h; CALL 0110h; HLT
+    // 0110: MVI A,41h; OUT 00h; RET
+    fn debugger() -> (Tui, Buffer) {
+        let mut i8080 = I8080::new(0x10000);
+        let code: [(u16, &[u8]); 2] =
+            [(0x0100, &[0x31, 0x00, 0x02, 0xCD, 0x10, 0x01, 0x76]), (0x0110, &[0x3E, 0x41, 0xD3, 0x00, 0xC9])];
+        for (start, bytes) in code {
+            for (offset, &value) in bytes.iter().enumerate() {
+                i8080.poke(start + offset as u16, value);
+            }
+        }
+        i8080.set_pc(0x0100);
+        let mut monitor = Monitor::new(i8080);
+        monitor.set_symbols(Symbols::parse("0110 PRINT").unwrap());
+        let console = Buffer::new();
+        (Tui::new(monitor, console.clone()), console)
+    }
+
+    #[test]
+    fn panes() {
+        let (mut tui, _) = debugger();
+        let screen = plain(&tui.render());
+        assert_eq!(screen.len(), HEIGHT);
+        assert!(screen.iter().all(|row| row.chars().count() <= WIDTH));
+        assert!(screen[0].contains("stopped"));
+        assert_eq!(&screen[1][..SPLIT].trim_end(), &"> 0100  310002            LXI SP,0200H");
+        assert!(screen[2].contains("CALL PRINT"));
+        assert!(screen[2].ends_with("A  00    Flags  02"));
+
+        tui.key(b's');
+        let screen = plain(&tui.render());
+        assert!(screen[1].starts_with("  0100  310002"));
+        assert!(screen[2].starts_with("> 0103  CD1001"));
+        tui.key(b's');
+        let screen = plain(&tui.render());
+        assert_eq!(&screen[1][..SPLIT].trim_end(), &"> 0110  3E41    PRINT:    MVI A,41H");
+        assert_eq!(screen[9][SPLIT..], *"01FE  0106");
+
+        let (mut tui, _) = debugger();
+        tui.key(b's');
+        tui.key(b'o');
+        let screen = plain(&tui.render());
+        assert!(screen[3].starts_with("> 0106  76"));
+        assert!(screen[2].ends_with("A  41    Flags  02"));
+        assert_eq!(screen[HEIGHT - 1], "Stopped at 0106");
+        tui.key(b'[');
+        assert!(plain(&tui.render())[13].contains("Memory FFC0"));
+    }
+
+    #[test]
+    fn commands_and_console() {
+        let (mut tui, console) = debugger();
+        for &key in b":P114\r:XA=42\r:M100\r" {
+            tui.key(key);
+        }
+        assert!(tui.monitor().pass_points().contains(&0x0114));
+        assert_eq!(tui.monitor().i8080().registers().a, 0x42);
+        assert!(plain(&tui.render())[14].starts_with("0100  31 00 02 CD"));
+
+        tui.key(b'c');
+        assert!(tui.running());
+        tui.run_for(Duration::from_secs(1));
+        assert!(!tui.running());
+        assert_eq!(tui.monitor().i8080().pc(), 0x0114);
+
+        let mut device = console.clone();
+        for &byte in b"one\r\ntwo\x08o" {
+            device.send(byte);
+        }
+        tui.key(TAB);
+        tui.key(b'q');
+        tui.key(LEAVE_CONSOLE);
+        assert_eq!(device.receive(), Some(b'q'));
+        let screen = plain(&tui.render());
+        assert_eq!(screen[19..21], ["one", "two"]);
+        tui.key(b'q');
+        assert!(tui.quit());
+        let screen = plain(&tui.render());
+        assert_eq!(screen[HEIGHT - 1], HELP);
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::{self, Write};
+
+use crate::bus::{self, BusObserver, MachineCycle};
+
+const STATUS_BITS: [(&str, u8); 8] = [
+    ("INTA", bus::INTA),
+    ("WO", bus::WO),
+    ("STACK", bus::STACK),
+    ("HLTA", bus::HLTA),
+    ("OUT", bus::OUT),
+    ("M1", bus::M1),
+    ("INP", bus::INP),
+    ("MEMR", bus::MEMR),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Pins {
+    address: u16,
+    data: Option<u8>, // None while the data bus floats
+    sync: bool,
+    dbin: bool,
+    wr: bool, // pin level, low while writing
+    wait: bool,
+    inte: bool,
+    hlda: bool,
+    status: u8, // as latched at SYNC
+}
+
+impl Pins {
+    const IDLE: Pins = Pins {
+        address: 0,
+        data: None,
+        sync: false,
+        dbin: false,
+        wr: true,
+        wait: false,
+        inte: false,
+        hlda: false,
+        status: 0,
+    };
+}
+
+// Value Change Dump of the CPU pins, one time unit per T-state.
+pub struct VcdWriter<W: Write> {
+    writer: W,
+    written: Pins,
+    pins: Pins, // pending until time moves on
+    time: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> VcdWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "$version i8080_rs $end")?;
+        writeln!(writer, "$comment one time unit is one T-state, WR is active low $end")?;
+        writeln!(writer, "$timescale 1 ns $end")?;
+        writeln!(writer, "$scope module i8080 $end")?;
+        writeln!(writer, "$var wire 16 A A[15:0] $end")?;
+        writeln!(writer, "$var wire 8 D D[7:0] $end")?;
+        writeln!(writer, "$var wire 1 S SYNC $end")?;
+        writeln!(writer, "$var wire 1 R DBIN $end")?;
+        writeln!(writer, "$var wire 1 W WR $end")?;
+        writeln!(writer, "$var wire 1 T WAIT $end")?;
+        writeln!(writer, "$var wire 1 I INTE $end")?;
+        writeln!(writer, "$var wire 1 H HLDA $end")?;
+        writeln!(writer, "$scope module status $end")?;
+        for (index, (name, _)) in STATUS_BITS.iter().enumerate() {
+            writeln!(writer, "$var wire 1 {index} {name} $end")?;
+        }
+        writeln!(writer, "$upscope $end")?;
+        writeln!(writer, "$upscope $end")?;
+        writeln!(writer, "$enddefinitions $end")?;
+        writeln!(writer, "#0")?;
+        writeln!(writer, "$dumpvars")?;
+        let pins = Pins::IDLE;
+        Self::dump(&mut writer, &pins, None)?;
+        writeln!(writer, "$end")?;
+        Ok(Self { writer, written: pins, pins, time: 0, error: None })
+    }
+
+    // Flushes the dump and returns the writer, or the first write error.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush();
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn dump(writer: &mut W, pins: &Pins, previous: Option<&Pins>) -> io::Result<()> {
+        if previous.is_none_or(|previous| previous.address != pins.address) {
+            writeln!(writer, "b{:016b} A", pins.address)?;
+        }
+        if previous.is_none_or(|previous| previous.data != pins.data) {
+            match pins.data {
+                Some(data) => writeln!(writer, "b{data:08b} D")?,
+                None => writeln!(writer, "bzzzzzzzz D")?,
+            }
+        }
+        let wires = |p: &Pins| [p.sync, p.dbin, p.wr, p.wait, p.inte, p.hlda];
+        let before = previous.map(wires);
+        for (index, (id, level)) in "SRWTIH".chars().zip(wires(pins)).enumerate() {
+            if before.is_none_or(|before| before[index] != level) {
+                writeln!(writer, "{}{id}", level as u8)?;
+            }
+        }
+        for (index, (_, bit)) in STATUS_BITS.iter().enumerate() {
+            if previous.is_none_or(|previous| (previous.status ^ pins.status) & bit != 0) {
+                writeln!(writer, "{}{index}", (pins.status & bit != 0) as u8)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn change(&mut self, time: u64, pins: Pins) {
+        if time != self.time {
+            self.flush();
+            self.time = time;
+        }
+        self.pins = pins;
+    }
+
+    fn flush(&mut self) {
+        if self.pins == self.written || self.error.is_some() {
+            return;
+        }
+        let result = writeln!(self.writer, "#{}", self.time)
+            .and_then(|_| Self::dump(&mut self.writer, &self.pins, Some(&self.written)));
+        match result {
+            Ok(()) => self.written = self.pins,
+            Err(error) => self.error = Some(error),
+        }
+    }
+}
+
+impl<W: Write> BusObserver for VcdWriter<W> {
+    fn machine_cycle(&mut self, cycle: &MachineCycle) {
+        let t1 = Pins {
+            address: cycle.address,
+            data: Some(cycle.status),
+            sync: true,
+            dbin: false,
+            wr: true,
+            wait: false,
+            inte: cycle.inte,
+            hlda: false,
+            status: cycle.status,
+        };
+        self.change(cycle.start, t1);
+        let t2 = Pins {
+            data: (cycle.dbin() || cycle.wr()).then_some(cycle.data),
+            sync: false,
+            dbin: cycle.dbin(),
+            ..t1
+        };
+        self.change(cycle.start + 1, t2);
+        if cycle.wait > 0 {
+            self.change(cycle.start + 2, Pins { wait: true, ..t2 });
+        }
+        let t3 = cycle.start + 2 + cycle.wait as u64;
+        self.change(t3, Pins { wr: !cycle.wr(), ..t2 });
+        self.change(t3 + 1, Pins { data: None, dbin: false, wr: true, ..t2 });
+    }
+
+    fn hold(&mut self, start: u64, states: usize) {
+        let idle = Pins { data: None, sync: false, dbin: false, wr: true, ..self.pins };
+        self.change(start, Pins { hlda: true, ..idle });
+        self.change(start + states as u64, idle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::I8080;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn out() {
+        // MVI A,42h; OUT 10h
+        let mut i8080 = I8080::new(0x100);
+        for (location, value) in [0x3E, 0x42, 0xD3, 0x10].into_iter().enumerate() {
+            i8080.poke(location as u16, value);
+        }
+        let vcd = Rc::new(RefCell::new(VcdWriter::new(Vec::new()).unwrap()));
+        i8080.observe(vcd.clone());
+        i8080.step();
+        i8080.step();
+        drop(i8080);
+        let vcd = Rc::try_unwrap(vcd).ok().unwrap().into_inner();
+        let dump = String::from_utf8(vcd.finish().unwrap()).unwrap();
+        assert!(dump.contains("$var wire 1 S SYNC $end"));
+        // OUT is M3 of the second instruction, starting at T-state 7 + 4 + 3. D stays
+        // at 10h because the port number read in M2 equals the OUT status word.
+        assert!(dump.contains("#14\nb0001000000010000 A\n1S\n0R\n"));
+        assert!(dump.contains("#15\nb01000010 D\n0S\n"));
+        assert!(dump.contains("#16\n0W\n"));
+        assert!(dump.contains("#17\nbzzzzzzzz D\n1W\n"));
+    }
+}
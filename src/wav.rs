@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Mono 16-bit PCM audio.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Wave {
+    pub sample_rate: u32,
+    pub samples: Vec<i16>,
+}
+
+impl Wave {
+    pub fn new(sample_rate: u32, samples: Vec<i16>) -> Self {
+        Self { sample_rate, samples }
+    }
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        self.write(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let data = (self.samples.len() * 2) as u32;
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(36 + data).to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        writer.write_all(&1u16.to_le_bytes())?; // PCM
+        writer.write_all(&1u16.to_le_bytes())?; // mono
+        writer.write_all(&self.sample_rate.to_le_bytes())?;
+        writer.write_all(&(self.sample_rate * 2).to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&data.to_le_bytes())?;
+        for sample in &self.samples {
+            writer.write_all(&sample.to_le_bytes())?;
+        }
+        writer.flush()
+    }
+
+    // Reads 8 or 16-bit PCM, mixing several channels down to one.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("not a WAV file"));
+        }
+        let mut format = None;
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let id = &bytes[offset..offset + 4];
+            let size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            let body = &bytes[offset + 8..(offset + 8 + size).min(bytes.len())];
+            match id {
+                b"fmt " if body.len() >= 16 => {
+                    let field = |at: usize| u16::from_le_bytes([body[at], body[at + 1]]);
+                    if field(0) != 1 {
+                        return Err(invalid("not PCM"));
+                    }
+                    let sample_rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                    format = Some((field(2) as usize, sample_rate, field(14)));
+                }
+                b"data" => {
+                    let (channels, sample_rate, bits) = format.ok_or_else(|| invalid("data before fmt"))?;
+                    let width = match bits {
+                        8 => 1,
+                        16 => 2,
+                        _ => return Err(invalid("unsupported sample size")),
+                    };
+                    let samples = body
+                        .chunks_exact(width * channels.max(1))
+                        .map(|frame| {
+                            let sum: i32 = frame
+                                .chunks_exact(width)
+                                .map(|sample| match width {
+                                    1 => (sample[0] as i32 - 128) << 8,
+                                    _ => i16::from_le_bytes([sample[0], sample[1]]) as i32,
+                                })
+                                .sum();
+                            (sum / channels.max(1) as i32) as i16
+                        })
+                        .collect();
+                    return Ok(Self { sample_rate, samples });
+                }
+                _ => {}
+            }
+            offset += 8 + size + (size & 1);
+        }
+        Err(invalid("no data chunk"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let wave = Wave::new(22050, vec![0, 1000, -1000, i16::MAX, i16::MIN]);
+        let mut bytes = Vec::new();
+        wave.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 44 + 10);
+        assert_eq!(Wave::read(&bytes[..]).unwrap(), wave);
+        assert!(Wave::read(&b"RIFF\0\0\0\0WAVX"[..]).is_err());
+    }
+
+    #[test]
+    fn stereo_8_bit() {
+        let mut bytes = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x02\0\x40\x1F\0\0\x80\x3E\0\0\x02\0\x08\0".to_vec();
+        bytes.extend_from_slice(b"data\x04\0\0\0\x80\x80\xFF\x81");
+        let wave = Wave::read(&bytes[..]).unwrap();
+        assert_eq!(wave, Wave::new(8000, vec![0, 64 << 8]));
+    }
+}